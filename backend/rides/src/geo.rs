@@ -0,0 +1,219 @@
+/*
+* Module        :  backend/rides/src/geo.rs
+* Copyright     :  2022 Webi.ai
+* License       :  Not Yet Licensed for Public Use
+* Description   :  Distance and travel time estimates without external map services
+*/
+
+use crate::Location;
+use ic_cdk::export::candid::CandidType;
+use serde::Deserialize;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+const NANOS_PER_HOUR: f64 = 3_600_000_000_000.0;
+
+/// great-circle distance between two locations in kilometres
+pub fn distance_km(from: &Location, to: &Location) -> f64 {
+    let d_lat = (to.lat - from.lat).to_radians();
+    let d_lng = (to.lng - from.lng).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + from.lat.to_radians().cos() * to.lat.to_radians().cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// an area around `center` where traffic moves at `speed_kmh` on average
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SpeedZone {
+    pub name: String,
+    pub center: Location,
+    pub radius_km: f64,
+    pub speed_kmh: f64,
+}
+
+impl SpeedZone {
+    fn contains(&self, at: &Location) -> bool {
+        distance_km(&self.center, at) <= self.radius_km
+    }
+}
+
+/// settings for turning straight-line distances into road distances and travel times
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct GeoConfig {
+    /// how much longer roads are than the straight line, at least 1
+    pub road_factor: f64,
+    /// speed used where no zone applies
+    pub default_speed_kmh: f64,
+    /// zones checked in order, the first containing the start of a trip wins
+    pub speed_zones: Vec<SpeedZone>,
+}
+
+impl Default for GeoConfig {
+    fn default() -> GeoConfig {
+        GeoConfig {
+            road_factor: 1.3,
+            default_speed_kmh: 30.0,
+            speed_zones: Vec::new(),
+        }
+    }
+}
+
+impl GeoConfig {
+    /// check the factor and speeds make sense
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.road_factor >= 1.0 && self.road_factor.is_finite()) {
+            return Err("Road factor must be at least 1".to_string());
+        }
+        if !(self.default_speed_kmh > 0.0 && self.default_speed_kmh.is_finite()) {
+            return Err("Default speed must be positive".to_string());
+        }
+        for zone in &self.speed_zones {
+            zone.center.validate()?;
+            let positive = |x: f64| x > 0.0 && x.is_finite();
+            if !(positive(zone.speed_kmh) && positive(zone.radius_km)) {
+                return Err(format!("Speed zone {} needs a positive speed and radius", zone.name));
+            }
+        }
+        Ok(())
+    }
+
+    /// estimated distance by road between two locations in kilometres
+    pub fn road_distance_km(&self, from: &Location, to: &Location) -> f64 {
+        distance_km(from, to) * self.road_factor
+    }
+
+    /// average speed for trips starting at `at`
+    pub fn speed_kmh(&self, at: &Location) -> f64 {
+        self.speed_zones
+            .iter()
+            .find(|zone| zone.contains(at))
+            .map_or(self.default_speed_kmh, |zone| zone.speed_kmh)
+    }
+
+    /// estimated travel time between two locations in nanoseconds
+    pub fn eta(&self, from: &Location, to: &Location) -> u64 {
+        let hours = self.road_distance_km(from, to) / self.speed_kmh(from);
+        (hours * NANOS_PER_HOUR).round() as u64
+    }
+
+    /// estimated travel time along a route of stops in nanoseconds
+    pub fn route_eta(&self, route: &[Location]) -> u64 {
+        route.windows(2).map(|leg| self.eta(&leg[0], &leg[1])).sum()
+    }
+}
+
+/// whether `point` lies inside the polygon with corners `polygon`, by ray casting
+pub fn point_in_polygon(point: &Location, polygon: &[Location]) -> bool {
+    let mut inside = false;
+    let mut previous = match polygon.last() {
+        Some(corner) => corner,
+        None => return false,
+    };
+    for corner in polygon {
+        let crosses = (corner.lat > point.lat) != (previous.lat > point.lat);
+        if crosses {
+            let slope = (previous.lng - corner.lng) / (previous.lat - corner.lat);
+            let lng_at = corner.lng + (point.lat - corner.lat) * slope;
+            if point.lng < lng_at {
+                inside = !inside;
+            }
+        }
+        previous = corner;
+    }
+    inside
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn at(lat: f64, lng: f64) -> Location {
+        Location {
+            address_text: "".to_string(),
+            lat,
+            lng,
+        }
+    }
+
+    ///test the haversine distance against known coordinate pairs
+    #[test]
+    fn test_known_distances() {
+        let london = at(51.5074, -0.1278);
+        let paris = at(48.8566, 2.3522);
+        //london to paris is about 344 km
+        assert!((distance_km(&london, &paris) - 343.5).abs() < 1.0);
+        //new york to san francisco is about 4129 km
+        let new_york = at(40.7128, -74.0060);
+        let san_francisco = at(37.7749, -122.4194);
+        assert!((distance_km(&new_york, &san_francisco) - 4129.0).abs() < 5.0);
+        assert_eq!(distance_km(&new_york, &new_york), 0.0);
+        //one degree of latitude is about 111.2 km
+        assert!((distance_km(&at(0.0, 0.0), &at(1.0, 0.0)) - 111.19).abs() < 0.01);
+        //half way round the equator
+        let half = std::f64::consts::PI * EARTH_RADIUS_KM;
+        assert!((distance_km(&at(0.0, 0.0), &at(0.0, 180.0)) - half).abs() < 0.01);
+        assert_eq!(distance_km(&london, &paris), distance_km(&paris, &london));
+    }
+
+    ///test road distance and eta use the factor and the zone speed of the start
+    #[test]
+    fn test_eta_by_zone() {
+        let mut config = GeoConfig {
+            road_factor: 1.5,
+            default_speed_kmh: 60.0,
+            speed_zones: Vec::new(),
+        };
+        let from = at(0.0, 0.0);
+        let to = at(1.0, 0.0);
+        let road = config.road_distance_km(&from, &to);
+        assert!((road - 166.79).abs() < 0.01);
+        let hours = road / 60.0;
+        assert_eq!(config.eta(&from, &to), (hours * NANOS_PER_HOUR).round() as u64);
+
+        config.speed_zones.push(SpeedZone {
+            name: "downtown".to_string(),
+            center: at(0.0, 0.0),
+            radius_km: 5.0,
+            speed_kmh: 20.0,
+        });
+        assert_eq!(config.speed_kmh(&from), 20.0);
+        assert_eq!(config.speed_kmh(&to), 60.0);
+        let slow = config.eta(&from, &to) as i128;
+        assert!((slow - 3 * config.eta(&to, &from) as i128).abs() <= 3);
+        assert_eq!(config.route_eta(&[from.clone(), to.clone(), from.clone()]), {
+            config.eta(&from, &to) + config.eta(&to, &from)
+        });
+
+        config.road_factor = 0.9;
+        assert!(config.validate().is_err());
+        config.road_factor = f64::INFINITY;
+        assert!(config.validate().is_err());
+        config.road_factor = 1.5;
+        assert!(config.validate().is_ok());
+        config.default_speed_kmh = f64::NAN;
+        assert!(config.validate().is_err());
+        config.default_speed_kmh = 60.0;
+        config.speed_zones[0].speed_kmh = f64::INFINITY;
+        assert!(config.validate().is_err());
+    }
+
+    ///test points inside, outside and in the notch of a concave polygon
+    #[test]
+    fn test_point_in_polygon() {
+        //a square with a notch cut into its top edge
+        let polygon = vec![
+            at(0.0, 0.0),
+            at(0.0, 4.0),
+            at(4.0, 4.0),
+            at(4.0, 3.0),
+            at(2.0, 2.0),
+            at(4.0, 1.0),
+            at(4.0, 0.0),
+        ];
+        assert!(point_in_polygon(&at(1.0, 1.0), &polygon));
+        assert!(point_in_polygon(&at(3.5, 0.5), &polygon));
+        assert!(!point_in_polygon(&at(3.5, 2.0), &polygon));
+        assert!(!point_in_polygon(&at(5.0, 2.0), &polygon));
+        assert!(!point_in_polygon(&at(-1.0, 2.0), &polygon));
+        assert!(!point_in_polygon(&at(1.0, 1.0), &[]));
+    }
+}
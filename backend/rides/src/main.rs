@@ -1,4 +1,4 @@
-/**
+/*
 * Module        :  contracts/token/main.rs
 * Copyright     :  2022 Webi.ai
 * License       :  Not Yet Licensed for Public Use
@@ -14,24 +14,61 @@ use ic_cdk::export::{
     candid::{candid_method, CandidType, export_service},
     Principal,
 };
+use geo::{distance_km, point_in_polygon, GeoConfig};
+use memory::StableLog;
 use ic_cdk_macros::*;
+use ic_kit::ic;
 ///allow for ledger_types incase we need it later
 #[allow(unused_imports)]
 use ic_ledger_types::{
     query_archived_blocks, query_blocks, AccountIdentifier, Block, BlockIndex, GetBlocksArgs, Memo,
-    Subaccount, Tokens, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+    Operation, Subaccount, Tokens, TransferError, DEFAULT_FEE, DEFAULT_SUBACCOUNT,
+    MAINNET_LEDGER_CANISTER_ID,
 };
+use std::convert::{TryFrom, TryInto};
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 
+mod geo;
+mod memory;
+
 type IdStore = BTreeMap<String, Principal>;
 type ProfileStore = BTreeMap<Principal, Profile>;
 
 type DriverStore = Vec<Driver>;
 type RiderStore = Vec<Rider>;
 type RidesStore = Vec<Ride>;
+type UsedBlocks = BTreeSet<BlockHeight>;
+type PoolStore = Vec<Pool>;
+type StartCodeStore = BTreeMap<String, StartCode>;
+type RoleStore = BTreeMap<Principal, BTreeSet<Role>>;
+type ControllerStore = BTreeSet<Principal>;
+type DriverApplicationStore = BTreeMap<String, DriverApplication>;
+type SanctionStore = Vec<Sanction>;
+type DisputeStore = Vec<Dispute>;
+type NotificationStore = Vec<Notification>;
+type AuditLog = Vec<AuditEvent>;
+type EventLog = Vec<DomainEvent>;
+type SubscriberStore = BTreeMap<Principal, Subscriber>;
+type ChatStore = BTreeMap<String, ChatThread>;
+type DriverLocationStore = BTreeMap<String, DriverPosition>;
+type BreadcrumbStore = BTreeMap<String, Vec<DriverPosition>>;
+type ZoneStore = BTreeMap<String, ServiceZone>;
+type PromoStore = BTreeMap<String, PromoCode>;
+type PayoutAccountStore = BTreeMap<String, AccountIdentifier>;
+/// each driver's earnings in the order they were posted
+type EarningStore = BTreeMap<String, Vec<EarningEntry>>;
+
+/// nanoseconds in one minute of canister time
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+/// how long a ride may wait for a driver to accept before it is cancelled
+const RIDE_REQUEST_TIMEOUT: u64 = 5 * NANOS_PER_MINUTE;
+/// how long a ride start code stays valid after it is issued
+const START_CODE_TTL: u64 = 5 * NANOS_PER_MINUTE;
+/// wrong start codes a rider may enter before the code is thrown away
+const START_CODE_MAX_ATTEMPTS: u64 = 5;
 
 #[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
 pub enum CurrentStatus {
@@ -66,6 +103,9 @@ impl std::str::FromStr for CurrentStatus {
 impl fmt::Display for RideStatus {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            RideStatus::Scheduled => write!(f, "Scheduled"),
+            RideStatus::Requested => write!(f, "Requested"),
+            RideStatus::Accepted => write!(f, "Accepted"),
             RideStatus::Active => write!(f, "Active"),
             RideStatus::Completed => write!(f, "Completed"),
             RideStatus::Cancelled => write!(f, "Cancelled"),
@@ -78,6 +118,9 @@ impl std::str::FromStr for RideStatus {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "Scheduled" => Ok(RideStatus::Scheduled),
+            "Requested" => Ok(RideStatus::Requested),
+            "Accepted" => Ok(RideStatus::Accepted),
             "Active" => Ok(RideStatus::Active),
             "Completed" => Ok(RideStatus::Completed),
             "Cancelled" => Ok(RideStatus::Cancelled),
@@ -151,386 +194,2684 @@ impl Default for Driver {
     }
 }
 
-#[derive(Clone, Debug, Default, CandidType, Deserialize)]
-struct Profile {
-    pub name: String,
-    pub description: String,
-    pub keywords: Vec<String>,
+/// fare settings in e8s, applied to the trip duration and route when a ride completes
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FareConfig {
+    pub base_fare: u64,
+    pub per_minute: u64,
+    pub per_km: u64,
+    /// percentage of each settled fare the platform keeps, DEFAULT_PLATFORM_FEE_PERCENT if unset
+    pub platform_fee_percent: Option<u64>,
 }
 
-thread_local! {
-    static PROFILE_STORE: RefCell<ProfileStore> = RefCell::default();
-    static ID_STORE: RefCell<IdStore> = RefCell::default();
-    static DRIVER_STORE: RefCell<DriverStore> = RefCell::default();
-    static RIDER_STORE: RefCell<RiderStore> = RefCell::default();
-    static RIDES_STORE: RefCell<RidesStore> = RefCell::default();
+const DEFAULT_PLATFORM_FEE_PERCENT: u64 = 20;
+
+impl Default for FareConfig {
+    fn default() -> FareConfig {
+        FareConfig {
+            base_fare: 10_000_000,
+            per_minute: 1_000_000,
+            per_km: 2_000_000,
+            platform_fee_percent: Some(DEFAULT_PLATFORM_FEE_PERCENT),
+        }
+    }
 }
 
-#[query]
-#[candid_method(query)]
-fn get_self() -> Profile {
-    let id = ic_cdk::api::caller();
-    PROFILE_STORE.with(|profile_store| profile_store.borrow().get(&id).cloned().unwrap_or_default())
+impl FareConfig {
+    /// percentage of each settled fare the platform keeps, at most 100
+    pub fn platform_fee_percent(&self) -> u64 {
+        self.platform_fee_percent.unwrap_or(DEFAULT_PLATFORM_FEE_PERCENT).min(100)
+    }
+
+    /// fare for a trip lasting `duration` nanoseconds, billed per started minute
+    pub fn fare_for_duration(&self, duration: u64) -> u64 {
+        let minutes = duration.div_ceil(NANOS_PER_MINUTE);
+        self.base_fare + minutes * self.per_minute
+    }
+
+    /// fare for a trip lasting `duration` nanoseconds over `distance_km` kilometres
+    pub fn fare_for_trip(&self, duration: u64, distance_km: f64) -> u64 {
+        self.fare_for_duration(duration) + (distance_km * self.per_km as f64).round() as u64
+    }
 }
 
-#[query]
-#[candid_method(query)]
-fn get(name: String) -> Profile {
-    ID_STORE.with(|id_store| {
-        PROFILE_STORE.with(|profile_store| {
-            id_store
-                .borrow()
-                .get(&name)
-                .and_then(|id| profile_store.borrow().get(id).cloned())
-                .unwrap_or_default()
-        })
-    })
+/// fare adjustments locked onto a ride when it is requested
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Pricing {
+    /// service zone the pickup is in
+    pub zone_id: Option<String>,
+    pub fare_multiplier: f64,
+    /// surge in the zone when the ride was requested
+    pub surge_multiplier: f64,
+    /// flat surcharge in e8s, such as for airport trips
+    pub surcharge: u64,
+    pub promo_code: Option<String>,
+    pub discount: Option<Discount>,
 }
 
-#[update]
-#[candid_method(update)]
-fn update(profile: Profile) {
-    let principal_id = ic_cdk::api::caller();
-    ID_STORE.with(|id_store| {
-        id_store
-            .borrow_mut()
-            .insert(profile.name.clone(), principal_id);
-    });
-    PROFILE_STORE.with(|profile_store| {
-        profile_store.borrow_mut().insert(principal_id, profile);
-    });
+impl Default for Pricing {
+    fn default() -> Pricing {
+        Pricing {
+            zone_id: None,
+            fare_multiplier: 1.0,
+            surge_multiplier: 1.0,
+            surcharge: 0,
+            promo_code: None,
+            discount: None,
+        }
+    }
 }
 
-///get rides store   
-#[query]
-#[candid_method(query)]
-fn get_rides() -> RidesStore {
-    RIDES_STORE.with(|rides_store| rides_store.borrow().clone())
+impl Pricing {
+    /// apply the multipliers, surcharge and promo discount to a trip fare in e8s
+    pub fn apply(&self, fare: u64) -> u64 {
+        let multiplier = self.fare_multiplier * self.surge_multiplier;
+        let fare = (fare as f64 * multiplier).round() as u64 + self.surcharge;
+        match self.discount {
+            Some(discount) => discount.apply(fare),
+            None => fare,
+        }
+    }
 }
 
-///get riders
-#[query]
-#[candid_method(query)]
-fn get_riders() -> RiderStore {
-    RIDER_STORE.with(|rider_store| rider_store.borrow().clone())
+/// amount a promo code takes off a fare
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum Discount {
+    Percent(u64),
+    /// e8s off, never below zero
+    Flat(u64),
 }
 
-///get drivers
-#[query]
-#[candid_method(query)]
-fn get_drivers() -> DriverStore {
-    DRIVER_STORE.with(|driver_store| driver_store.borrow().clone())
+impl Discount {
+    /// the discounted `fare` in e8s
+    pub fn apply(&self, fare: u64) -> u64 {
+        match *self {
+            Discount::Percent(percent) => fare * (100 - percent.min(100)) / 100,
+            Discount::Flat(amount) => fare.saturating_sub(amount),
+        }
+    }
 }
 
-///register rider
-#[update]
-#[candid_method(update)]
-fn register_rider(rider: Rider) {
-    RIDER_STORE.with(|rider_store| {
-        rider_store.borrow_mut().push(rider);
-    });
+/// a code riders can apply to a request for a discount
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PromoCode {
+    /// stored upper case, riders can enter it in any case
+    pub code: String,
+    pub discount: Discount,
+    /// redemptions across all riders, unlimited when not set
+    pub max_uses: Option<u64>,
+    /// redemptions per rider, unlimited when not set
+    pub per_user_limit: Option<u64>,
+    pub expires_at: u64,
+    /// zones the pickup must be in, any zone when empty
+    pub zone_ids: Vec<String>,
+    /// vehicle types of the dispatched driver, any vehicle when empty
+    pub vehicle_types: Vec<String>,
+    pub first_ride_only: bool,
 }
 
-///register driver
-#[update]
-#[candid_method(update)]
-fn register_driver(driver: Driver) {
-    DRIVER_STORE.with(|driver_store| {
-        driver_store.borrow_mut().push(driver);
-    });
+/// a promo code used on a ride, it stops counting if the ride is cancelled
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PromoRedemption {
+    pub code: String,
+    pub rider: String,
+    pub ride_id: String,
+    pub redeemed_at: u64,
 }
 
-/// update driver rating value
-#[update]
-#[candid_method(update)]
-fn update_driver_rating(driver_name: String, rating: f64) {
-    DRIVER_STORE.with(|driver_store| {
-        for driver in driver_store.borrow_mut().iter_mut() {
-            if driver.name == driver_name {
-                driver.rating = rating;
+/// a spot inside a zone where riders cannot be picked up
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RestrictedPickup {
+    pub name: String,
+    pub center: Location,
+    pub radius_km: f64,
+}
+
+/// an area the service operates in, with its own pricing rules
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ServiceZone {
+    pub zone_id: String,
+    pub name: String,
+    /// corners of the area in order, the last joins back to the first
+    pub polygon: Vec<Location>,
+    pub enabled: bool,
+    pub fare_multiplier: f64,
+    /// added in e8s when the pickup or dropoff is in this zone, for airports
+    pub airport_surcharge: u64,
+    pub restricted_pickups: Vec<RestrictedPickup>,
+}
+
+impl ServiceZone {
+    /// check the zone has an area and sensible settings
+    pub fn validate(&self) -> Result<(), String> {
+        if self.zone_id.is_empty() {
+            return Err("Zone id is required".to_string());
+        }
+        if self.polygon.len() < 3 {
+            return Err(format!("Zone {} needs at least 3 corners", self.zone_id));
+        }
+        for corner in &self.polygon {
+            corner.validate()?;
+        }
+        if !(self.fare_multiplier > 0.0 && self.fare_multiplier.is_finite()) {
+            return Err(format!("Zone {} needs a positive fare multiplier", self.zone_id));
+        }
+        for restricted in &self.restricted_pickups {
+            restricted.center.validate()?;
+            if !(restricted.radius_km > 0.0 && restricted.radius_km.is_finite()) {
+                let name = &restricted.name;
+                return Err(format!("Restricted pickup {} needs a positive radius", name));
             }
         }
-    });
+        Ok(())
+    }
+
+    /// whether `location` is inside the zone
+    pub fn contains(&self, location: &Location) -> bool {
+        point_in_polygon(location, &self.polygon)
+    }
+
+    /// the restricted pickup spot covering `location`, if any
+    pub fn restricted_at(&self, location: &Location) -> Option<&RestrictedPickup> {
+        self.restricted_pickups
+            .iter()
+            .find(|restricted| distance_km(&restricted.center, location) <= restricted.radius_km)
+    }
 }
 
-/// update driver status value
-#[query]
-#[candid_method(query)]
-fn update_driver_status(driver_name: String, status: CurrentStatus) {
-    DRIVER_STORE.with(|driver_store| {
-        for driver in driver_store.borrow_mut().iter_mut() {
-            if driver.name == driver_name {
-                driver.currentstatus = status;
-            }
+/// party that cancelled a ride
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum CancelledBy {
+    Rider,
+    Driver,
+    System,
+}
+
+/// record of a cancelled ride, kept on the ride for history and settlement
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Cancellation {
+    pub cancelled_by: CancelledBy,
+    pub canceller: String,
+    pub reason: String,
+    /// fee in e8s owed by the rider
+    pub fee: u64,
+    /// penalty in e8s owed by the driver
+    pub driver_penalty: u64,
+}
+
+/// cancellation fee policy, amounts in e8s and windows in nanoseconds
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CancellationPolicy {
+    /// riders cancel for free within this window after the ride is dispatched
+    pub free_window: u64,
+    /// fee charged to a rider who cancels once a driver is en route
+    pub rider_fee: u64,
+    /// how long after accepting the driver has to arrive at the pickup before counting as a
+    /// no-show
+    pub no_show_wait: u64,
+    /// penalty charged to a driver who did not arrive in time, or who cancelled an accepted
+    /// ride after the free window
+    pub driver_no_show_penalty: u64,
+}
+
+impl Default for CancellationPolicy {
+    fn default() -> CancellationPolicy {
+        CancellationPolicy {
+            free_window: 2 * NANOS_PER_MINUTE,
+            rider_fee: 5_000_000,
+            no_show_wait: 10 * NANOS_PER_MINUTE,
+            driver_no_show_penalty: 5_000_000,
         }
-    });
+    }
 }
 
-/// search for driver by name and return the driver
-#[query]
-#[candid_method(query)]
-fn search_driver_by_name(driver_name: String) -> Option<Driver> {
-    DRIVER_STORE.with(|driver_store| {
-        for driver in driver_store.borrow().iter() {
-            if driver.name == driver_name {
-                return Some(driver.clone());
-            }
+impl CancellationPolicy {
+    /// work out the rider fee and driver penalty for cancelling `ride` at `now`
+    pub fn assess(&self, ride: &Ride, cancelled_by: CancelledBy, now: u64) -> (u64, u64) {
+        if ride.status != RideStatus::Accepted {
+            return (0, 0);
         }
-        None
-    })
+        let dispatched_at = ride.dispatched_at.unwrap_or(ride.requested_at);
+        let accepted_at = ride.accepted_at.unwrap_or(dispatched_at);
+        if cancelled_by == CancelledBy::Driver {
+            //a driver backing out leaves the rider stranded just like a no-show
+            return if now.saturating_sub(dispatched_at) <= self.free_window {
+                (0, 0)
+            } else {
+                (0, self.driver_no_show_penalty)
+            };
+        }
+        if cancelled_by != CancelledBy::Rider {
+            return (0, 0);
+        }
+        //the driver reports arriving at the pickup as its first stop
+        let arrived_in_time = ride
+            .waypoints
+            .first()
+            .and_then(|pickup| pickup.arrived_at)
+            .is_some_and(|arrived_at| arrived_at.saturating_sub(accepted_at) <= self.no_show_wait);
+        if now.saturating_sub(dispatched_at) <= self.free_window {
+            (0, 0)
+        } else if !arrived_in_time && now.saturating_sub(accepted_at) > self.no_show_wait {
+            (0, self.driver_no_show_penalty)
+        } else {
+            (self.rider_fee, 0)
+        }
+    }
 }
 
-/// search for driver by contect and return the driver
-#[query]
-#[candid_method(query)]
-fn search_driver_by_contact(contact: String) -> Option<Driver> {
-    DRIVER_STORE.with(|driver_store| {
-        for driver in driver_store.borrow().iter() {
-            if driver.contact == contact {
-                return Some(driver.clone());
-            }
+/// a point on the map, matching the pickup and dropoff json sent by the frontend
+#[derive(PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct Location {
+    pub address_text: String,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+impl Location {
+    /// a location with the coordinates and address removed
+    pub fn scrubbed() -> Location {
+        Location {
+            address_text: "".to_string(),
+            lat: 0.0,
+            lng: 0.0,
         }
-        None
-    })
+    }
+
+    /// check the coordinates are on the globe
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lng) {
+            return Err(format!("Invalid coordinates for {}", self.address_text));
+        }
+        Ok(())
+    }
 }
 
-///search for driver by address and return the driver
-#[query]
-#[candid_method(query)]
-fn search_driver_by_address(principal_id: String) -> Option<Driver> {
-    DRIVER_STORE.with(|driver_store| {
-        for driver in driver_store.borrow().iter() {
-            if driver.address == principal_id {
-                return Some(driver.clone());
-            }
+/// arrival status of a stop on the route
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum StopStatus {
+    Pending,
+    Arrived,
+}
+
+/// a stop on the ride route, the first is the pickup and the last the dropoff
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Waypoint {
+    pub location: Location,
+    pub status: StopStatus,
+    pub arrived_at: Option<u64>,
+}
+
+impl Waypoint {
+    pub fn new(location: Location) -> Waypoint {
+        Waypoint {
+            location,
+            status: StopStatus::Pending,
+            arrived_at: None,
         }
-        None
-    })
+    }
 }
 
-///search for rider by address and return the rider
-#[query]
-#[candid_method(query)]
-fn search_rider_by_address(principal_id: String) -> Option<Rider> {
-    RIDER_STORE.with(|rider_store| {
-        for rider in rider_store.borrow().iter() {
-            if rider.address.to_string() == principal_id {
-                return Some(rider.clone());
+/// what a rider asks for when requesting or scheduling a ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RideRequest {
+    pub pickup: Location,
+    pub dropoff: Location,
+    /// intermediate stops in the order they are visited
+    pub stops: Vec<Location>,
+    pub promo_code: Option<String>,
+    /// surge multiplier the rider was quoted, the request is refused if surge has risen above it
+    pub quoted_surge: Option<f64>,
+}
+
+impl RideRequest {
+    /// all locations of the request in route order
+    pub fn route(&self) -> Vec<Location> {
+        let mut route = vec![self.pickup.clone()];
+        route.extend(self.stops.iter().cloned());
+        route.push(self.dropoff.clone());
+        route
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.route().iter().try_for_each(|location| location.validate())
+    }
+}
+
+/// change to the stops of a ride, indexes are positions in the waypoint list
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum StopChange {
+    Add { index: u64, location: Location },
+    Remove { index: u64 },
+}
+
+/// stop change waiting for the other party to confirm
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PendingStopChange {
+    pub change: StopChange,
+    pub proposed_by: String,
+    pub proposed_at: u64,
+}
+
+/// whether a pool stop picks a rider up or drops them off
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum PoolStopKind {
+    Pickup,
+    Dropoff,
+}
+
+/// a stop on a pooled trip belonging to one rider's ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PoolStop {
+    pub ride_id: String,
+    pub kind: PoolStopKind,
+    pub location: Location,
+    pub visited: bool,
+}
+
+/// a driver's pooled trip carrying the legs of several riders
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Pool {
+    pub pool_id: String,
+    pub driver: String,
+    pub seats: u64,
+    pub route: Vec<PoolStop>,
+    pub created_at: u64,
+}
+
+impl Pool {
+    /// the pool still has stops to visit
+    pub fn is_open(&self) -> bool {
+        self.route.iter().any(|stop| !stop.visited)
+    }
+
+    /// distance along the whole of `route` in kilometres
+    fn route_distance(route: &[PoolStop]) -> f64 {
+        route
+            .windows(2)
+            .map(|leg| distance_km(&leg[0].location, &leg[1].location))
+            .sum()
+    }
+
+    /// every rider stays within the detour limit and the car never holds more than the seats
+    fn is_feasible(route: &[PoolStop], seats: u64, max_detour_ratio: f64) -> bool {
+        let mut onboard = 0;
+        for (index, stop) in route.iter().enumerate() {
+            match stop.kind {
+                PoolStopKind::Pickup => {
+                    onboard += 1;
+                    if onboard > seats {
+                        return false;
+                    }
+                    let dropoff = route.iter().position(|other| {
+                        other.ride_id == stop.ride_id && other.kind == PoolStopKind::Dropoff
+                    });
+                    if let Some(dropoff) = dropoff {
+                        let direct = distance_km(&stop.location, &route[dropoff].location);
+                        let pooled = Pool::route_distance(&route[index..=dropoff]);
+                        if pooled > direct * max_detour_ratio + f64::EPSILON {
+                            return false;
+                        }
+                    }
+                }
+                PoolStopKind::Dropoff => onboard = onboard.saturating_sub(1),
             }
         }
-        None
-    })
+        true
+    }
+
+    /// cheapest feasible route with the new rider's pickup and dropoff inserted after
+    /// the stops already visited, with the extra distance it adds
+    fn plan_insertion(
+        &self,
+        ride_id: &str,
+        request: &RideRequest,
+        max_detour_ratio: f64,
+    ) -> Option<(Vec<PoolStop>, f64)> {
+        let current = Pool::route_distance(&self.route);
+        let first = self
+            .route
+            .iter()
+            .rposition(|stop| stop.visited)
+            .map_or(0, |index| index + 1);
+        let stop = |kind, location: &Location| PoolStop {
+            ride_id: ride_id.to_string(),
+            kind,
+            location: location.clone(),
+            visited: false,
+        };
+        let mut best: Option<(Vec<PoolStop>, f64)> = None;
+        for pickup in first..=self.route.len() {
+            for dropoff in pickup + 1..=self.route.len() + 1 {
+                let mut route = self.route.clone();
+                route.insert(pickup, stop(PoolStopKind::Pickup, &request.pickup));
+                route.insert(dropoff, stop(PoolStopKind::Dropoff, &request.dropoff));
+                if !Pool::is_feasible(&route, self.seats, max_detour_ratio) {
+                    continue;
+                }
+                let added = Pool::route_distance(&route) - current;
+                if best.as_ref().is_none_or(|(_, best_added)| added < *best_added) {
+                    best = Some((route, added));
+                }
+            }
+        }
+        best
+    }
 }
 
-impl Rider {
-    /// create a new rider
-    pub fn new(
-        name: String,
-        contact: String,
-        email: String,
-        role: String,
-        address: String,
-    ) -> Rider {
-        Rider {
-            name,
-            contact,
-            email,
-            role,
-            address,
-        } //create a new rider
+/// pooled ride settings
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PoolPolicy {
+    /// a rider's pooled leg may be at most this many times their direct distance
+    pub max_detour_ratio: f64,
+    /// percentage taken off each rider's fare
+    pub discount_percent: u64,
+    /// riders per pool when the driver's seat count is not a number
+    pub max_riders: u64,
+}
+
+impl Default for PoolPolicy {
+    fn default() -> PoolPolicy {
+        PoolPolicy {
+            max_detour_ratio: 1.5,
+            discount_percent: 25,
+            max_riders: 3,
+        }
     }
-    pub fn get_name(&self) -> String {
-        self.name.clone()
+}
+
+impl PoolPolicy {
+    /// a rider's discounted share of `fare`
+    pub fn discounted(&self, fare: u64) -> u64 {
+        fare * (100 - self.discount_percent.min(100)) / 100
     }
-    pub fn name(&self) -> &str {
-        &self.name
-    } // get the name of the rider
-    pub fn contact(&self) -> &str {
-        &self.contact
-    } // get the contact of the rider
-    pub fn email(&self) -> &str {
-        &self.email
-    } // get the email of the rider
-    pub fn role(&self) -> &str {
-        &self.role
-    } // get the role of the rider
-    pub fn address(&self) -> &str {
-        &self.address
-    } // get the address of the rider
-    pub fn get_field(&self, field: String) -> String {
-        match field.as_str() {
-            "name" => self.name.clone(),
-            "contact" => self.contact.clone(),
-            "email" => self.email.clone(),
-            "role" => self.role.clone(),
-            "address" => self.address.clone(),
-            _ => "".to_string(),
+}
+
+/// surge pricing settings
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SurgePolicy {
+    /// how much each extra open request per on-duty driver adds to the multiplier
+    pub sensitivity: f64,
+    /// highest multiplier riders can be charged
+    pub max_multiplier: f64,
+    /// nanoseconds between recomputing the multipliers
+    pub update_interval: u64,
+}
+
+impl Default for SurgePolicy {
+    fn default() -> SurgePolicy {
+        SurgePolicy {
+            sensitivity: 0.5,
+            max_multiplier: 2.5,
+            update_interval: NANOS_PER_MINUTE,
         }
-    } // get the field of the rider
-} // end of impl Rider
+    }
+}
 
-impl Driver {
-    // create a new driver
-    pub fn new(
-        name: String,
-        contact: String,
-        email: String,
-        role: String,
-        vehicleplatenumber: String,
-        vehicleseatnumber: String,
-        vehiclemake: String,
-        vehiclemodel: String,
-        vehiclecolor: String,
-        vehicletype: String,
-        vehicleyear: String,
-        rating: f64,
-        currentstatus: CurrentStatus,
-        address: String,
-    ) -> Driver {
-        Driver {
-            name,
-            contact,
-            email,
-            role,
-            vehicleplatenumber,
-            vehicleseatnumber,
-            vehiclemake,
-            vehiclemodel,
-            vehiclecolor,
-            vehicletype,
-            vehicleyear,
-            rating,
-            currentstatus,
+impl SurgePolicy {
+    /// check the cap does not discount fares
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.sensitivity >= 0.0 && self.sensitivity.is_finite()) {
+            return Err("Surge sensitivity cannot be negative".to_string());
+        }
+        if !(self.max_multiplier >= 1.0 && self.max_multiplier.is_finite()) {
+            return Err("Surge cap must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    /// multiplier for `requests` open requests shared by `drivers` on-duty drivers
+    pub fn multiplier(&self, requests: u64, drivers: u64) -> f64 {
+        let ratio = requests as f64 / drivers.max(1) as f64;
+        let surge = 1.0 + self.sensitivity * (ratio - 1.0).max(0.0);
+        //steps of 0.1 keep quotes steady while demand wobbles
+        ((surge * 10.0).round() / 10.0).min(self.max_multiplier)
+    }
+}
+
+/// current surge multipliers by zone id, the empty id covers everywhere when no zones exist
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct SurgeTable {
+    pub updated_at: u64,
+    pub multipliers: BTreeMap<String, f64>,
+}
+
+/// scheduled ride policy, times in nanoseconds
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SchedulingPolicy {
+    /// dispatch starts this long before the scheduled pickup
+    pub dispatch_lead_time: u64,
+    /// earliest a pickup can be booked ahead of now
+    pub min_advance: u64,
+    /// latest a pickup can be booked ahead of now
+    pub max_advance: u64,
+}
+
+impl Default for SchedulingPolicy {
+    fn default() -> SchedulingPolicy {
+        SchedulingPolicy {
+            dispatch_lead_time: 15 * NANOS_PER_MINUTE,
+            min_advance: 30 * NANOS_PER_MINUTE,
+            max_advance: 30 * 24 * 60 * NANOS_PER_MINUTE,
+        }
+    }
+}
+
+/// single-use code the driver shows to the rider to start a ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct StartCode {
+    pub code: String,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub used: bool,
+    pub attempts: u64,
+}
+
+impl StartCode {
+    /// six digit code from random bytes, valid for START_CODE_TTL from `now`
+    pub fn new(entropy: &[u8], now: u64) -> StartCode {
+        let seed = entropy
+            .iter()
+            .take(8)
+            .fold(0u64, |seed, byte| (seed << 8) | *byte as u64);
+        StartCode {
+            code: format!("{:06}", seed % 1_000_000),
+            issued_at: now,
+            expires_at: now + START_CODE_TTL,
+            used: false,
+            attempts: 0,
+        }
+    }
+
+    /// the code can still be used to start the ride
+    pub fn is_valid(&self, now: u64) -> bool {
+        !self.used && now <= self.expires_at && self.attempts < START_CODE_MAX_ATTEMPTS
+    }
+}
+
+/// payment received from the rider for a ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Payment {
+    pub block_height: BlockHeight,
+    pub amount: u64,
+    pub paid_at: u64,
+}
+
+/// tip from the rider, paid to the canister and owed in full to the driver
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Tip {
+    pub block_height: BlockHeight,
+    pub amount: u64,
+    pub tipped_at: u64,
+}
+
+/// why support changed what a rider paid
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum AdjustmentReason {
+    Overcharge,
+    RouteIssue,
+    DriverNoShow,
+    ServiceQuality,
+    Safety,
+    Goodwill,
+    Other,
+}
+
+/// progress of a refund transfer back to the rider
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum RefundStatus {
+    Pending,
+    Paid,
+    Failed,
+}
+
+/// a refund of a paid ride, or a change to the fare of an unpaid one
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FareAdjustment {
+    /// e8s returned to the rider for refunds, the new fare less the old for fare changes
+    pub amount: i64,
+    pub reason: AdjustmentReason,
+    pub note: String,
+    pub issued_by: Principal,
+    pub issued_at: u64,
+    /// e8s taken back from the driver's earnings
+    pub driver_charged: u64,
+    /// set for refunds, which go out through the ledger
+    pub refund_status: Option<RefundStatus>,
+    pub block_height: Option<BlockHeight>,
+    /// unique across all rides, the memo of the refund's ledger transfer
+    pub refund_id: Option<u64>,
+}
+
+/// canister subaccount holding platform funds, refunds can come from here instead of escrow
+const TREASURY_SUBACCOUNT: Subaccount = Subaccount([1; 32]);
+
+/// how long after completion a rider can tip, in nanoseconds
+const TIP_WINDOW: u64 = 24 * 60 * NANOS_PER_MINUTE;
+
+/// what a driver earnings ledger entry is for
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum EarningKind {
+    Fare,
+    Tip,
+    CancellationFee,
+    PlatformFee,
+    Adjustment,
+    Payout,
+}
+
+impl fmt::Display for EarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            EarningKind::Fare => "fare",
+            EarningKind::Tip => "tip",
+            EarningKind::CancellationFee => "cancellation_fee",
+            EarningKind::PlatformFee => "platform_fee",
+            EarningKind::Adjustment => "adjustment",
+            EarningKind::Payout => "payout",
+        };
+        write!(f, "{}", kind)
+    }
+}
+
+/// one line of a driver's earnings, `amount` in e8s is negative for deductions
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EarningEntry {
+    pub seq: u64,
+    pub driver: String,
+    pub ride_id: Option<String>,
+    pub kind: EarningKind,
+    pub amount: i64,
+    pub note: String,
+    pub created_at: u64,
+}
+
+/// a driver's earnings for one week starting on monday
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EarningsStatement {
+    pub driver: String,
+    pub week_start: u64,
+    pub week_end: u64,
+    pub opening_balance: i64,
+    pub closing_balance: i64,
+    pub entries: Vec<EarningEntry>,
+}
+
+impl EarningsStatement {
+    /// the statement as csv, one row per entry
+    pub fn to_csv(&self) -> String {
+        let mut csv = "created_at,ride_id,kind,amount_e8s,note\n".to_string();
+        for entry in &self.entries {
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                entry.created_at,
+                entry.ride_id.as_deref().unwrap_or(""),
+                entry.kind,
+                entry.amount,
+                csv_field(&entry.note)
+            ));
+        }
+        csv
+    }
+}
+
+/// quote a csv field if it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+const NANOS_PER_WEEK: u64 = 7 * 24 * 60 * NANOS_PER_MINUTE;
+
+/// payout batch settings
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PayoutPolicy {
+    /// smallest balance in e8s paid out, it must cover the ledger fee
+    pub threshold: u64,
+    /// nanoseconds between batches
+    pub interval: u64,
+    /// transfers the ledger refuses are retried this many times before the balance is returned,
+    /// unanswered ones until PAYOUT_RETRY_WINDOW closes
+    pub max_attempts: u64,
+}
+
+/// wait before retrying a payout, doubled after each failed attempt
+const PAYOUT_RETRY_DELAY: u64 = NANOS_PER_MINUTE;
+/// payouts are only retried this long after they are created, inside the 24 hours the ledger
+/// recognises a repeat of the same memo and creation time as a duplicate
+const PAYOUT_RETRY_WINDOW: u64 = 23 * 60 * NANOS_PER_MINUTE;
+
+impl Default for PayoutPolicy {
+    fn default() -> PayoutPolicy {
+        PayoutPolicy {
+            threshold: 100_000_000,
+            interval: NANOS_PER_WEEK / 7,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// progress of a payout transfer
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum PayoutStatus {
+    Pending,
+    Paid,
+    Failed,
+    /// no answer from the ledger before the retry window closed, the balance stays taken until
+    /// finance finds out from the ledger whether it was paid
+    Unconfirmed,
+}
+
+/// why a payout transfer did not go through
+#[derive(Debug)]
+enum PayoutFailure {
+    /// the ledger refused it, which it would not have done had an earlier attempt gone through
+    Rejected(String),
+    /// no usable answer, this or an earlier attempt may still have gone through
+    Unknown(String),
+}
+
+/// a driver's balance on its way to their ledger account
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Payout {
+    /// also the ledger memo, so retries of the same payout are recognised as duplicates
+    pub payout_id: u64,
+    pub driver: String,
+    pub to: AccountIdentifier,
+    /// e8s taken from the balance, the ledger fee comes out of it
+    pub amount: u64,
+    pub status: PayoutStatus,
+    pub block_height: Option<BlockHeight>,
+    pub attempts: u64,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub in_flight: bool,
+    /// earliest time the next attempt is made after a failure
+    pub next_attempt_at: Option<u64>,
+}
+
+/// start of the monday to monday week holding `time`, the epoch fell on a thursday
+fn week_start(time: u64) -> u64 {
+    time.saturating_sub((time + 3 * NANOS_PER_WEEK / 7) % NANOS_PER_WEEK)
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub description: String,
+    pub keywords: Vec<String>,
+}
+
+/// everything the canister links to one principal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Account {
+    pub principal: Principal,
+    pub profile: Option<Profile>,
+    pub rider: Option<Rider>,
+    pub driver: Option<Driver>,
+}
+
+/// where a driver is in onboarding, only Approved drivers are dispatched
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum ApplicationStatus {
+    Submitted,
+    DocumentsPending,
+    UnderReview,
+    Approved,
+    Rejected,
+    Suspended,
+}
+
+/// documents every driver must keep on file
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum DocumentKind {
+    Licence,
+    Insurance,
+    Registration,
+}
+
+const REQUIRED_DOCUMENTS: [DocumentKind; 3] = [
+    DocumentKind::Licence,
+    DocumentKind::Insurance,
+    DocumentKind::Registration,
+];
+
+/// a driver document stored off-chain, referenced by its sha-256 hash
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverDocument {
+    pub kind: DocumentKind,
+    pub content_hash: String,
+    pub expires_at: u64,
+}
+
+/// whether `value` looks like a hex sha-256 digest
+fn is_sha256_hex(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// a driver's onboarding record, keyed by their principal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverApplication {
+    pub address: String,
+    pub status: ApplicationStatus,
+    pub documents: Vec<DriverDocument>,
+    pub submitted_at: u64,
+    pub reviewed_at: Option<u64>,
+    pub reviewer: Option<String>,
+    pub note: String,
+}
+
+impl DriverApplication {
+    pub fn new(address: String, now: u64) -> DriverApplication {
+        DriverApplication {
             address,
+            status: ApplicationStatus::Submitted,
+            documents: vec![],
+            submitted_at: now,
+            reviewed_at: None,
+            reviewer: None,
+            note: "".to_string(),
         }
     }
-    pub fn get_name(&self) -> String {
-        self.name.clone()
-    } // get the name of the driver
-    pub fn name(&self) -> &str {
-        &self.name
-    } // get the name of the driver
-    pub fn contact(&self) -> &str {
-        &self.contact
-    } // get the contact of the driver
-    pub fn email(&self) -> &str {
-        &self.email
-    } // get the email of the driver
-    pub fn role(&self) -> &str {
-        &self.role
-    } // get the role of the driver
-    pub fn vehicleplatenumber(&self) -> &str {
-        &self.vehicleplatenumber
-    } // get the vehicleplatenumber of the driver
-    pub fn vehicleseatnumber(&self) -> &str {
-        &self.vehicleseatnumber
-    } // get the vehicleseatnumber of the driver
-    pub fn vehiclemake(&self) -> &str {
-        &self.vehiclemake
-    } // get the vehiclemake of the driver
-    pub fn vehiclemodel(&self) -> &str {
-        &self.vehiclemodel
-    } // get the vehiclemodel of the driver
-    pub fn vehiclecolor(&self) -> &str {
-        &self.vehiclecolor
-    } // get the vehiclecolor of the driver
-    pub fn vehicletype(&self) -> &str {
-        &self.vehicletype
-    } // get the vehicletype of the driver
-    pub fn vehicleyear(&self) -> &str {
-        &self.vehicleyear
-    } // get the vehicleyear of the driver
-    pub fn rating(&self) -> f64 {
-        self.rating
-    } // get the rating of the driver
-    pub fn currentstatus(&self) -> &CurrentStatus {
-        &self.currentstatus
-    } // get the currentstatus of the driver
-    pub fn address(&self) -> &str {
-        &self.address
-    } // get the address of the driver
-    pub fn get_field(&self, field: String) -> String {
-        match field.as_str() {
-            "name" => self.name.clone(),
-            "contact" => self.contact.clone(),
-            "email" => self.email.clone(),
-            "role" => self.role.clone(),
-            "vehicleplatenumber" => self.vehicleplatenumber.clone(),
-            "vehicleseatnumber" => self.vehicleseatnumber.clone(),
-            "vehiclemake" => self.vehiclemake.clone(),
-            "vehiclemodel" => self.vehiclemodel.clone(),
-            "vehiclecolor" => self.vehiclecolor.clone(),
-            "vehicletype" => self.vehicletype.clone(),
-            "vehicleyear" => self.vehicleyear.clone(),
-            "rating" => self.rating.to_string(),
-            "currentstatus" => self.currentstatus.to_string(),
-            "address" => self.address.clone(),
-            _ => "".to_string(),
+
+    /// every required document is on file and unexpired at `now`
+    pub fn documents_complete(&self, now: u64) -> bool {
+        REQUIRED_DOCUMENTS.iter().all(|kind| {
+            self.documents
+                .iter()
+                .any(|document| document.kind == *kind && document.expires_at > now)
+        })
+    }
+
+    /// replace the document of the same kind and move the application along
+    pub fn add_document(&mut self, document: DriverDocument, now: u64) -> Result<(), String> {
+        if !is_sha256_hex(&document.content_hash) {
+            return Err("Document content hash must be a hex sha-256".to_string());
         }
-    } // get the value in the field
-    //updates
-    pub fn update_name(&mut self, name: String) {
-        self.name = name;
-    } // update the name of the driver
-    pub fn update_contact(&mut self, contact: String) {
-        self.contact = contact;
-    } // update the contact of the driver
-    pub fn update_email(&mut self, email: String) {
-        self.email = email;
-    } // update the email of the driver
-    pub fn update_role(&mut self, role: String) {
-        self.role = role;
-    } // update the role of the driver
-    pub fn update_vehicleplatenumber(&mut self, vehicleplatenumber: String) {
-        self.vehicleplatenumber = vehicleplatenumber;
-    } // update the vehicleplatenumber of the driver
-    pub fn update_vehicleseatnumber(&mut self, vehicleseatnumber: String) {
-        self.vehicleseatnumber = vehicleseatnumber;
-    } // update the vehicleseatnumber of the driver
-    pub fn update_vehiclemake(&mut self, vehiclemake: String) {
-        self.vehiclemake = vehiclemake;
-    } // update the vehiclemake of the driver
-    pub fn update_vehiclemodel(&mut self, vehiclemodel: String) {
-        self.vehiclemodel = vehiclemodel;
-    } // update the vehiclemodel of the driver
-    pub fn update_vehiclecolor(&mut self, vehiclecolor: String) {
-        self.vehiclecolor = vehiclecolor;
-    } // update the vehiclecolor of the driver
-    pub fn update_vehicletype(&mut self, vehicletype: String) {
-        self.vehicletype = vehicletype;
-    } // update the vehicletype of the driver
-    pub fn update_vehicleyear(&mut self, vehicleyear: String) {
-        self.vehicleyear = vehicleyear;
-    } // update the vehicleyear of the driver
-    pub fn update_rating(&mut self, rating: f64) {
-        self.rating = rating;
-    } // update the rating of the driver
-    pub fn update_currentstatus(&mut self, currentstatus: CurrentStatus) {
-        self.currentstatus = currentstatus;
-    } // update the currentstatus of the driver
-    pub fn update_address(&mut self, address: String) {
-        self.address = address;
-    } // update the address of the driver
-    pub fn update_field(&mut self, field: String, value: String) {
-        match field.as_str() {
-            "name" => self.name = value,
-            "contact" => self.contact = value,
-            "email" => self.email = value,
-            "role" => self.role = value,
-            "vehicleplatenumber" => self.vehicleplatenumber = value,
-            "vehicleseatnumber" => self.vehicleseatnumber = value,
-            "vehiclemake" => self.vehiclemake = value,
-            "vehiclemodel" => self.vehiclemodel = value,
-            "vehiclecolor" => self.vehiclecolor = value,
-            "vehicletype" => self.vehicletype = value,
-            "vehicleyear" => self.vehicleyear = value,
-            "rating" => self.rating = value.parse().unwrap(),
-            "address" => self.address = value,
-            _ => (),
+        if document.expires_at <= now {
+            return Err("Document has already expired".to_string());
         }
-    } // update the field of the driver
-    //update whole driver
-    pub fn update_driver(&mut self, driver: Driver) {
+        if self.status == ApplicationStatus::UnderReview {
+            return Err("Application is under review".to_string());
+        }
+        self.documents.retain(|current| current.kind != document.kind);
+        self.documents.push(document);
+        //an approved driver renewing a document keeps driving
+        if self.status != ApplicationStatus::Approved {
+            self.status = if self.documents_complete(now) {
+                ApplicationStatus::UnderReview
+            } else {
+                ApplicationStatus::DocumentsPending
+            };
+        }
+        Ok(())
+    }
+}
+
+/// one state change, who made it and what it changed
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AuditEvent {
+    pub seq: u64,
+    pub caller: Principal,
+    pub timestamp: u64,
+    pub endpoint: String,
+    pub entity_id: String,
+    pub summary: String,
+}
+
+/// audit events to return, every set field must match
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AuditFilter {
+    pub entity_id: Option<String>,
+    pub principal: Option<Principal>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.entity_id.as_ref().is_none_or(|id| *id == event.entity_id)
+            && self.principal.is_none_or(|principal| principal == event.caller)
+            && self.from.is_none_or(|from| event.timestamp >= from)
+            && self.to.is_none_or(|to| event.timestamp < to)
+    }
+}
+
+/// a page of audit events and the cursor to pass for the next one
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AuditPage {
+    pub events: AuditLog,
+    pub next: Option<u64>,
+}
+
+/// most audit events returned by one query
+const MAX_AUDIT_PAGE: u64 = 100;
+/// most audit events one query reads looking for matches
+const MAX_AUDIT_SCAN: u64 = 5_000;
+
+/// audit events in stable memory, never loaded onto the heap
+const AUDIT_LOG: StableLog<AuditEvent> =
+    StableLog::new(memory::AUDIT_INDEX, memory::AUDIT_DATA);
+/// domain events in stable memory, the event with sequence number `seq` is entry `seq - 1`
+const EVENT_LOG: StableLog<DomainEvent> =
+    StableLog::new(memory::EVENT_INDEX, memory::EVENT_DATA);
+
+/// what happened to a ride
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum RideEvent {
+    RideRequested,
+    OfferSent,
+    RideAccepted,
+    PickedUp,
+    Completed,
+    Cancelled,
+    PaymentConfirmed,
+    TipReceived,
+    FareAdjusted,
+}
+
+/// a ride event clients poll for, `amount` is the fare, the cancellation fee, the payment,
+/// the tip, the refund or the adjusted fare; the parties are the ride's relay ids so events
+/// never carry principals
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DomainEvent {
+    pub seq: u64,
+    pub event: RideEvent,
+    pub ride_id: String,
+    pub rider: String,
+    pub driver: String,
+    pub status: RideStatus,
+    pub amount: u64,
+    pub timestamp: u64,
+}
+
+/// domain events to return, an empty `events` list means every kind
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct EventFilter {
+    pub ride_id: Option<String>,
+    pub events: Vec<RideEvent>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &DomainEvent) -> bool {
+        self.ride_id.as_ref().is_none_or(|id| *id == event.ride_id)
+            && (self.events.is_empty() || self.events.contains(&event.event))
+    }
+}
+
+/// a canister that has domain events pushed to its `on_ride_events` method
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Subscriber {
+    pub canister: Principal,
+    pub delivered: u64,
+    pub in_flight: bool,
+}
+
+/// most domain events returned by one query or pushed in one call
+const MAX_EVENT_BATCH: usize = 100;
+/// most domain events one query reads looking for matches
+const MAX_EVENT_SCAN: u64 = 5_000;
+
+/// domain events from one poll and the `seq` to poll from next
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EventPage {
+    pub events: Vec<DomainEvent>,
+    pub next: u64,
+}
+
+/// where a driver was and which way they were heading, in degrees from north
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DriverPosition {
+    pub location: Location,
+    pub heading: f64,
+    pub reported_at: u64,
+}
+
+/// what the rider sees of their driver while waiting and riding
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct RideTracking {
+    pub ride_id: String,
+    pub position: DriverPosition,
+    pub heading_to: Location,
+    pub eta: u64,
+}
+
+/// shortest gap between two location reports from a driver
+const LOCATION_REPORT_INTERVAL: u64 = 5_000_000_000;
+/// most positions kept per ride, older ones are thinned out past this
+const MAX_BREADCRUMBS: usize = 500;
+/// how long after a ride ends its breadcrumbs are kept, unless it is under an open dispute
+const BREADCRUMB_RETENTION: u64 = 30 * 24 * 60 * NANOS_PER_MINUTE;
+
+/// canned messages for a driver or rider on the move
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum QuickReply {
+    OnMyWay,
+    ImHere,
+    RunningLate,
+    CantFindYou,
+    Thanks,
+}
+
+impl QuickReply {
+    pub fn text(&self) -> &'static str {
+        match self {
+            QuickReply::OnMyWay => "On my way",
+            QuickReply::ImHere => "I'm here",
+            QuickReply::RunningLate => "Running a few minutes late",
+            QuickReply::CantFindYou => "I can't find you, where are you?",
+            QuickReply::Thanks => "Thanks!",
+        }
+    }
+}
+
+/// one message in a ride's chat thread, sent by the relay id of the rider, driver or support
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ChatMessage {
+    pub message_id: u64,
+    pub sender: String,
+    pub text: String,
+    pub quick_reply: Option<QuickReply>,
+    pub sent_at: u64,
+    pub read_at: Option<u64>,
+}
+
+/// the messages between a ride's rider and driver, read-only once archived
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ChatThread {
+    pub ride_id: String,
+    pub messages: Vec<ChatMessage>,
+    pub archived_at: Option<u64>,
+}
+
+/// longest chat message in characters
+const MAX_CHAT_MESSAGE: usize = 500;
+
+/// a temporary suspension or a permanent ban
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum SanctionKind {
+    Suspension,
+    Ban,
+}
+
+/// which accounts of a principal a sanction applies to
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum SanctionScope {
+    Rider,
+    Driver,
+    Both,
+}
+
+impl SanctionScope {
+    pub fn covers(&self, role: Role) -> bool {
+        matches!(
+            (self, role),
+            (SanctionScope::Both, _)
+                | (SanctionScope::Rider, Role::Rider)
+                | (SanctionScope::Driver, Role::Driver)
+        )
+    }
+}
+
+/// note added to a sanction by the sanctioned principal or an operator
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AppealNote {
+    pub author: String,
+    pub note: String,
+    pub added_at: u64,
+}
+
+/// a suspension or ban placed on a principal by an operator
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Sanction {
+    pub sanction_id: u64,
+    pub principal: Principal,
+    pub kind: SanctionKind,
+    pub scope: SanctionScope,
+    pub reason: String,
+    pub issued_by: String,
+    pub issued_at: u64,
+    pub expires_at: Option<u64>,
+    pub lifted_at: Option<u64>,
+    pub appeal_notes: Vec<AppealNote>,
+}
+
+impl Sanction {
+    /// the sanction has not been lifted and has not run out at `now`
+    pub fn is_active(&self, now: u64) -> bool {
+        self.lifted_at.is_none() && self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// a disagreement about a ride, or a report of something that happened on it
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum DisputeKind {
+    Dispute,
+    Incident,
+}
+
+/// what a dispute or incident report is about
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum DisputeCategory {
+    Fare,
+    Route,
+    Conduct,
+    Safety,
+    LostItem,
+    Vehicle,
+    Other,
+}
+
+impl DisputeCategory {
+    /// reason code for refunds given when resolving a report in this category
+    pub fn adjustment_reason(&self) -> AdjustmentReason {
+        match self {
+            DisputeCategory::Fare => AdjustmentReason::Overcharge,
+            DisputeCategory::Route => AdjustmentReason::RouteIssue,
+            DisputeCategory::Conduct | DisputeCategory::Vehicle => AdjustmentReason::ServiceQuality,
+            DisputeCategory::Safety => AdjustmentReason::Safety,
+            DisputeCategory::LostItem | DisputeCategory::Other => AdjustmentReason::Other,
+        }
+    }
+}
+
+/// where a dispute is in the support queue
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum DisputeStatus {
+    Open,
+    Investigating,
+    Resolved,
+    Rejected,
+}
+
+/// one side of a ride
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum RideParty {
+    Rider,
+    Driver,
+}
+
+/// suspension a resolution places on one side of the ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DisputeSuspension {
+    pub party: RideParty,
+    /// open-ended when not set
+    pub expires_at: Option<u64>,
+}
+
+/// how support closed a dispute
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DisputeResolution {
+    /// resolved in the reporter's favour, otherwise rejected
+    pub upheld: bool,
+    pub note: String,
+    /// e8s refunded to the rider of a paid ride
+    pub refund: Option<u64>,
+    /// take the driver's share of the refund back from their earnings
+    pub charge_driver: bool,
+    pub suspension: Option<DisputeSuspension>,
+}
+
+/// a dispute or incident report opened by the rider or driver of a ride
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Dispute {
+    pub dispute_id: u64,
+    pub ride_id: String,
+    pub reporter: String,
+    pub reporter_party: RideParty,
+    pub kind: DisputeKind,
+    pub category: DisputeCategory,
+    pub description: String,
+    /// hex sha-256 digests of photos, receipts and the like held off-chain
+    pub evidence: Vec<String>,
+    pub status: DisputeStatus,
+    pub assignee: Option<Principal>,
+    pub opened_at: u64,
+    pub updated_at: u64,
+    pub resolution: Option<DisputeResolution>,
+    pub refund_block: Option<BlockHeight>,
+    pub sanction_id: Option<u64>,
+}
+
+const MAX_DISPUTE_DESCRIPTION: usize = 2000;
+const MAX_DISPUTE_EVIDENCE: usize = 10;
+/// most notifications kept for one recipient
+const MAX_NOTIFICATIONS: usize = 100;
+
+/// a message for a rider or driver, the oldest go once they have MAX_NOTIFICATIONS
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Notification {
+    pub seq: u64,
+    pub recipient: String,
+    pub ride_id: Option<String>,
+    pub text: String,
+    pub created_at: u64,
+    pub read: bool,
+}
+
+/// what a principal is allowed to do in the canister
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum Role {
+    SuperAdmin,
+    Support,
+    Finance,
+    Driver,
+    Rider,
+}
+
+impl Role {
+    /// roles granted by controllers rather than by registering
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Role::SuperAdmin | Role::Support | Role::Finance)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::SuperAdmin => write!(f, "superadmin"),
+            Role::Support => write!(f, "support"),
+            Role::Finance => write!(f, "finance"),
+            Role::Driver => write!(f, "driver"),
+            Role::Rider => write!(f, "rider"),
+        }
+    }
+}
+
+/// longest handle a profile can claim
+const MAX_HANDLE_LENGTH: usize = 32;
+
+/// handles are unique ignoring case and limited to letters, digits, '_' and '-'
+fn normalize_handle(name: &str) -> Result<String, String> {
+    let handle = name.trim().to_lowercase();
+    if handle.is_empty() || handle.len() > MAX_HANDLE_LENGTH {
+        return Err(format!("Handle must be 1 to {} characters", MAX_HANDLE_LENGTH));
+    }
+    if !handle
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("Handle may only contain letters, digits, '_' and '-'".to_string());
+    }
+    Ok(handle)
+}
+
+/// the caller, rejecting the anonymous principal
+fn authenticated_caller() -> Result<Principal, String> {
+    let caller = ic::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous callers must sign in first".to_string());
+    }
+    Ok(caller)
+}
+
+/// the rider record registered by the caller
+fn caller_rider() -> Result<Rider, String> {
+    let caller = authenticated_caller()?;
+    ensure_not_sanctioned(caller, Role::Rider)?;
+    find_rider(&caller.to_text())
+        .ok_or_else(|| "Register as a rider first".to_string())
+}
+
+thread_local! {
+    static PROFILE_STORE: RefCell<ProfileStore> = RefCell::default();
+    static ID_STORE: RefCell<IdStore> = RefCell::default();
+    static DRIVER_STORE: RefCell<DriverStore> = RefCell::default();
+    static RIDER_STORE: RefCell<RiderStore> = RefCell::default();
+    static RIDES_STORE: RefCell<RidesStore> = RefCell::default();
+    static RIDE_COUNTER: RefCell<u64> = RefCell::default();
+    static REFUND_COUNTER: RefCell<u64> = RefCell::default();
+    static FARE_CONFIG: RefCell<FareConfig> = RefCell::default();
+    static GEO_CONFIG: RefCell<GeoConfig> = RefCell::default();
+    static ZONE_STORE: RefCell<ZoneStore> = RefCell::default();
+    static SURGE_POLICY: RefCell<SurgePolicy> = RefCell::default();
+    static SURGE: RefCell<SurgeTable> = RefCell::default();
+    static PROMO_CODES: RefCell<PromoStore> = RefCell::default();
+    static PROMO_REDEMPTIONS: RefCell<Vec<PromoRedemption>> = RefCell::default();
+    static EARNINGS: RefCell<EarningStore> = RefCell::default();
+    static PAYOUTS: RefCell<Vec<Payout>> = RefCell::default();
+    static PAYOUT_ACCOUNTS: RefCell<PayoutAccountStore> = RefCell::default();
+    static PAYOUT_POLICY: RefCell<PayoutPolicy> = RefCell::default();
+    static LAST_PAYOUT_BATCH: RefCell<u64> = RefCell::default();
+    static CANCELLATION_POLICY: RefCell<CancellationPolicy> = RefCell::default();
+    static SCHEDULING_POLICY: RefCell<SchedulingPolicy> = RefCell::default();
+    static POOL_STORE: RefCell<PoolStore> = RefCell::default();
+    static POOL_POLICY: RefCell<PoolPolicy> = RefCell::default();
+    static START_CODES: RefCell<StartCodeStore> = RefCell::default();
+    static USED_BLOCKS: RefCell<UsedBlocks> = RefCell::default();
+    static ROLE_STORE: RefCell<RoleStore> = RefCell::default();
+    static CONTROLLERS: RefCell<ControllerStore> = RefCell::default();
+    static DRIVER_APPLICATIONS: RefCell<DriverApplicationStore> = RefCell::default();
+    static SANCTIONS: RefCell<SanctionStore> = RefCell::default();
+    static DISPUTES: RefCell<DisputeStore> = RefCell::default();
+    static NOTIFICATIONS: RefCell<NotificationStore> = RefCell::default();
+    static SUBSCRIBERS: RefCell<SubscriberStore> = RefCell::default();
+    static CHAT_THREADS: RefCell<ChatStore> = RefCell::default();
+    static DRIVER_LOCATIONS: RefCell<DriverLocationStore> = RefCell::default();
+    static BREADCRUMBS: RefCell<BreadcrumbStore> = RefCell::default();
+}
+
+///the installer becomes the first controller and super admin
+#[init]
+fn init() {
+    seed_controller("init", ic::caller());
+}
+
+/// make `principal` a controller and super admin
+fn seed_controller(endpoint: &str, principal: Principal) {
+    CONTROLLERS.with(|controllers| controllers.borrow_mut().insert(principal));
+    grant_role(principal, Role::SuperAdmin);
+    audit(endpoint, &principal.to_text(), "installed as controller".to_string());
+}
+
+fn grant_role(principal: Principal, role: Role) {
+    ROLE_STORE.with(|role_store| {
+        role_store.borrow_mut().entry(principal).or_default().insert(role);
+    });
+}
+
+fn revoke_role(principal: Principal, role: Role) {
+    ROLE_STORE.with(|role_store| {
+        let mut role_store = role_store.borrow_mut();
+        if let Some(roles) = role_store.get_mut(&principal) {
+            roles.remove(&role);
+            if roles.is_empty() {
+                role_store.remove(&principal);
+            }
+        }
+    });
+}
+
+/// the principal holds `role`, super admins hold every admin role
+fn has_role(principal: Principal, role: Role) -> bool {
+    ROLE_STORE.with(|role_store| {
+        role_store.borrow().get(&principal).is_some_and(|roles| {
+            roles.contains(&role) || (role.is_admin() && roles.contains(&Role::SuperAdmin))
+        })
+    })
+}
+
+/// guard for endpoints only canister controllers may call
+fn is_controller() -> Result<(), String> {
+    if CONTROLLERS.with(|controllers| controllers.borrow().contains(&ic::caller())) {
+        Ok(())
+    } else {
+        Err("Caller is not a controller".to_string())
+    }
+}
+
+/// guard for endpoints that edit users and rides on someone else's behalf
+fn is_support() -> Result<(), String> {
+    if has_role(ic::caller(), Role::Support) {
+        Ok(())
+    } else {
+        Err("Caller is not a support operator".to_string())
+    }
+}
+
+/// guard for endpoints that change fees and money settings
+fn is_finance() -> Result<(), String> {
+    if has_role(ic::caller(), Role::Finance) {
+        Ok(())
+    } else {
+        Err("Caller is not a finance operator".to_string())
+    }
+}
+
+///grant an admin role to a principal
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn add_admin(principal: Principal, role: Role) -> Result<(), String> {
+    if !role.is_admin() {
+        return Err(format!("The {} role comes from registering", role));
+    }
+    grant_role(principal, role);
+    audit("add_admin", &principal.to_text(), format!("granted {}", role));
+    Ok(())
+}
+
+///take an admin role away from a principal
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn revoke_admin(principal: Principal, role: Role) -> Result<(), String> {
+    if !role.is_admin() {
+        return Err(format!("The {} role comes from registering", role));
+    }
+    revoke_role(principal, role);
+    audit("revoke_admin", &principal.to_text(), format!("revoked {}", role));
+    Ok(())
+}
+
+///get the canister controllers
+#[query(guard = "is_controller")]
+#[candid_method(query)]
+fn get_controllers() -> Vec<Principal> {
+    CONTROLLERS.with(|controllers| controllers.borrow().iter().copied().collect())
+}
+
+///add a canister controller
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn add_controller(principal: Principal) {
+    CONTROLLERS.with(|controllers| controllers.borrow_mut().insert(principal));
+    audit("add_controller", &principal.to_text(), "added as controller".to_string());
+}
+
+///remove a canister controller, the last one cannot be removed
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn remove_controller(principal: Principal) -> Result<(), String> {
+    CONTROLLERS.with(|controllers| {
+        let mut controllers = controllers.borrow_mut();
+        if !controllers.contains(&principal) {
+            return Err(format!("{} is not a controller", principal));
+        }
+        if controllers.len() == 1 {
+            return Err("The last controller cannot be removed".to_string());
+        }
+        controllers.remove(&principal);
+        Ok(())
+    })?;
+    audit("remove_controller", &principal.to_text(), "removed as controller".to_string());
+    Ok(())
+}
+
+///roles held by the caller
+#[query]
+#[candid_method(query)]
+fn get_my_roles() -> Vec<Role> {
+    ROLE_STORE.with(|role_store| {
+        role_store
+            .borrow()
+            .get(&ic::caller())
+            .map(|roles| roles.iter().copied().collect())
+            .unwrap_or_default()
+    })
+}
+
+///the caller's profile with their rider and driver accounts
+#[query]
+#[candid_method(query)]
+fn get_self() -> Account {
+    let principal = ic::caller();
+    Account {
+        principal,
+        profile: PROFILE_STORE
+            .with(|profile_store| profile_store.borrow().get(&principal).cloned()),
+        rider: find_rider(&principal.to_text()),
+        driver: find_driver(&principal.to_text()),
+    }
+}
+
+///look up a profile by its handle
+#[query]
+#[candid_method(query)]
+fn get(name: String) -> Profile {
+    let handle = normalize_handle(&name).unwrap_or_default();
+    ID_STORE.with(|id_store| {
+        PROFILE_STORE.with(|profile_store| {
+            id_store
+                .borrow()
+                .get(&handle)
+                .and_then(|id| profile_store.borrow().get(id).cloned())
+                .unwrap_or_default()
+        })
+    })
+}
+
+///set the caller's profile, claiming its name as a handle nobody else holds
+#[update]
+#[candid_method(update)]
+fn update(profile: Profile) -> Result<(), String> {
+    let principal_id = authenticated_caller()?;
+    for role in [Role::Rider, Role::Driver] {
+        if has_role(principal_id, role) {
+            ensure_not_sanctioned(principal_id, role)?;
+        }
+    }
+    let handle = normalize_handle(&profile.name)?;
+    ID_STORE.with(|id_store| {
+        let mut id_store = id_store.borrow_mut();
+        if id_store.get(&handle).is_some_and(|owner| *owner != principal_id) {
+            return Err(format!("Handle {} is already taken", handle));
+        }
+        //release the handle the caller held before
+        id_store.retain(|_, owner| *owner != principal_id);
+        id_store.insert(handle, principal_id);
+        Ok(())
+    })?;
+    let summary = format!("handle: {}", profile.name);
+    PROFILE_STORE.with(|profile_store| {
+        profile_store.borrow_mut().insert(principal_id, profile);
+    });
+    audit("update", &principal_id.to_text(), summary);
+    Ok(())
+}
+
+///get rides store   
+#[query]
+#[candid_method(query)]
+fn get_rides() -> RidesStore {
+    let viewer = Viewer::caller();
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow().iter().map(|ride| viewer.redacted_ride(ride)).collect()
+    })
+}
+
+///get riders
+#[query]
+#[candid_method(query)]
+fn get_riders() -> RiderStore {
+    let viewer = Viewer::caller();
+    RIDER_STORE.with(|rider_store| {
+        rider_store
+            .borrow()
+            .iter()
+            .map(|rider| rider.redacted(&viewer.visibility_of(&rider.address)))
+            .collect()
+    })
+}
+
+///get drivers
+#[query]
+#[candid_method(query)]
+fn get_drivers() -> DriverStore {
+    let viewer = Viewer::caller();
+    DRIVER_STORE.with(|driver_store| {
+        driver_store
+            .borrow()
+            .iter()
+            .map(|driver| driver.redacted(&viewer.visibility_of(&driver.address)))
+            .collect()
+    })
+}
+
+///register the caller as a rider
+#[update]
+#[candid_method(update)]
+fn register_rider(mut rider: Rider) -> Result<(), String> {
+    let caller = authenticated_caller()?;
+    ensure_not_sanctioned(caller, Role::Rider)?;
+    rider.address = caller.to_text();
+    rider.role = Role::Rider.to_string();
+    if find_rider(&rider.address).is_some() {
+        return Err("Caller is already registered as a rider".to_string());
+    }
+    audit("register_rider", &rider.address, "registered as rider".to_string());
+    RIDER_STORE.with(|rider_store| {
+        rider_store.borrow_mut().push(rider);
+    });
+    grant_role(caller, Role::Rider);
+    Ok(())
+}
+
+///register the caller as a driver
+#[update]
+#[candid_method(update)]
+fn register_driver(mut driver: Driver) -> Result<(), String> {
+    let caller = authenticated_caller()?;
+    ensure_not_sanctioned(caller, Role::Driver)?;
+    driver.address = caller.to_text();
+    driver.role = Role::Driver.to_string();
+    driver.rating = 0.0;
+    if find_driver(&driver.address).is_some() {
+        return Err("Caller is already registered as a driver".to_string());
+    }
+    DRIVER_APPLICATIONS.with(|applications| {
+        applications.borrow_mut().insert(
+            driver.address.clone(),
+            DriverApplication::new(driver.address.clone(), ic::time()),
+        );
+    });
+    audit("register_driver", &driver.address, "registered as driver".to_string());
+    DRIVER_STORE.with(|driver_store| {
+        driver_store.borrow_mut().push(driver);
+    });
+    grant_role(caller, Role::Driver);
+    Ok(())
+}
+
+/// run `f` on a driver application, auditing what it changed under `endpoint`
+fn with_application_mut<T>(
+    endpoint: &str,
+    address: &str,
+    f: impl FnOnce(&mut DriverApplication) -> Result<T, String>,
+) -> Result<T, String> {
+    DRIVER_APPLICATIONS.with(|applications| {
+        let mut applications = applications.borrow_mut();
+        let application = applications
+            .get_mut(address)
+            .ok_or_else(|| format!("No driver application for {}", address))?;
+        let before = application.clone();
+        let result = f(application)?;
+        audit(endpoint, address, diff_summary(&before, application));
+        Ok(result)
+    })
+}
+
+/// the driver has been approved and is not suspended or banned
+fn is_approved_driver(address: &str) -> bool {
+    let approved = DRIVER_APPLICATIONS.with(|applications| {
+        applications
+            .borrow()
+            .get(address)
+            .is_some_and(|application| application.status == ApplicationStatus::Approved)
+    });
+    approved
+        && Principal::from_text(address)
+            .is_ok_and(|principal| ensure_not_sanctioned(principal, Role::Driver).is_ok())
+}
+
+///driver uploads a licence, insurance or registration document reference
+#[update]
+#[candid_method(update)]
+fn submit_driver_document(document: DriverDocument) -> Result<ApplicationStatus, String> {
+    ensure_not_sanctioned(ic::caller(), Role::Driver)?;
+    let address = ic::caller().to_text();
+    with_application_mut("submit_driver_document", &address, |application| {
+        application.add_document(document, ic::time())?;
+        Ok(application.status)
+    })
+}
+
+///the caller's driver application
+#[query]
+#[candid_method(query)]
+fn get_my_driver_application() -> Option<DriverApplication> {
+    DRIVER_APPLICATIONS.with(|applications| {
+        applications.borrow().get(&ic::caller().to_text()).cloned()
+    })
+}
+
+///driver applications, optionally only those with a given status
+#[query(guard = "is_support")]
+#[candid_method(query)]
+fn get_driver_applications(status: Option<ApplicationStatus>) -> Vec<DriverApplication> {
+    DRIVER_APPLICATIONS.with(|applications| {
+        applications
+            .borrow()
+            .values()
+            .filter(|application| status.is_none_or(|status| application.status == status))
+            .cloned()
+            .collect()
+    })
+}
+
+///approve or reject a driver application that is under review
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn review_driver_application(address: String, approve: bool, note: String) -> Result<(), String> {
+    let now = ic::time();
+    with_application_mut("review_driver_application", &address, |application| {
+        if application.status != ApplicationStatus::UnderReview {
+            return Err(format!("Application is {:?}, not under review", application.status));
+        }
+        if approve && !application.documents_complete(now) {
+            return Err("Application is missing unexpired documents".to_string());
+        }
+        application.status = if approve {
+            ApplicationStatus::Approved
+        } else {
+            ApplicationStatus::Rejected
+        };
+        application.reviewed_at = Some(now);
+        application.reviewer = Some(ic::caller().to_text());
+        application.note = note;
+        Ok(())
+    })
+}
+
+///suspend an approved driver, they return to review by uploading documents again
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn suspend_driver(address: String, note: String) -> Result<(), String> {
+    with_application_mut("suspend_driver", &address, |application| {
+        if application.status != ApplicationStatus::Approved {
+            return Err(format!("Application is {:?}, not approved", application.status));
+        }
+        application.status = ApplicationStatus::Suspended;
+        application.reviewed_at = Some(ic::time());
+        application.reviewer = Some(ic::caller().to_text());
+        application.note = note;
+        Ok(())
+    })
+}
+
+/// suspend approved drivers whose documents have lapsed
+fn suspend_expired_drivers(now: u64) {
+    DRIVER_APPLICATIONS.with(|applications| {
+        for application in applications.borrow_mut().values_mut() {
+            if application.status == ApplicationStatus::Approved
+                && !application.documents_complete(now)
+            {
+                application.status = ApplicationStatus::Suspended;
+                application.reviewed_at = Some(now);
+                application.reviewer = None;
+                application.note = "A required document has expired".to_string();
+                audit("heartbeat", &application.address, "suspended, document expired".to_string());
+            }
+        }
+    });
+}
+
+/// active sanctions against the principal that cover `role`
+fn active_sanctions(principal: Principal, role: Role, now: u64) -> Vec<Sanction> {
+    SANCTIONS.with(|sanctions| {
+        sanctions
+            .borrow()
+            .iter()
+            .filter(|sanction| sanction.principal == principal && sanction.scope.covers(role))
+            .filter(|sanction| sanction.is_active(now))
+            .cloned()
+            .collect()
+    })
+}
+
+/// refuse callers suspended or banned as `role`, the driver can still complete a trip under way
+fn ensure_not_sanctioned(principal: Principal, role: Role) -> Result<(), String> {
+    match active_sanctions(principal, role, ic::time()).first() {
+        None => Ok(()),
+        Some(sanction) if sanction.kind == SanctionKind::Ban => {
+            Err(format!("Account is banned as a {}: {}", role, sanction.reason))
+        }
+        Some(sanction) => Err(format!(
+            "Account is suspended as a {}: {}",
+            role, sanction.reason
+        )),
+    }
+}
+
+fn add_sanction(
+    principal: Principal,
+    kind: SanctionKind,
+    scope: SanctionScope,
+    reason: String,
+    expires_at: Option<u64>,
+) -> Result<u64, String> {
+    let now = ic::time();
+    if reason.trim().is_empty() {
+        return Err("A reason is required".to_string());
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err("Expiry must be in the future".to_string());
+    }
+    SANCTIONS.with(|sanctions| {
+        let mut sanctions = sanctions.borrow_mut();
+        let sanction_id = sanctions.len() as u64;
+        let summary = format!("{:?} as {:?} for {}: {}", kind, scope, principal, reason);
+        audit("add_sanction", &format!("sanction-{}", sanction_id), summary);
+        sanctions.push(Sanction {
+            sanction_id,
+            principal,
+            kind,
+            scope,
+            reason,
+            issued_by: ic::caller().to_text(),
+            issued_at: now,
+            expires_at,
+            lifted_at: None,
+            appeal_notes: vec![],
+        });
+        Ok(sanction_id)
+    })
+}
+
+/// run `f` on a sanction, auditing what it changed under `endpoint`
+fn with_sanction_mut<T>(
+    endpoint: &str,
+    sanction_id: u64,
+    f: impl FnOnce(&mut Sanction) -> Result<T, String>,
+) -> Result<T, String> {
+    SANCTIONS.with(|sanctions| {
+        let mut sanctions = sanctions.borrow_mut();
+        let sanction = sanctions
+            .get_mut(sanction_id as usize)
+            .ok_or_else(|| format!("Sanction not found: {}", sanction_id))?;
+        let before = sanction.clone();
+        let result = f(sanction)?;
+        audit(endpoint, &format!("sanction-{}", sanction_id), diff_summary(&before, sanction));
+        Ok(result)
+    })
+}
+
+///suspend a principal as rider and/or driver, until `expires_at` when given
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn suspend_account(
+    principal: Principal,
+    scope: SanctionScope,
+    reason: String,
+    expires_at: Option<u64>,
+) -> Result<u64, String> {
+    add_sanction(principal, SanctionKind::Suspension, scope, reason, expires_at)
+}
+
+///permanently ban a principal as rider and/or driver
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn ban_account(principal: Principal, scope: SanctionScope, reason: String) -> Result<u64, String> {
+    add_sanction(principal, SanctionKind::Ban, scope, reason, None)
+}
+
+///lift a suspension or ban, recording why
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn lift_sanction(sanction_id: u64, note: String) -> Result<(), String> {
+    let now = ic::time();
+    with_sanction_mut("lift_sanction", sanction_id, |sanction| {
+        if sanction.lifted_at.is_some() {
+            return Err("Sanction has already been lifted".to_string());
+        }
+        sanction.lifted_at = Some(now);
+        sanction.appeal_notes.push(AppealNote {
+            author: ic::caller().to_text(),
+            note,
+            added_at: now,
+        });
+        Ok(())
+    })
+}
+
+///sanctioned principal or an operator adds a note to the appeal trail
+#[update]
+#[candid_method(update)]
+fn appeal_sanction(sanction_id: u64, note: String) -> Result<(), String> {
+    let caller = ic::caller();
+    if note.trim().is_empty() {
+        return Err("Appeal note cannot be empty".to_string());
+    }
+    with_sanction_mut("appeal_sanction", sanction_id, |sanction| {
+        if sanction.principal != caller && is_support().is_err() {
+            return Err("Caller cannot appeal this sanction".to_string());
+        }
+        sanction.appeal_notes.push(AppealNote {
+            author: caller.to_text(),
+            note,
+            added_at: ic::time(),
+        });
+        Ok(())
+    })
+}
+
+///sanctions ever placed on the caller
+#[query]
+#[candid_method(query)]
+fn get_my_sanctions() -> Vec<Sanction> {
+    get_sanctions_for(ic::caller())
+}
+
+///sanctions ever placed on a principal
+#[query(guard = "is_support")]
+#[candid_method(query)]
+fn get_sanctions(principal: Principal) -> Vec<Sanction> {
+    get_sanctions_for(principal)
+}
+
+fn get_sanctions_for(principal: Principal) -> Vec<Sanction> {
+    SANCTIONS.with(|sanctions| {
+        sanctions
+            .borrow()
+            .iter()
+            .filter(|sanction| sanction.principal == principal)
+            .cloned()
+            .collect()
+    })
+}
+
+///rider or driver of a ride opens a dispute or incident report about it
+#[update]
+#[candid_method(update)]
+fn open_dispute(
+    ride_id: String,
+    kind: DisputeKind,
+    category: DisputeCategory,
+    description: String,
+    evidence: Vec<String>,
+) -> Result<u64, String> {
+    let reporter = ic::caller().to_text();
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    let (reporter_party, counterparty) = if reporter == ride.rider.address {
+        (RideParty::Rider, ride.driver.address)
+    } else if reporter == ride.driver.address {
+        (RideParty::Driver, ride.rider.address)
+    } else {
+        return Err("Caller is not the rider or driver for this ride".to_string());
+    };
+    if !matches!(
+        ride.status,
+        RideStatus::Accepted | RideStatus::Active | RideStatus::Completed | RideStatus::Cancelled
+    ) {
+        return Err(format!("Ride cannot be reported while {}", ride.status));
+    }
+    let already_open = DISPUTES.with(|disputes| {
+        disputes.borrow().iter().any(|dispute| {
+            dispute.ride_id == ride_id
+                && dispute.reporter == reporter
+                && matches!(dispute.status, DisputeStatus::Open | DisputeStatus::Investigating)
+        })
+    });
+    if already_open {
+        return Err("You already have an open report for this ride".to_string());
+    }
+    let description = description.trim().to_string();
+    if description.is_empty() || description.chars().count() > MAX_DISPUTE_DESCRIPTION {
+        return Err(format!(
+            "Description must be between 1 and {} characters",
+            MAX_DISPUTE_DESCRIPTION
+        ));
+    }
+    if evidence.len() > MAX_DISPUTE_EVIDENCE {
+        return Err(format!("At most {} pieces of evidence", MAX_DISPUTE_EVIDENCE));
+    }
+    if !evidence.iter().all(|hash| is_sha256_hex(hash)) {
+        return Err("Evidence must be hex sha-256 hashes".to_string());
+    }
+    let now = ic::time();
+    let dispute_id = DISPUTES.with(|disputes| {
+        let mut disputes = disputes.borrow_mut();
+        let dispute_id = disputes.len() as u64;
+        disputes.push(Dispute {
+            dispute_id,
+            ride_id: ride_id.clone(),
+            reporter: reporter.clone(),
+            reporter_party,
+            kind,
+            category,
+            description,
+            evidence,
+            status: DisputeStatus::Open,
+            assignee: None,
+            opened_at: now,
+            updated_at: now,
+            resolution: None,
+            refund_block: None,
+            sanction_id: None,
+        });
+        dispute_id
+    });
+    let entity_id = format!("dispute-{}", dispute_id);
+    audit("open_dispute", &entity_id, format!("{:?} {:?} on {}", kind, category, ride_id));
+    let (ride_id, kind) = (Some(ride_id.as_str()), format!("{:?}", kind).to_lowercase());
+    notify(&reporter, ride_id, format!("Your {} report {} was received", kind, dispute_id));
+    notify(&counterparty, ride_id, format!("A {} report was opened about your ride", kind));
+    Ok(dispute_id)
+}
+
+///disputes and incident reports the caller opened
+#[query]
+#[candid_method(query)]
+fn get_my_disputes() -> Vec<Dispute> {
+    let reporter = ic::caller().to_text();
+    DISPUTES.with(|disputes| {
+        disputes.borrow().iter().filter(|dispute| dispute.reporter == reporter).cloned().collect()
+    })
+}
+
+///the support queue, optionally only disputes with `status`
+#[query(guard = "is_support")]
+#[candid_method(query)]
+fn get_disputes(status: Option<DisputeStatus>) -> Vec<Dispute> {
+    DISPUTES.with(|disputes| {
+        disputes
+            .borrow()
+            .iter()
+            .filter(|dispute| status.is_none_or(|status| dispute.status == status))
+            .cloned()
+            .collect()
+    })
+}
+
+fn with_dispute_mut<T>(
+    endpoint: &str,
+    dispute_id: u64,
+    f: impl FnOnce(&mut Dispute) -> Result<T, String>,
+) -> Result<T, String> {
+    DISPUTES.with(|disputes| {
+        let mut disputes = disputes.borrow_mut();
+        let dispute = disputes
+            .get_mut(dispute_id as usize)
+            .ok_or_else(|| format!("Dispute not found: {}", dispute_id))?;
+        let before = dispute.clone();
+        let result = f(dispute)?;
+        dispute.updated_at = ic::time();
+        audit(endpoint, &format!("dispute-{}", dispute_id), diff_summary(&before, dispute));
+        Ok(result)
+    })
+}
+
+///assign a dispute to a support agent, who starts investigating it
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn assign_dispute(dispute_id: u64, assignee: Principal) -> Result<(), String> {
+    if !has_role(assignee, Role::Support) {
+        return Err("Disputes can only be assigned to support agents".to_string());
+    }
+    let dispute = with_dispute_mut("assign_dispute", dispute_id, |dispute| {
+        if !matches!(dispute.status, DisputeStatus::Open | DisputeStatus::Investigating) {
+            return Err(format!("Dispute is already {:?}", dispute.status));
+        }
+        dispute.assignee = Some(assignee);
+        dispute.status = DisputeStatus::Investigating;
+        Ok(dispute.clone())
+    })?;
+    let text = format!("Your report {} is being investigated", dispute_id);
+    notify(&dispute.reporter, Some(&dispute.ride_id), text);
+    Ok(())
+}
+
+///close a dispute, refunding the rider and suspending a party when the resolution says so
+#[update(guard = "is_support")]
+#[candid_method(update)]
+async fn resolve_dispute(dispute_id: u64, resolution: DisputeResolution) -> Result<(), String> {
+    if !resolution.upheld && (resolution.refund.is_some() || resolution.suspension.is_some()) {
+        return Err("Only upheld disputes can refund or suspend".to_string());
+    }
+    //check the suspension can be placed before any money moves
+    let ride_id = DISPUTES
+        .with(|disputes| disputes.borrow().get(dispute_id as usize).map(|d| d.ride_id.clone()))
+        .ok_or_else(|| format!("Dispute not found: {}", dispute_id))?;
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    let suspended = match &resolution.suspension {
+        Some(suspension) => {
+            let (address, scope) = match suspension.party {
+                RideParty::Rider => (&ride.rider.address, SanctionScope::Rider),
+                RideParty::Driver => (&ride.driver.address, SanctionScope::Driver),
+            };
+            let principal = Principal::from_text(address)
+                .map_err(|_| format!("The {:?} cannot be suspended", suspension.party))?;
+            if suspension.expires_at.is_some_and(|expires_at| expires_at <= ic::time()) {
+                return Err("Expiry must be in the future".to_string());
+            }
+            Some((principal, scope, suspension.expires_at))
+        }
+        None => None,
+    };
+    //close the dispute before the refund goes out so it cannot be resolved twice
+    let (dispute, previous) = with_dispute_mut("resolve_dispute", dispute_id, |dispute| {
+        if !matches!(dispute.status, DisputeStatus::Open | DisputeStatus::Investigating) {
+            return Err(format!("Dispute is already {:?}", dispute.status));
+        }
+        let previous = dispute.status;
+        dispute.status = if resolution.upheld {
+            DisputeStatus::Resolved
+        } else {
+            DisputeStatus::Rejected
+        };
+        dispute.resolution = Some(resolution.clone());
+        Ok((dispute.clone(), previous))
+    })?;
+    if let Some(amount) = resolution.refund {
+        let reason = dispute.category.adjustment_reason();
+        let note = format!("dispute {}: {}", dispute_id, resolution.note);
+        let ride_id = dispute.ride_id.clone();
+        let charge_driver = resolution.charge_driver;
+        match issue_refund(ride_id, Some(amount), reason, note, false, charge_driver).await {
+            Ok(block_height) => {
+                let recorded = with_dispute_mut("resolve_dispute", dispute_id, |dispute| {
+                    dispute.refund_block = Some(block_height);
+                    Ok(())
+                });
+                if let Err(error) = recorded {
+                    let summary = format!("refund {} not recorded: {}", block_height, error);
+                    audit("resolve_dispute", &format!("dispute-{}", dispute_id), summary);
+                }
+            }
+            Err(error) => {
+                with_dispute_mut("resolve_dispute", dispute_id, |dispute| {
+                    dispute.status = previous;
+                    dispute.resolution = None;
+                    Ok(())
+                })?;
+                return Err(error);
+            }
+        }
+    }
+    //the refund may have gone out by now, so later failures are audited instead of returned
+    if let Some((principal, scope, expires_at)) = suspended {
+        let reason = format!("dispute {}: {}", dispute_id, resolution.note);
+        let kind = SanctionKind::Suspension;
+        let placed = add_sanction(principal, kind, scope, reason, expires_at).and_then(|id| {
+            with_dispute_mut("resolve_dispute", dispute_id, |dispute| {
+                dispute.sanction_id = Some(id);
+                Ok(())
+            })
+        });
+        if let Err(error) = placed {
+            let summary = format!("suspension not placed: {}", error);
+            audit("resolve_dispute", &format!("dispute-{}", dispute_id), summary);
+        }
+    }
+    let outcome = if resolution.upheld { "resolved" } else { "rejected" };
+    let text = format!("Report {} was {}: {}", dispute_id, outcome, resolution.note);
+    for party in [&ride.rider.address, &ride.driver.address] {
+        notify(party, Some(&ride.rideid), text.clone());
+    }
+    Ok(())
+}
+
+/// leave a message in a rider or driver's notification inbox
+fn notify(recipient: &str, ride_id: Option<&str>, text: String) {
+    if recipient.is_empty() || recipient == DELETED_ADDRESS {
+        return;
+    }
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let seq = notifications.last().map_or(0, |last| last.seq + 1);
+        let mine = notifications.iter().filter(|n| n.recipient == recipient);
+        if mine.count() >= MAX_NOTIFICATIONS {
+            if let Some(oldest) = notifications.iter().position(|n| n.recipient == recipient) {
+                notifications.remove(oldest);
+            }
+        }
+        notifications.push(Notification {
+            seq,
+            recipient: recipient.to_string(),
+            ride_id: ride_id.map(str::to_string),
+            text,
+            created_at: ic::time(),
+            read: false,
+        });
+    });
+}
+
+///the caller's notifications, newest first
+#[query]
+#[candid_method(query)]
+fn get_my_notifications(unread_only: bool) -> Vec<Notification> {
+    let recipient = ic::caller().to_text();
+    NOTIFICATIONS.with(|notifications| {
+        notifications
+            .borrow()
+            .iter()
+            .rev()
+            .filter(|notification| notification.recipient == recipient)
+            .filter(|notification| !unread_only || !notification.read)
+            .cloned()
+            .collect()
+    })
+}
+
+///mark the caller's notifications up to and including `seq` as read
+#[update]
+#[candid_method(update)]
+fn mark_notifications_read(seq: u64) {
+    let recipient = ic::caller().to_text();
+    NOTIFICATIONS.with(|notifications| {
+        for notification in notifications.borrow_mut().iter_mut() {
+            if notification.recipient == recipient && notification.seq <= seq {
+                notification.read = true;
+            }
+        }
+    });
+    audit("mark_notifications_read", &recipient, format!("read up to {}", seq));
+}
+
+/// rate the driver of the caller's completed ride from 1 to 5, once per ride, the driver's
+/// rating is the average over their rated rides
+#[update]
+#[candid_method(update)]
+fn update_driver_rating(ride_id: String, rating: f64) -> Result<(), String> {
+    if !(1.0..=5.0).contains(&rating) {
+        return Err("Rating must be between 1 and 5".to_string());
+    }
+    let caller = ic::caller().to_text();
+    let address = with_ride_mut("update_driver_rating", &ride_id, |ride| {
+        if ride.rider.address != caller {
+            return Err("Caller is not the rider for this ride".to_string());
+        }
+        if ride.status != RideStatus::Completed {
+            return Err(format!("Ride cannot be rated while {}", ride.status));
+        }
+        if ride.get_driver_rating() > 0.0 {
+            return Err("Ride has already been rated".to_string());
+        }
+        ride.update_driver_rating(rating);
+        Ok(ride.driver.address.clone())
+    })?;
+    let ratings: Vec<f64> = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.driver.address == address && ride.get_driver_rating() > 0.0)
+            .map(|ride| ride.get_driver_rating())
+            .collect()
+    });
+    let average = ratings.iter().sum::<f64>() / ratings.len() as f64;
+    DRIVER_STORE.with(|driver_store| {
+        let mut driver_store = driver_store.borrow_mut();
+        if let Some(driver) = driver_store.iter_mut().find(|driver| driver.address == address) {
+            audit("update_driver_rating", &address, "changed: rating".to_string());
+            driver.rating = average;
+        }
+    });
+    Ok(())
+}
+
+/// put a driver on or off duty, drivers set their own status and support anyone's, suspended
+/// and banned drivers can only go off duty
+#[update]
+#[candid_method(update)]
+fn update_driver_status(address: String, status: CurrentStatus) -> Result<(), String> {
+    if ic::caller().to_text() != address {
+        is_support()?;
+    }
+    if status == CurrentStatus::Active {
+        let principal = Principal::from_text(&address)
+            .map_err(|_| format!("Driver not found: {}", address))?;
+        ensure_not_sanctioned(principal, Role::Driver)?;
+    }
+    DRIVER_STORE.with(|driver_store| {
+        let mut driver_store = driver_store.borrow_mut();
+        let driver = driver_store
+            .iter_mut()
+            .find(|driver| driver.address == address)
+            .ok_or_else(|| format!("Driver not found: {}", address))?;
+        let summary = format!("currentstatus: {} -> {}", driver.currentstatus, status);
+        audit("update_driver_status", &driver.address, summary);
+        driver.currentstatus = status;
+        Ok(())
+    })
+}
+
+/// search for driver by name and return the driver, names are matched as the caller sees them
+/// so the public finds drivers by first name only
+#[query]
+#[candid_method(query)]
+fn search_driver_by_name(driver_name: String) -> Option<Driver> {
+    get_drivers()
+        .into_iter()
+        .find(|driver| driver.name == driver_name)
+}
+
+/// search for driver by contect and return the driver, only contacts the caller may see match
+#[query]
+#[candid_method(query)]
+fn search_driver_by_contact(contact: String) -> Option<Driver> {
+    get_drivers()
+        .into_iter()
+        .find(|driver| driver.contact == contact)
+}
+
+///search for driver by address and return the driver
+#[query]
+#[candid_method(query)]
+fn search_driver_by_address(principal_id: String) -> Option<Driver> {
+    find_driver(&principal_id).map(|driver| driver.redacted(&visibility_of(&driver.address)))
+}
+
+///search for rider by address and return the rider
+#[query]
+#[candid_method(query)]
+fn search_rider_by_address(principal_id: String) -> Option<Rider> {
+    find_rider(&principal_id).map(|rider| rider.redacted(&visibility_of(&rider.address)))
+}
+
+/// the stored driver record, unredacted
+fn find_driver(address: &str) -> Option<Driver> {
+    DRIVER_STORE.with(|driver_store| {
+        driver_store
+            .borrow()
+            .iter()
+            .find(|driver| driver.address == address)
+            .cloned()
+    })
+}
+
+/// the stored rider record, unredacted
+fn find_rider(address: &str) -> Option<Rider> {
+    RIDER_STORE.with(|rider_store| {
+        rider_store
+            .borrow()
+            .iter()
+            .find(|rider| rider.address == address)
+            .cloned()
+    })
+}
+
+/// how much of a rider or driver record the caller may see
+#[derive(PartialEq, Clone, Debug)]
+enum Visibility {
+    /// the record's owner and support operators
+    Full,
+    /// the other party of an accepted or active ride, contact goes through a relay
+    Counterparty(String),
+    /// everyone else
+    Public,
+}
+
+/// the caller and who they share an accepted or active ride with, built once per call
+struct Viewer {
+    address: String,
+    support: bool,
+    /// counterparty address to the id of the ride they share
+    counterparties: BTreeMap<String, String>,
+}
+
+impl Viewer {
+    fn caller() -> Viewer {
+        let viewer = ic::caller();
+        let address = viewer.to_text();
+        let mut counterparties = BTreeMap::new();
+        RIDES_STORE.with(|rides_store| {
+            for ride in rides_store.borrow().iter() {
+                if !matches!(ride.status, RideStatus::Accepted | RideStatus::Active) {
+                    continue;
+                }
+                let counterparty = if ride.rider.address == address {
+                    &ride.driver.address
+                } else if ride.driver.address == address {
+                    &ride.rider.address
+                } else {
+                    continue;
+                };
+                counterparties
+                    .entry(counterparty.clone())
+                    .or_insert_with(|| ride.rideid.clone());
+            }
+        });
+        Viewer {
+            address,
+            support: has_role(viewer, Role::Support),
+            counterparties,
+        }
+    }
+
+    fn visibility_of(&self, address: &str) -> Visibility {
+        if self.address == address || self.support {
+            return Visibility::Full;
+        }
+        self.counterparties
+            .get(address)
+            .map_or(Visibility::Public, |ride_id| Visibility::Counterparty(ride_id.clone()))
+    }
+
+    /// the ride as the viewer may see it
+    fn redacted_ride(&self, ride: &Ride) -> Ride {
+        let mut ride = ride.clone();
+        ride.rider = ride.rider.redacted(&self.visibility_of(&ride.rider.address));
+        ride.driver = ride.driver.redacted(&self.visibility_of(&ride.driver.address));
+        if let Some(cancellation) = ride.cancellation.as_mut() {
+            if self.visibility_of(&cancellation.canceller) == Visibility::Public {
+                cancellation.canceller = "".to_string();
+            }
+        }
+        if let Some(change) = ride.pending_stop_change.as_mut() {
+            if self.visibility_of(&change.proposed_by) == Visibility::Public {
+                change.proposed_by = "".to_string();
+            }
+        }
+        ride
+    }
+}
+
+fn visibility_of(address: &str) -> Visibility {
+    Viewer::caller().visibility_of(address)
+}
+
+/// address left on historical rides of deleted accounts
+const DELETED_ADDRESS: &str = "deleted";
+
+/// identifier the frontend relays calls and messages through instead of the real one
+fn relay_id(ride_id: &str, party: &str) -> String {
+    format!("relay-{}-{}", ride_id, party)
+}
+
+/// first word of a name, shown to the public
+fn first_name(name: &str) -> String {
+    name.split_whitespace().next().unwrap_or_default().to_string()
+}
+
+
+impl Rider {
+    /// create a new rider
+    pub fn new(
+        name: String,
+        contact: String,
+        email: String,
+        role: String,
+        address: String,
+    ) -> Rider {
+        Rider {
+            name,
+            contact,
+            email,
+            role,
+            address,
+        } //create a new rider
+    }
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    } // get the name of the rider
+    pub fn contact(&self) -> &str {
+        &self.contact
+    } // get the contact of the rider
+    pub fn email(&self) -> &str {
+        &self.email
+    } // get the email of the rider
+    pub fn role(&self) -> &str {
+        &self.role
+    } // get the role of the rider
+    pub fn address(&self) -> &str {
+        &self.address
+    } // get the address of the rider
+    pub fn get_field(&self, field: String) -> String {
+        match field.as_str() {
+            "name" => self.name.clone(),
+            "contact" => self.contact.clone(),
+            "email" => self.email.clone(),
+            "role" => self.role.clone(),
+            "address" => self.address.clone(),
+            _ => "".to_string(),
+        }
+    } // get the field of the rider
+
+    /// strip personal details, keeping the record usable in ride history
+    fn anonymize(&mut self) {
+        self.name = "Deleted rider".to_string();
+        self.contact = "".to_string();
+        self.email = "".to_string();
+        self.address = DELETED_ADDRESS.to_string();
+    }
+
+    /// the rider as a viewer with `visibility` may see them
+    fn redacted(&self, visibility: &Visibility) -> Rider {
+        match visibility {
+            Visibility::Full => self.clone(),
+            Visibility::Counterparty(ride_id) => Rider {
+                contact: relay_id(ride_id, "rider"),
+                email: "".to_string(),
+                address: relay_id(ride_id, "rider"),
+                ..self.clone()
+            },
+            Visibility::Public => Rider {
+                name: first_name(&self.name),
+                contact: "".to_string(),
+                email: "".to_string(),
+                address: "".to_string(),
+                ..self.clone()
+            },
+        }
+    }
+} // end of impl Rider
+
+impl Driver {
+    // create a new driver
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: String,
+        contact: String,
+        email: String,
+        role: String,
+        vehicleplatenumber: String,
+        vehicleseatnumber: String,
+        vehiclemake: String,
+        vehiclemodel: String,
+        vehiclecolor: String,
+        vehicletype: String,
+        vehicleyear: String,
+        rating: f64,
+        currentstatus: CurrentStatus,
+        address: String,
+    ) -> Driver {
+        Driver {
+            name,
+            contact,
+            email,
+            role,
+            vehicleplatenumber,
+            vehicleseatnumber,
+            vehiclemake,
+            vehiclemodel,
+            vehiclecolor,
+            vehicletype,
+            vehicleyear,
+            rating,
+            currentstatus,
+            address,
+        }
+    }
+    // placeholder for rides no driver has taken yet
+    pub fn unassigned() -> Driver {
+        Driver {
+            address: "".to_string(),
+            ..Driver::default()
+        }
+    }
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    } // get the name of the driver
+    pub fn name(&self) -> &str {
+        &self.name
+    } // get the name of the driver
+    pub fn contact(&self) -> &str {
+        &self.contact
+    } // get the contact of the driver
+    pub fn email(&self) -> &str {
+        &self.email
+    } // get the email of the driver
+    pub fn role(&self) -> &str {
+        &self.role
+    } // get the role of the driver
+    pub fn vehicleplatenumber(&self) -> &str {
+        &self.vehicleplatenumber
+    } // get the vehicleplatenumber of the driver
+    pub fn vehicleseatnumber(&self) -> &str {
+        &self.vehicleseatnumber
+    } // get the vehicleseatnumber of the driver
+    pub fn vehiclemake(&self) -> &str {
+        &self.vehiclemake
+    } // get the vehiclemake of the driver
+    pub fn vehiclemodel(&self) -> &str {
+        &self.vehiclemodel
+    } // get the vehiclemodel of the driver
+    pub fn vehiclecolor(&self) -> &str {
+        &self.vehiclecolor
+    } // get the vehiclecolor of the driver
+    pub fn vehicletype(&self) -> &str {
+        &self.vehicletype
+    } // get the vehicletype of the driver
+    pub fn vehicleyear(&self) -> &str {
+        &self.vehicleyear
+    } // get the vehicleyear of the driver
+    pub fn rating(&self) -> f64 {
+        self.rating
+    } // get the rating of the driver
+    pub fn currentstatus(&self) -> &CurrentStatus {
+        &self.currentstatus
+    } // get the currentstatus of the driver
+    pub fn address(&self) -> &str {
+        &self.address
+    } // get the address of the driver
+    pub fn get_field(&self, field: String) -> String {
+        match field.as_str() {
+            "name" => self.name.clone(),
+            "contact" => self.contact.clone(),
+            "email" => self.email.clone(),
+            "role" => self.role.clone(),
+            "vehicleplatenumber" => self.vehicleplatenumber.clone(),
+            "vehicleseatnumber" => self.vehicleseatnumber.clone(),
+            "vehiclemake" => self.vehiclemake.clone(),
+            "vehiclemodel" => self.vehiclemodel.clone(),
+            "vehiclecolor" => self.vehiclecolor.clone(),
+            "vehicletype" => self.vehicletype.clone(),
+            "vehicleyear" => self.vehicleyear.clone(),
+            "rating" => self.rating.to_string(),
+            "currentstatus" => self.currentstatus.to_string(),
+            "address" => self.address.clone(),
+            _ => "".to_string(),
+        }
+    } // get the value in the field
+    //updates
+    pub fn update_name(&mut self, name: String) {
+        self.name = name;
+    } // update the name of the driver
+    pub fn update_contact(&mut self, contact: String) {
+        self.contact = contact;
+    } // update the contact of the driver
+    pub fn update_email(&mut self, email: String) {
+        self.email = email;
+    } // update the email of the driver
+    pub fn update_role(&mut self, role: String) {
+        self.role = role;
+    } // update the role of the driver
+    pub fn update_vehicleplatenumber(&mut self, vehicleplatenumber: String) {
+        self.vehicleplatenumber = vehicleplatenumber;
+    } // update the vehicleplatenumber of the driver
+    pub fn update_vehicleseatnumber(&mut self, vehicleseatnumber: String) {
+        self.vehicleseatnumber = vehicleseatnumber;
+    } // update the vehicleseatnumber of the driver
+    pub fn update_vehiclemake(&mut self, vehiclemake: String) {
+        self.vehiclemake = vehiclemake;
+    } // update the vehiclemake of the driver
+    pub fn update_vehiclemodel(&mut self, vehiclemodel: String) {
+        self.vehiclemodel = vehiclemodel;
+    } // update the vehiclemodel of the driver
+    pub fn update_vehiclecolor(&mut self, vehiclecolor: String) {
+        self.vehiclecolor = vehiclecolor;
+    } // update the vehiclecolor of the driver
+    pub fn update_vehicletype(&mut self, vehicletype: String) {
+        self.vehicletype = vehicletype;
+    } // update the vehicletype of the driver
+    pub fn update_vehicleyear(&mut self, vehicleyear: String) {
+        self.vehicleyear = vehicleyear;
+    } // update the vehicleyear of the driver
+    pub fn update_rating(&mut self, rating: f64) {
+        self.rating = rating;
+    } // update the rating of the driver
+    pub fn update_currentstatus(&mut self, currentstatus: CurrentStatus) {
+        self.currentstatus = currentstatus;
+    } // update the currentstatus of the driver
+    pub fn update_address(&mut self, address: String) {
+        self.address = address;
+    } // update the address of the driver
+    pub fn update_field(&mut self, field: String, value: String) {
+        match field.as_str() {
+            "name" => self.name = value,
+            "contact" => self.contact = value,
+            "email" => self.email = value,
+            "role" => self.role = value,
+            "vehicleplatenumber" => self.vehicleplatenumber = value,
+            "vehicleseatnumber" => self.vehicleseatnumber = value,
+            "vehiclemake" => self.vehiclemake = value,
+            "vehiclemodel" => self.vehiclemodel = value,
+            "vehiclecolor" => self.vehiclecolor = value,
+            "vehicletype" => self.vehicletype = value,
+            "vehicleyear" => self.vehicleyear = value,
+            "rating" => self.rating = value.parse().unwrap(),
+            "address" => self.address = value,
+            _ => (),
+        }
+    } // update the field of the driver
+    //update whole driver
+    pub fn update_driver(&mut self, driver: Driver) {
         self.name = driver.name;
         self.contact = driver.contact;
         self.email = driver.email;
@@ -588,465 +2929,3866 @@ impl Driver {
     } // delete the address of the driver
     pub fn delete_field(&mut self, field: String) {
         match field.as_str() {
-            "name" => self.name = "".to_string(),
-            "contact" => self.contact = "".to_string(),
-            "email" => self.email = "".to_string(),
-            "role" => self.role = "".to_string(),
-            "vehicleplatenumber" => self.vehicleplatenumber = "".to_string(),
-            "vehicleseatnumber" => self.vehicleseatnumber = "".to_string(),
-            "vehiclemake" => self.vehiclemake = "".to_string(),
-            "vehiclemodel" => self.vehiclemodel = "".to_string(),
-            "vehiclecolor" => self.vehiclecolor = "".to_string(),
-            "vehicletype" => self.vehicletype = "".to_string(),
-            "vehicleyear" => self.vehicleyear = "".to_string(),
-            "rating" => self.rating = 0.0,
-            "currentstatus" => self.currentstatus = CurrentStatus::Inactive,
-            "address" => self.address = "".to_string(),
-            _ => (),
+            "name" => self.name = "".to_string(),
+            "contact" => self.contact = "".to_string(),
+            "email" => self.email = "".to_string(),
+            "role" => self.role = "".to_string(),
+            "vehicleplatenumber" => self.vehicleplatenumber = "".to_string(),
+            "vehicleseatnumber" => self.vehicleseatnumber = "".to_string(),
+            "vehiclemake" => self.vehiclemake = "".to_string(),
+            "vehiclemodel" => self.vehiclemodel = "".to_string(),
+            "vehiclecolor" => self.vehiclecolor = "".to_string(),
+            "vehicletype" => self.vehicletype = "".to_string(),
+            "vehicleyear" => self.vehicleyear = "".to_string(),
+            "rating" => self.rating = 0.0,
+            "currentstatus" => self.currentstatus = CurrentStatus::Inactive,
+            "address" => self.address = "".to_string(),
+            _ => (),
+        }
+    } // delete the field of the driver
+    //delete whole driver
+    pub fn delete_driver(&mut self) {
+        self.name = "".to_string();
+        self.contact = "".to_string();
+        self.email = "".to_string();
+        self.role = "".to_string();
+        self.vehicleplatenumber = "".to_string();
+        self.vehicleseatnumber = "".to_string();
+        self.vehiclemake = "".to_string();
+        self.vehiclemodel = "".to_string();
+        self.vehiclecolor = "".to_string();
+        self.vehicletype = "".to_string();
+        self.vehicleyear = "".to_string();
+        self.rating = 0.0;
+        self.currentstatus = CurrentStatus::Inactive;
+        self.address = "".to_string();
+    } // delete the whole driver
+    
+
+    /// strip personal details, keeping the record usable in ride history
+    fn anonymize(&mut self) {
+        self.name = "Deleted driver".to_string();
+        self.contact = "".to_string();
+        self.email = "".to_string();
+        self.vehicleplatenumber = "".to_string();
+        self.address = DELETED_ADDRESS.to_string();
+    }
+
+    /// the driver as a viewer with `visibility` may see them
+    fn redacted(&self, visibility: &Visibility) -> Driver {
+        match visibility {
+            Visibility::Full => self.clone(),
+            Visibility::Counterparty(ride_id) => Driver {
+                contact: relay_id(ride_id, "driver"),
+                email: "".to_string(),
+                address: relay_id(ride_id, "driver"),
+                ..self.clone()
+            },
+            Visibility::Public => Driver {
+                name: first_name(&self.name),
+                contact: "".to_string(),
+                email: "".to_string(),
+                vehicleplatenumber: "".to_string(),
+                address: "".to_string(),
+                ..self.clone()
+            },
+        }
+    }
+} // end of impl Driver
+
+///search for riders by field and return the results
+#[query]
+#[candid_method(query)]
+fn search_rider_by_field(field: String, value: String) -> Vec<Option<Rider>> {
+    let mut result = Vec::new();
+    for rider in get_riders().iter() {
+        if rider.get_field(field.clone()) == value {
+            result.push(Some(rider.clone()));
+        }
+    }
+    result
+}
+
+///search for drivers by field and return the results  
+#[query]
+#[candid_method(query)]
+fn search_driver_by_field(field: String, value: String) -> Vec<Option<Driver>> {
+    let mut results = Vec::new();
+    for driver in get_drivers().iter() {
+        if driver.get_field(field.clone()) == value {
+            results.push(Some(driver.clone()));
+        } else {
+            results.push(None);
+        }
+    }
+    results
+}
+
+//search for rides by field and return all results
+#[query]
+#[candid_method(query)]
+fn search_ride_by_field(field: String, value: String) -> Vec<Option<Ride>> {
+    let mut result = Vec::new();
+    for ride in get_rides().iter() {
+        if ride.get_field(field.clone()) == value {
+            result.push(Some(ride.clone()));
+        }
+    }
+    result
+}
+
+/// ridestatus enum for ride struct to represent the status of the ride
+#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum RideStatus {
+    Scheduled,
+    Requested,
+    Accepted,
+    Active,
+    Completed,
+    Cancelled,
+}
+
+
+//remove a ride from the ride store
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn remove_ride(ride_id: String) {
+    RIDES_STORE.with(|ride_store| {
+        let mut ride_store = ride_store.borrow_mut();
+        if let Some(index) = ride_store.iter().position(|ride| ride.rideid == ride_id) {
+            let ride = ride_store.remove(index);
+            audit("remove_ride", &ride_id, format!("removed ride while {}", ride.status));
+        }
+    });
+}
+
+//replace a ride in the store, the new record must keep the ride id
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn update_ride(ride_id: String, ride: Ride) -> Result<(), String> {
+    if ride.rideid != ride_id {
+        return Err("Ride id cannot be changed".to_string());
+    }
+    with_ride_mut("update_ride", &ride_id, |ride_| {
+        *ride_ = ride;
+        Ok(())
+    })
+}
+
+//remove a rider from the store by address
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn remove_rider(address: String) {
+    RIDER_STORE.with(|rider_store| {
+        let mut rider_store = rider_store.borrow_mut();
+        if let Some(index) = rider_store.iter().position(|rider| rider.address == address) {
+            rider_store.remove(index);
+            audit("remove_rider", &address, "removed rider".to_string());
+        }
+    });
+    if let Ok(principal) = Principal::from_text(&address) {
+        revoke_role(principal, Role::Rider);
+    }
+}
+
+//replace a driver in the store by address, the new record must keep the address
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn update_driver(address: String, driver: Driver) -> Result<(), String> {
+    if driver.address != address {
+        return Err("Driver address cannot be changed".to_string());
+    }
+    DRIVER_STORE.with(|driver_store| {
+        let mut driver_store = driver_store.borrow_mut();
+        let driver_ = driver_store
+            .iter_mut()
+            .find(|driver_| driver_.address == address)
+            .ok_or_else(|| format!("Driver not found: {}", address))?;
+        audit("update_driver", &address, diff_summary(driver_, &driver));
+        *driver_ = driver;
+        Ok(())
+    })
+}
+
+
+///Ride struct for the ride table
+#[derive(Debug, Deserialize, Clone, CandidType)]
+pub struct Ride {
+    pub rideid: String,
+    pub driver: Driver,
+    pub rider: Rider,
+    pub pickup: String,
+    pub dropoff: String,
+    pub status: RideStatus,
+    pub timestamp: String,
+    pub rating: f64,
+    pub driverrating: f64,
+    pub riderrating: f64,
+    pub driverfeedback: String,
+    pub riderfeedback: String,
+    pub riderconfirmation: String,
+    pub driverconfirmation: String,
+    /// lifecycle times from the canister clock, in nanoseconds
+    pub requested_at: u64,
+    pub scheduled_for: Option<u64>,
+    pub dispatched_at: Option<u64>,
+    pub accepted_at: Option<u64>,
+    pub picked_up_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    /// fare in e8s, set when the ride completes
+    pub fare: u64,
+    pub cancellation: Option<Cancellation>,
+    pub payment: Option<Payment>,
+    pub tip: Option<Tip>,
+    pub adjustments: Option<Vec<FareAdjustment>>,
+    /// ordered route from pickup to dropoff
+    pub waypoints: Vec<Waypoint>,
+    pub pending_stop_change: Option<PendingStopChange>,
+    /// pooled trip this ride is a leg of
+    pub pool_id: Option<String>,
+    pub pricing: Option<Pricing>,
+}
+
+#[allow(dead_code)]
+/// implement the ride struct with the following functions
+impl Ride {
+    /// create a new requested ride stamped with the canister time `now`
+    pub fn new(
+        rideid: String,
+        driver: Driver,
+        rider: Rider,
+        request: RideRequest,
+        now: u64,
+    ) -> Ride {
+        Ride {
+            rideid,
+            driver,
+            rider,
+            pickup: request.pickup.address_text.clone(),
+            dropoff: request.dropoff.address_text.clone(),
+            status: RideStatus::Requested,
+            timestamp: now.to_string(),
+            rating: 0.0,
+            driverrating: 0.0,
+            riderrating: 0.0,
+            driverfeedback: "".to_string(),
+            riderfeedback: "".to_string(),
+            riderconfirmation: "".to_string(),
+            driverconfirmation: "".to_string(),
+            requested_at: now,
+            scheduled_for: None,
+            dispatched_at: None,
+            accepted_at: None,
+            picked_up_at: None,
+            completed_at: None,
+            cancelled_at: None,
+            fare: 0,
+            cancellation: None,
+            payment: None,
+            tip: None,
+            adjustments: Some(vec![]),
+            waypoints: request.route().into_iter().map(Waypoint::new).collect(),
+            pending_stop_change: None,
+            pool_id: None,
+            pricing: Some(Pricing::default()),
+        }
+    }
+
+    /// estimated road distance of each leg between consecutive waypoints in kilometres
+    fn leg_distances_km(&self) -> Vec<f64> {
+        let config = get_geo_config();
+        self.waypoints
+            .windows(2)
+            .map(|leg| config.road_distance_km(&leg[0].location, &leg[1].location))
+            .collect()
+    }
+
+    /// total route distance in kilometres
+    fn route_distance_km(&self) -> f64 {
+        self.leg_distances_km().iter().sum()
+    }
+
+    /// check and apply a stop change to the waypoints
+    fn apply_stop_change(&mut self, change: &StopChange) -> Result<(), String> {
+        //stops already reached cannot be changed or preceded by a new stop
+        let next_stop = self
+            .waypoints
+            .iter()
+            .rposition(|waypoint| waypoint.status == StopStatus::Arrived)
+            .map_or(1, |index| index + 1);
+        let last = self.waypoints.len().saturating_sub(1);
+        match change {
+            StopChange::Add { index, location } => {
+                let index = *index as usize;
+                location.validate()?;
+                if index < next_stop || index > last {
+                    return Err(format!("Cannot add a stop at position {}", index));
+                }
+                self.waypoints.insert(index, Waypoint::new(location.clone()));
+            }
+            StopChange::Remove { index } => {
+                let index = *index as usize;
+                if index < next_stop || index >= last {
+                    return Err(format!("Cannot remove the stop at position {}", index));
+                }
+                self.waypoints.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// remove the pickup, dropoff and stops of a deleted account's ride
+    fn scrub_locations(&mut self) {
+        self.pickup = "".to_string();
+        self.dropoff = "".to_string();
+        for waypoint in self.waypoints.iter_mut() {
+            waypoint.location = Location::scrubbed();
+        }
+        if let Some(change) = self.pending_stop_change.as_mut() {
+            if let StopChange::Add { location, .. } = &mut change.change {
+                *location = Location::scrubbed();
+            }
+        }
+    }
+
+    /// a driver has been assigned to the ride
+    fn has_driver(&self) -> bool {
+        !self.driver.address.is_empty()
+    }
+
+    fn update_rider_confirmation(&mut self, confirmation: String) {
+        self.riderconfirmation = confirmation;
+    }
+
+    fn update_driver_confirmation(&mut self, confirmation: String) {
+        self.driverconfirmation = confirmation;
+    }
+    fn update_driver_rating(&mut self, rating: f64) {
+        self.driverrating = rating;
+    }
+    fn update_rider_rating(&mut self, rating: f64) {
+        self.riderrating = rating;
+    }
+    fn update_driver_feedback(&mut self, feedback: String) {
+        self.driverfeedback = feedback;
+    }
+    fn update_rider_feedback(&mut self, feedback: String) {
+        self.riderfeedback = feedback;
+    }
+    fn update_rating(&mut self, rating: f64) {
+        self.rating = rating;
+    }
+    fn update_status(&mut self, status: RideStatus) {
+        self.status = status;
+    }
+    fn update_timestamp(&mut self, timestamp: String) {
+        self.timestamp = timestamp;
+    }
+    fn update_dropoff(&mut self, dropoff: String) {
+        self.dropoff = dropoff;
+    }
+    fn update_pickup(&mut self, pickup: String) {
+        self.pickup = pickup;
+    }
+    fn update_rider(&mut self, rider: Rider) {
+        self.rider = rider;
+    }
+    fn update_driver(&mut self, driver: Driver) {
+        self.driver = driver;
+    }
+    fn update_rideid(&mut self, rideid: String) {
+        self.rideid = rideid;
+    }
+
+    fn get_rider_confirmation(&self) -> String {
+        self.riderconfirmation.clone()
+    }
+    fn get_driver_confirmation(&self) -> String {
+        self.driverconfirmation.clone()
+    }
+    fn get_driver_rating(&self) -> f64 {
+        self.driverrating
+    }
+    fn get_rider_rating(&self) -> f64 {
+        self.riderrating
+    }
+    fn get_driver_feedback(&self) -> String {
+        self.driverfeedback.clone()
+    }
+    fn get_rider_feedback(&self) -> String {
+        self.riderfeedback.clone()
+    }
+    fn get_rating(&self) -> f64 {
+        self.rating
+    }
+    fn get_status(&self) -> RideStatus {
+        self.status
+    }
+    fn get_timestamp(&self) -> String {
+        self.timestamp.clone()
+    }
+    fn get_dropoff(&self) -> String {
+        self.dropoff.clone()
+    }
+    fn get_pickup(&self) -> String {
+        self.pickup.clone()
+    }
+    fn get_rider(&self) -> Rider {
+        self.rider.clone()
+    }
+    fn get_driver(&self) -> Driver {
+        self.driver.clone()
+    }
+    fn get_rideid(&self) -> String {
+        self.rideid.clone()
+    }
+
+    fn get_id(&self) -> String {
+        self.rideid.clone()
+    }
+
+    fn get_type(&self) -> String {
+        "Ride".to_string()
+    }
+
+    /// time between pickup and completion in nanoseconds
+    fn trip_duration(&self) -> Option<u64> {
+        Some(self.completed_at?.saturating_sub(self.picked_up_at?))
+    }
+
+    /// time the rider waited for a driver to accept in nanoseconds
+    fn wait_duration(&self) -> Option<u64> {
+        Some(self.accepted_at?.saturating_sub(self.requested_at))
+    }
+
+    /// check `rider` can tip `amount` e8s at `now`
+    fn check_tip(&self, rider: &str, amount: u64, now: u64) -> Result<(), String> {
+        if self.rider.address != rider {
+            return Err("Caller is not the rider for this ride".to_string());
+        }
+        if amount == 0 {
+            return Err("Tip must be more than zero".to_string());
+        }
+        if self.tip.is_some() {
+            return Err("Ride has already been tipped".to_string());
+        }
+        match (self.status, self.completed_at) {
+            (RideStatus::Completed, Some(done)) if now <= done.saturating_add(TIP_WINDOW) => Ok(()),
+            (RideStatus::Completed, _) => Err("The window for tipping has closed".to_string()),
+            _ => Err(format!("Ride cannot be tipped while {}", self.status)),
+        }
+    }
+
+    /// zone, surge and promo rules locked onto the ride, the defaults for rides older than them
+    fn pricing(&self) -> Pricing {
+        self.pricing.clone().unwrap_or_default()
+    }
+
+    fn adjustments(&self) -> &[FareAdjustment] {
+        self.adjustments.as_deref().unwrap_or_default()
+    }
+
+    fn adjustments_mut(&mut self) -> &mut Vec<FareAdjustment> {
+        self.adjustments.get_or_insert_with(Vec::new)
+    }
+
+    /// e8s of the payment not yet refunded or being refunded
+    fn refundable(&self) -> u64 {
+        let paid = self.payment.as_ref().map_or(0, |payment| payment.amount);
+        let refunded: i64 = self
+            .adjustments()
+            .iter()
+            .filter(|adjustment| {
+                matches!(adjustment.refund_status, Some(RefundStatus::Pending | RefundStatus::Paid))
+            })
+            .map(|adjustment| adjustment.amount)
+            .sum();
+        paid.saturating_sub(refunded as u64)
+    }
+
+    /// amount in e8s the rider has to settle for this ride
+    fn amount_due(&self) -> u64 {
+        match self.status {
+            RideStatus::Completed => self.fare,
+            RideStatus::Cancelled => self.cancellation.as_ref().map_or(0, |c| c.fee),
+            _ => 0,
+        }
+    }
+
+    fn get_field(&self, field: String) -> String {
+        match field.as_str() {
+            "rideid" => self.rideid.clone(),
+            "driver" => self.driver.get_name(),
+            "rider" => self.rider.get_name(),
+            "pickup" => self.pickup.clone(),
+            "dropoff" => self.dropoff.clone(),
+            "status" => self.status.to_string(),
+            "timestamp" => self.timestamp.clone(),
+            "rating" => self.rating.to_string(),
+            "driverrating" => self.driverrating.to_string(),
+            "riderrating" => self.riderrating.to_string(),
+            "driverfeedback" => self.driverfeedback.clone(),
+            "riderfeedback" => self.riderfeedback.clone(),
+            "riderconfirmation" => self.riderconfirmation.clone(),
+            "driverconfirmation" => self.driverconfirmation.clone(),
+            "driveraddress" => self.driver.address.clone(),
+            "rideraddress" => self.rider.address.clone(),
+            "requested_at" => self.requested_at.to_string(),
+            "fare" => self.fare.to_string(),
+            "cancelledby" => self
+                .cancellation
+                .as_ref()
+                .map_or("".to_string(), |c| c.canceller.clone()),
+            _ => "".to_string(),
+        }
+    }
+}
+
+///register ride to RIDES_STORE, lifecycle times are stamped by the canister
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn register_ride(mut ride: Ride) {
+    let now = ic::time();
+    ride.status = RideStatus::Requested;
+    ride.timestamp = now.to_string();
+    ride.requested_at = now;
+    ride.scheduled_for = None;
+    ride.dispatched_at = Some(now);
+    ride.accepted_at = None;
+    ride.picked_up_at = None;
+    ride.completed_at = None;
+    ride.cancelled_at = None;
+    ride.fare = 0;
+    ride.cancellation = None;
+    ride.payment = None;
+    ride.pending_stop_change = None;
+    ride.pool_id = None;
+    ride.riderconfirmation = "".to_string();
+    ride.driverconfirmation = "".to_string();
+    for waypoint in ride.waypoints.iter_mut() {
+        waypoint.status = StopStatus::Pending;
+        waypoint.arrived_at = None;
+    }
+    let summary = format!("registered for rider {}", ride.rider.address);
+    audit("register_ride", &ride.rideid, summary);
+    emit(RideEvent::RideRequested, &ride, 0);
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow_mut().push(ride);
+    });
+}
+
+///search ride by
+#[allow(dead_code)]
+#[candid_method(query)]
+fn search_ride_by_id(rideid: String) -> Option<Ride> {
+    RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .find(|ride| ride.rideid == rideid)
+            .cloned()
+    })
+}
+
+///update a driver for a ride by rideid
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn update_driver_for_ride(rideid: String, driver: Driver) {
+    let _ = with_ride_mut("update_driver_for_ride", &rideid, |ride| {
+        ride.update_driver(driver);
+        Ok(())
+    });
+}
+
+
+///update a rider for a ride by rideid
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn update_rider_for_ride(rideid: String, rider: Rider) {
+    let _ = with_ride_mut("update_rider_for_ride", &rideid, |ride| {
+        ride.update_rider(rider);
+        Ok(())
+    });
+}
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash)]
+pub struct TransferArgs {
+    amount: Tokens,
+    to_principal: Principal,
+    to_subaccount: Option<Subaccount>,
+}
+
+///get one
+pub type BlockHeight = u64;
+
+///get block from ledger with height
+#[allow(dead_code)]
+async fn get_block_from_ledger(
+    block_height: BlockHeight,
+    ledger_canister_id: Principal,
+) -> Option<Block> {
+    //set arguments for get blocks
+    let args = GetBlocksArgs {
+        start: block_height,
+        length: 1,
+    };
+    //set ledger to mainnet
+    if let Ok(result) = query_blocks(ledger_canister_id, args.clone()).await {
+        //get block from result
+        if !result.blocks.is_empty() {
+            return result.blocks.first().cloned();
+        }
+        //get block from archived blocks
+        if let Some(b) = result
+            .archived_blocks
+            .into_iter()
+            .find(|b| b.start <= block_height && (block_height - b.start) < b.length)
+        {
+            if let Ok(Ok(range)) = query_archived_blocks(&b.callback, args).await {
+                return range.blocks.get((block_height - b.start) as usize).cloned();
+            }
+        }
+    }
+    None
+}
+
+///caller requests a ride, dispatched to the nearest active driver
+#[update]
+#[candid_method(update)]
+pub fn request_ride(request: RideRequest) -> Result<String, String> {
+    let rider = caller_rider()?;
+    request.validate()?;
+    let mut pricing = zone_pricing(&request)?;
+    let driver = find_available_driver(&request.pickup, &busy_drivers())
+        .ok_or_else(|| "No active drivers available".to_string())?;
+    let code = &request.promo_code;
+    apply_promo(code, &rider.address, Some(&driver.vehicletype), &mut pricing)?;
+    let now = ic::time();
+    let mut ride = Ride::new(next_ride_id(), driver, rider, request, now);
+    ride.dispatched_at = Some(now);
+    ride.pricing = Some(pricing);
+    let rideid = ride.rideid.clone();
+    audit("request_ride", &rideid, format!("dispatched to {}", ride.driver.address));
+    record_redemption(&ride);
+    emit(RideEvent::RideRequested, &ride, 0);
+    emit(RideEvent::OfferSent, &ride, 0);
+    //register ride
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow_mut().push(ride);
+    });
+    Ok(rideid)
+}
+
+/// find the online driver who can reach `pickup` soonest, drivers with no known position last,
+/// skipping the drivers in `excluded`
+fn find_available_driver(pickup: &Location, excluded: &BTreeSet<String>) -> Option<Driver> {
+    let config = get_geo_config();
+    DRIVER_STORE
+        .with(|driver_store| driver_store.borrow().clone())
+        .into_iter()
+        .filter(|d| d.currentstatus == CurrentStatus::Active && is_approved_driver(&d.address))
+        .filter(|d| !excluded.contains(&d.address))
+        .min_by_key(|d| {
+            DRIVER_LOCATIONS.with(|locations| {
+                locations
+                    .borrow()
+                    .get(&d.address)
+                    .map_or(u64::MAX, |position| config.eta(&position.location, pickup))
+            })
+        })
+}
+
+/// drivers offered or holding a ride of their own, who are not dispatched another
+fn busy_drivers() -> BTreeSet<String> {
+    RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.pool_id.is_none())
+            .filter(|ride| {
+                matches!(
+                    ride.status,
+                    RideStatus::Requested | RideStatus::Accepted | RideStatus::Active
+                )
+            })
+            .map(|ride| ride.driver.address.clone())
+            .collect()
+    })
+}
+
+///request a shared ride, joining an in-progress pool when the detour limit allows
+#[update]
+#[candid_method(update)]
+fn request_pooled_ride(request: RideRequest) -> Result<String, String> {
+    let rider = caller_rider()?;
+    request.validate()?;
+    if !request.stops.is_empty() {
+        return Err("Pooled rides cannot have extra stops".to_string());
+    }
+    let mut pricing = zone_pricing(&request)?;
+    let code = request.promo_code.clone();
+    let policy = get_pool_policy();
+    let rideid = next_ride_id();
+    let now = ic::time();
+    //find the in-progress pool the new rider adds the least distance to
+    let joinable = POOL_STORE.with(|pool_store| {
+        pool_store
+            .borrow()
+            .iter()
+            .filter(|pool| pool.is_open() && pool_in_progress(pool))
+            .filter(|pool| is_approved_driver(&pool.driver))
+            .filter_map(|pool| {
+                let (route, added) =
+                    pool.plan_insertion(&rideid, &request, policy.max_detour_ratio)?;
+                Some((pool.pool_id.clone(), pool.driver.clone(), route, added))
+            })
+            .min_by(|a, b| a.3.total_cmp(&b.3))
+    });
+    let mut ride = match joinable {
+        Some((pool_id, driver_address, route, _)) => {
+            let driver = find_driver(&driver_address)
+                .ok_or_else(|| "Pool driver is no longer registered".to_string())?;
+            apply_promo(&code, &rider.address, Some(&driver.vehicletype), &mut pricing)?;
+            POOL_STORE.with(|pool_store| {
+                if let Some(pool) = pool_store
+                    .borrow_mut()
+                    .iter_mut()
+                    .find(|pool| pool.pool_id == pool_id)
+                {
+                    pool.route = route;
+                }
+            });
+            let mut ride = Ride::new(rideid.clone(), driver, rider, request, now);
+            //the driver is already on the trip, the canister accepts for them
+            ride.status = RideStatus::Accepted;
+            ride.accepted_at = Some(now);
+            ride.pool_id = Some(pool_id);
+            ride
+        }
+        None => {
+            let driver = find_available_driver(&request.pickup, &busy_drivers())
+                .ok_or_else(|| "No active drivers available".to_string())?;
+            let pool = Pool {
+                pool_id: format!("pool-{}", rideid),
+                driver: driver.address.clone(),
+                seats: driver.vehicleseatnumber.parse().unwrap_or(policy.max_riders),
+                route: vec![],
+                created_at: now,
+            };
+            let (route, _) = pool
+                .plan_insertion(&rideid, &request, policy.max_detour_ratio)
+                .ok_or_else(|| "Driver has no free seats".to_string())?;
+            apply_promo(&code, &rider.address, Some(&driver.vehicletype), &mut pricing)?;
+            let mut ride = Ride::new(rideid.clone(), driver, rider, request, now);
+            ride.pool_id = Some(pool.pool_id.clone());
+            POOL_STORE.with(|pool_store| pool_store.borrow_mut().push(Pool { route, ..pool }));
+            ride
+        }
+    };
+    ride.dispatched_at = Some(now);
+    ride.pricing = Some(pricing);
+    let summary = format!("pool {:?} with {}", ride.pool_id, ride.driver.address);
+    audit("request_pooled_ride", &rideid, summary);
+    record_redemption(&ride);
+    emit(RideEvent::RideRequested, &ride, 0);
+    if ride.status == RideStatus::Accepted {
+        emit(RideEvent::RideAccepted, &ride, 0);
+    } else {
+        emit(RideEvent::OfferSent, &ride, 0);
+    }
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow_mut().push(ride);
+    });
+    Ok(rideid)
+}
+
+/// the pool's driver has accepted at least one of its rides
+fn pool_in_progress(pool: &Pool) -> bool {
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow().iter().any(|ride| {
+            ride.pool_id.as_deref() == Some(pool.pool_id.as_str())
+                && (ride.status == RideStatus::Accepted || ride.status == RideStatus::Active)
+        })
+    })
+}
+
+/// mark a rider's pickup or dropoff on a pool route as visited
+fn visit_pool_stop(pool_id: &str, ride_id: &str, kind: PoolStopKind) {
+    POOL_STORE.with(|pool_store| {
+        if let Some(pool) = pool_store
+            .borrow_mut()
+            .iter_mut()
+            .find(|pool| pool.pool_id == pool_id)
+        {
+            for stop in pool.route.iter_mut() {
+                if stop.ride_id == ride_id && stop.kind == kind {
+                    stop.visited = true;
+                }
+            }
+        }
+    });
+}
+
+/// drop the stops of a cancelled ride that have not been visited from the pool route
+fn leave_pool(pool_id: &str, ride_id: &str) {
+    POOL_STORE.with(|pool_store| {
+        if let Some(pool) = pool_store
+            .borrow_mut()
+            .iter_mut()
+            .find(|pool| pool.pool_id == pool_id)
+        {
+            pool.route.retain(|stop| stop.ride_id != ride_id || stop.visited);
+        }
+    });
+}
+
+///get a pooled trip by id, only its driver, its riders and support can see it
+#[query]
+#[candid_method(query)]
+fn get_pool(pool_id: String) -> Result<Pool, String> {
+    let pool = POOL_STORE
+        .with(|pool_store| {
+            pool_store
+                .borrow()
+                .iter()
+                .find(|pool| pool.pool_id == pool_id)
+                .cloned()
+        })
+        .ok_or_else(|| format!("Pool not found: {}", pool_id))?;
+    let caller = ic::caller().to_text();
+    let rides_pool = RIDES_STORE.with(|rides_store| {
+        rides_store.borrow().iter().any(|ride| {
+            ride.rider.address == caller && ride.pool_id.as_ref() == Some(&pool.pool_id)
+        })
+    });
+    if pool.driver != caller && !rides_pool && is_support().is_err() {
+        return Err("Caller is not on this pool".to_string());
+    }
+    Ok(pool)
+}
+
+///get the pooled ride policy
+#[query]
+#[candid_method(query)]
+fn get_pool_policy() -> PoolPolicy {
+    POOL_POLICY.with(|policy| policy.borrow().clone())
+}
+
+///replace the pooled ride policy
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_pool_policy(policy: PoolPolicy) {
+    POOL_POLICY.with(|current| {
+        let summary = diff_summary(&*current.borrow(), &policy);
+        audit("set_pool_policy", "pool_policy", summary);
+        *current.borrow_mut() = policy;
+    });
+}
+
+///book a ride for a future pickup time in nanoseconds
+#[update]
+#[candid_method(update)]
+fn schedule_ride(request: RideRequest, pickup_at: u64) -> Result<String, String> {
+    let rider = caller_rider()?;
+    request.validate()?;
+    let mut pricing = zone_pricing(&request)?;
+    //there is no vehicle until a driver is assigned, dispatch only offers ones the code allows
+    apply_promo(&request.promo_code, &rider.address, None, &mut pricing)?;
+    let policy = get_scheduling_policy();
+    let now = ic::time();
+    if pickup_at < now + policy.min_advance {
+        return Err("Scheduled pickup is too soon, request a ride instead".to_string());
+    }
+    if pickup_at > now + policy.max_advance {
+        return Err("Scheduled pickup is too far in the future".to_string());
+    }
+    let mut ride = Ride::new(next_ride_id(), Driver::unassigned(), rider, request, now);
+    ride.status = RideStatus::Scheduled;
+    ride.scheduled_for = Some(pickup_at);
+    ride.pricing = Some(pricing);
+    let rideid = ride.rideid.clone();
+    audit("schedule_ride", &rideid, format!("pickup at {}", pickup_at));
+    record_redemption(&ride);
+    emit(RideEvent::RideRequested, &ride, 0);
+    RIDES_STORE.with(|rides_store| {
+        rides_store.borrow_mut().push(ride);
+    });
+    Ok(rideid)
+}
+
+///scheduled rides no driver has pre-accepted yet, soonest pickup first
+#[query]
+#[candid_method(query)]
+fn get_upcoming_rides() -> RidesStore {
+    let viewer = Viewer::caller();
+    let mut rides: RidesStore = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.status == RideStatus::Scheduled && !ride.has_driver())
+            .map(|ride| viewer.redacted_ride(ride))
+            .collect()
+    });
+    rides.sort_by_key(|ride| ride.scheduled_for);
+    rides
+}
+
+///registered driver commits to a scheduled ride ahead of dispatch
+#[update]
+#[candid_method(update)]
+fn pre_accept_ride(ride_id: String) -> Result<(), String> {
+    ensure_not_sanctioned(ic::caller(), Role::Driver)?;
+    let driver = find_driver(&ic::caller().to_text())
+        .ok_or_else(|| "Caller is not a registered driver".to_string())?;
+    if !is_approved_driver(&driver.address) {
+        return Err("Driver is not approved to take rides".to_string());
+    }
+    with_ride_mut("pre_accept_ride", &ride_id, |ride| {
+        if ride.status != RideStatus::Scheduled {
+            return Err(format!("Ride cannot be pre-accepted while {}", ride.status));
+        }
+        if ride.has_driver() {
+            return Err("Ride has already been pre-accepted".to_string());
+        }
+        if !promo_allows_vehicle(ride, &driver.vehicletype) {
+            return Err("The ride's promo code does not apply to this vehicle".to_string());
+        }
+        ride.driver = driver;
+        Ok(())
+    })
+}
+
+///get the scheduled ride policy
+#[query]
+#[candid_method(query)]
+fn get_scheduling_policy() -> SchedulingPolicy {
+    SCHEDULING_POLICY.with(|policy| policy.borrow().clone())
+}
+
+///replace the scheduled ride policy
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_scheduling_policy(policy: SchedulingPolicy) {
+    SCHEDULING_POLICY.with(|current| {
+        let summary = diff_summary(&*current.borrow(), &policy);
+        audit("set_scheduling_policy", "scheduling_policy", summary);
+        *current.borrow_mut() = policy;
+    });
+}
+
+/// start dispatch for scheduled rides whose pickup is within the lead time
+fn dispatch_scheduled_rides(now: u64) {
+    let policy = get_scheduling_policy();
+    //a driver gets at most one ride from each pass, and none while holding another
+    let mut offered = busy_drivers();
+    RIDES_STORE.with(|rides_store| {
+        for ride in rides_store.borrow_mut().iter_mut() {
+            let pickup_at = match (ride.status, ride.scheduled_for) {
+                (RideStatus::Scheduled, Some(pickup_at)) => pickup_at,
+                _ => continue,
+            };
+            if now.saturating_add(policy.dispatch_lead_time) < pickup_at {
+                continue;
+            }
+            if ride.has_driver() && !is_approved_driver(&ride.driver.address) {
+                //a driver suspended since pre-accepting loses the ride to dispatch
+                let summary = format!("{} is no longer approved", ride.driver.address);
+                audit("heartbeat", &ride.rideid, summary);
+                ride.driver = Driver::unassigned();
+            }
+            if ride.has_driver() {
+                //pre-accepted rides go straight to the driver
+                ride.status = RideStatus::Accepted;
+                ride.dispatched_at = Some(now);
+                ride.accepted_at = Some(now);
+                audit("heartbeat", &ride.rideid, "dispatched to pre-accepting driver".to_string());
+                emit(RideEvent::RideAccepted, ride, 0);
+                offered.insert(ride.driver.address.clone());
+            } else if let Some(driver) = find_available_driver(
+                &ride.waypoints[0].location,
+                &offered.union(&promo_excluded_drivers(ride)).cloned().collect(),
+            ) {
+                audit("heartbeat", &ride.rideid, format!("dispatched to {}", driver.address));
+                offered.insert(driver.address.clone());
+                ride.driver = driver;
+                ride.status = RideStatus::Requested;
+                ride.dispatched_at = Some(now);
+                emit(RideEvent::OfferSent, ride, 0);
+            } else if now.saturating_sub(pickup_at) > RIDE_REQUEST_TIMEOUT {
+                ride.status = RideStatus::Cancelled;
+                ride.cancelled_at = Some(now);
+                ride.cancellation = Some(Cancellation {
+                    cancelled_by: CancelledBy::System,
+                    canceller: ic::id().to_text(),
+                    reason: "No driver available for the scheduled ride".to_string(),
+                    fee: 0,
+                    driver_penalty: 0,
+                });
+                audit("heartbeat", &ride.rideid, "no driver for scheduled ride".to_string());
+                emit(RideEvent::Cancelled, ride, 0);
+            }
+        }
+    });
+}
+
+/// generate a ride id from the canister clock and a counter
+fn next_ride_id() -> String {
+    let count = RIDE_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        *counter
+    });
+    format!("ride-{}-{}", ic::time(), count)
+}
+
+/// run `f` on a stored ride, auditing what it changed under `endpoint`
+fn with_ride_mut<T>(
+    endpoint: &str,
+    ride_id: &str,
+    f: impl FnOnce(&mut Ride) -> Result<T, String>,
+) -> Result<T, String> {
+    RIDES_STORE.with(|rides_store| {
+        let mut rides_store = rides_store.borrow_mut();
+        let ride = rides_store
+            .iter_mut()
+            .find(|ride| ride.rideid == ride_id)
+            .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+        let before = ride.clone();
+        let result = f(ride)?;
+        audit(endpoint, ride_id, diff_summary(&before, ride));
+        Ok(result)
+    })
+}
+
+/// refuse the caller while they are sanctioned in their role on `ride`
+fn ensure_party_not_sanctioned(ride: &Ride) -> Result<(), String> {
+    let caller = ic::caller();
+    let role = if caller.to_text() == ride.rider.address {
+        Role::Rider
+    } else {
+        Role::Driver
+    };
+    ensure_not_sanctioned(caller, role)
+}
+
+/// check that the caller is the driver assigned to the ride
+fn ensure_ride_driver(ride: &Ride) -> Result<(), String> {
+    if ride.driver.address != ic::caller().to_text() {
+        return Err("Caller is not the driver for this ride".to_string());
+    }
+    Ok(())
+}
+
+///driver accepts a requested ride
+#[update]
+#[candid_method(update)]
+fn accept_ride(ride_id: String) -> Result<(), String> {
+    ensure_not_sanctioned(ic::caller(), Role::Driver)?;
+    with_ride_mut("accept_ride", &ride_id, |ride| {
+        ensure_ride_driver(ride)?;
+        if ride.status != RideStatus::Requested {
+            return Err(format!("Ride cannot be accepted while {}", ride.status));
+        }
+        ride.status = RideStatus::Accepted;
+        ride.accepted_at = Some(ic::time());
+        emit(RideEvent::RideAccepted, ride, 0);
+        Ok(())
+    })
+}
+
+///driver gets the start code for an accepted ride, a new one is issued once it expires
+#[update]
+#[candid_method(update)]
+async fn get_start_code(ride_id: String) -> Result<StartCode, String> {
+    ensure_not_sanctioned(ic::caller(), Role::Driver)?;
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    ensure_ride_driver(&ride)?;
+    if ride.status != RideStatus::Accepted {
+        return Err(format!("Start codes are not issued while {}", ride.status));
+    }
+    let current = START_CODES.with(|start_codes| start_codes.borrow().get(&ride_id).cloned());
+    if let Some(start_code) = current.filter(|start_code| start_code.is_valid(ic::time())) {
+        return Ok(start_code);
+    }
+    let (entropy,): (Vec<u8>,) = ic::call(Principal::management_canister(), "raw_rand", ())
+        .await
+        .map_err(|(_, message)| message)?;
+    Ok(issue_start_code(&ride_id, &entropy, ic::time()))
+}
+
+/// store a fresh start code for a ride, replacing any earlier one
+fn issue_start_code(ride_id: &str, entropy: &[u8], now: u64) -> StartCode {
+    let start_code = StartCode::new(entropy, now);
+    START_CODES.with(|start_codes| {
+        start_codes
+            .borrow_mut()
+            .insert(ride_id.to_string(), start_code.clone());
+    });
+    let summary = format!("issued start code valid until {}", start_code.expires_at);
+    audit("get_start_code", ride_id, summary);
+    start_code
+}
+
+///rider enters the driver's start code at pickup and the trip starts
+#[update]
+#[candid_method(update)]
+fn confirm_pickup(ride_id: String, code: String) -> Result<(), String> {
+    ensure_not_sanctioned(ic::caller(), Role::Rider)?;
+    let now = ic::time();
+    with_ride_mut("confirm_pickup", &ride_id, |ride| {
+        if ride.rider.address != ic::caller().to_text() {
+            return Err("Caller is not the rider for this ride".to_string());
+        }
+        if ride.status != RideStatus::Accepted {
+            return Err(format!("Ride cannot be started while {}", ride.status));
+        }
+        START_CODES.with(|start_codes| {
+            let mut start_codes = start_codes.borrow_mut();
+            let start_code = start_codes
+                .get_mut(&ride_id)
+                .filter(|start_code| start_code.is_valid(now))
+                .ok_or_else(|| "Start code has expired, ask the driver for a new one".to_string())?;
+            if start_code.code != code {
+                start_code.attempts += 1;
+                return Err("Start code does not match".to_string());
+            }
+            start_code.used = true;
+            Ok(())
+        })?;
+        ride.status = RideStatus::Active;
+        ride.picked_up_at = Some(now);
+        ride.riderconfirmation = "confirmed".to_string();
+        ride.driverconfirmation = "confirmed".to_string();
+        if let Some(pool_id) = &ride.pool_id {
+            visit_pool_stop(pool_id, &ride.rideid, PoolStopKind::Pickup);
+        }
+        emit(RideEvent::PickedUp, ride, 0);
+        Ok(())
+    })
+}
+
+/// drop start codes that can no longer be used
+fn purge_start_codes(now: u64) {
+    START_CODES.with(|start_codes| {
+        start_codes
+            .borrow_mut()
+            .retain(|_, start_code| start_code.is_valid(now));
+    });
+}
+
+///rider or driver proposes adding or removing a stop, the other party has to confirm
+#[update]
+#[candid_method(update)]
+fn propose_stop_change(ride_id: String, change: StopChange) -> Result<(), String> {
+    let caller = ic::caller().to_text();
+    with_ride_mut("propose_stop_change", &ride_id, |ride| {
+        if caller != ride.rider.address && caller != ride.driver.address {
+            return Err("Caller is not the rider or driver for this ride".to_string());
+        }
+        ensure_party_not_sanctioned(ride)?;
+        if ride.status != RideStatus::Accepted && ride.status != RideStatus::Active {
+            return Err(format!("Stops cannot be changed while {}", ride.status));
+        }
+        if ride.pending_stop_change.is_some() {
+            return Err("Another stop change is waiting for confirmation".to_string());
+        }
+        //check the change is valid before asking the other party
+        ride.clone().apply_stop_change(&change)?;
+        ride.pending_stop_change = Some(PendingStopChange {
+            change,
+            proposed_by: caller,
+            proposed_at: ic::time(),
+        });
+        Ok(())
+    })
+}
+
+///the other party confirms or rejects the pending stop change
+#[update]
+#[candid_method(update)]
+fn respond_stop_change(ride_id: String, accept: bool) -> Result<(), String> {
+    let caller = ic::caller().to_text();
+    with_ride_mut("respond_stop_change", &ride_id, |ride| {
+        if caller != ride.rider.address && caller != ride.driver.address {
+            return Err("Caller is not the rider or driver for this ride".to_string());
+        }
+        ensure_party_not_sanctioned(ride)?;
+        let pending = ride
+            .pending_stop_change
+            .clone()
+            .ok_or_else(|| "No stop change is waiting for confirmation".to_string())?;
+        if pending.proposed_by == caller {
+            return Err("Stop change must be confirmed by the other party".to_string());
+        }
+        //the change stays pending if it can no longer be applied
+        if accept {
+            ride.apply_stop_change(&pending.change)?;
+        }
+        ride.pending_stop_change = None;
+        Ok(())
+    })
+}
+
+///driver reports arriving at the stop at `index` of the route
+#[update]
+#[candid_method(update)]
+fn arrive_at_stop(ride_id: String, index: u64) -> Result<(), String> {
+    with_ride_mut("arrive_at_stop", &ride_id, |ride| {
+        ensure_ride_driver(ride)?;
+        ensure_not_sanctioned(ic::caller(), Role::Driver)?;
+        if ride.status != RideStatus::Accepted && ride.status != RideStatus::Active {
+            return Err(format!("Stops cannot be reached while {}", ride.status));
+        }
+        let index = index as usize;
+        if index >= ride.waypoints.len() {
+            return Err(format!("No stop at position {}", index));
+        }
+        if ride.waypoints[..index].iter().any(|w| w.status == StopStatus::Pending) {
+            return Err("Earlier stops have not been reached".to_string());
+        }
+        let waypoint = &mut ride.waypoints[index];
+        if waypoint.status == StopStatus::Arrived {
+            return Err("Stop has already been reached".to_string());
+        }
+        waypoint.status = StopStatus::Arrived;
+        waypoint.arrived_at = Some(ic::time());
+        Ok(())
+    })
+}
+
+///driver completes the trip, the fare is computed from the trip duration and route
+#[update]
+#[candid_method(update)]
+fn complete_ride(ride_id: String) -> Result<u64, String> {
+    let config = get_fare_config();
+    let pool_policy = get_pool_policy();
+    with_ride_mut("complete_ride", &ride_id, |ride| {
+        ensure_ride_driver(ride)?;
+        if ride.status != RideStatus::Active {
+            return Err(format!("Ride cannot be completed while {}", ride.status));
+        }
+        let now = ic::time();
+        ride.status = RideStatus::Completed;
+        ride.completed_at = Some(now);
+        ride.pending_stop_change = None;
+        if let Some(dropoff) = ride.waypoints.last_mut() {
+            if dropoff.status == StopStatus::Pending {
+                dropoff.status = StopStatus::Arrived;
+                dropoff.arrived_at = Some(now);
+            }
+        }
+        let duration = ride.trip_duration().unwrap_or(0);
+        ride.fare = ride.pricing().apply(config.fare_for_trip(duration, ride.route_distance_km()));
+        if let Some(pool_id) = &ride.pool_id {
+            ride.fare = pool_policy.discounted(ride.fare);
+            visit_pool_stop(pool_id, &ride.rideid, PoolStopKind::Dropoff);
+        }
+        emit(RideEvent::Completed, ride, ride.fare);
+        Ok(ride.fare)
+    })
+}
+
+///rider or driver cancels a ride, returns the fee charged to the rider
+#[update]
+#[candid_method(update)]
+fn cancel_ride(ride_id: String, reason: String) -> Result<u64, String> {
+    let caller = ic::caller().to_text();
+    let policy = get_cancellation_policy();
+    with_ride_mut("cancel_ride", &ride_id, |ride| {
+        let cancelled_by = if caller == ride.rider.address {
+            CancelledBy::Rider
+        } else if caller == ride.driver.address {
+            CancelledBy::Driver
+        } else {
+            return Err("Caller is not the rider or driver for this ride".to_string());
+        };
+        ensure_party_not_sanctioned(ride)?;
+        if ride.status == RideStatus::Scheduled && cancelled_by == CancelledBy::Driver {
+            //the driver backs out of a pre-accepted ride, it goes back to the upcoming list
+            ride.driver = Driver::unassigned();
+            return Ok(0);
+        }
+        let cancellable = [RideStatus::Scheduled, RideStatus::Requested, RideStatus::Accepted];
+        if !cancellable.contains(&ride.status) {
+            return Err(format!("Ride cannot be cancelled while {}", ride.status));
+        }
+        let now = ic::time();
+        let (fee, driver_penalty) = policy.assess(ride, cancelled_by, now);
+        ride.status = RideStatus::Cancelled;
+        ride.cancelled_at = Some(now);
+        ride.cancellation = Some(Cancellation {
+            cancelled_by,
+            canceller: caller,
+            reason,
+            fee,
+            driver_penalty,
+        });
+        if driver_penalty > 0 {
+            let penalty = -(driver_penalty as i64);
+            let (driver, ride_id) = (&ride.driver.address, Some(ride.rideid.as_str()));
+            let note = match cancelled_by {
+                CancelledBy::Driver => "cancellation penalty",
+                _ => "no-show penalty",
+            };
+            post_earning(driver, ride_id, EarningKind::Adjustment, penalty, note);
+        }
+        if let Some(pool_id) = &ride.pool_id {
+            leave_pool(pool_id, &ride.rideid);
+        }
+        emit(RideEvent::Cancelled, ride, fee);
+        Ok(fee)
+    })
+}
+
+///get the cancellation fee policy
+#[query]
+#[candid_method(query)]
+fn get_cancellation_policy() -> CancellationPolicy {
+    CANCELLATION_POLICY.with(|policy| policy.borrow().clone())
+}
+
+///replace the cancellation fee policy
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_cancellation_policy(policy: CancellationPolicy) {
+    CANCELLATION_POLICY.with(|current| {
+        let summary = diff_summary(&*current.borrow(), &policy);
+        audit("set_cancellation_policy", "cancellation_policy", summary);
+        *current.borrow_mut() = policy;
+    });
+}
+
+/// check a ledger block is a transfer of at least `amount` e8s between two accounts
+fn verify_transfer(
+    block: &Block,
+    from: AccountIdentifier,
+    to: AccountIdentifier,
+    amount: u64,
+) -> Result<(), String> {
+    match &block.transaction.operation {
+        Some(Operation::Transfer {
+            from: block_from,
+            to: block_to,
+            amount: block_amount,
+            ..
+        }) => {
+            if *block_from != from || *block_to != to {
+                return Err("Transfer accounts do not match".to_string());
+            }
+            if block_amount.e8s() < amount {
+                return Err(format!(
+                    "Transfer of {} e8s is less than the {} e8s due",
+                    block_amount.e8s(),
+                    amount
+                ));
+            }
+            Ok(())
+        }
+        _ => Err("Block is not a transfer".to_string()),
+    }
+}
+
+/// ledger account held by this canister for ride payments
+fn escrow_account() -> AccountIdentifier {
+    AccountIdentifier::new(&ic::id(), &DEFAULT_SUBACCOUNT)
+}
+
+/// default ledger account of a rider or driver address
+fn account_of(address: &str) -> Result<AccountIdentifier, String> {
+    let principal = Principal::from_text(address).map_err(|e| e.to_string())?;
+    Ok(AccountIdentifier::new(&principal, &DEFAULT_SUBACCOUNT))
+}
+
+///rider settles the fare or cancellation fee of a ride with a ledger transfer to the canister
+#[update]
+#[candid_method(update)]
+async fn settle_ride(ride_id: String, block_height: BlockHeight) -> Result<(), String> {
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    if ride.rider.address != ic::caller().to_text() {
+        return Err("Caller is not the rider for this ride".to_string());
+    }
+    ensure_not_sanctioned(ic::caller(), Role::Rider)?;
+    if ride.payment.is_some() {
+        return Err("Ride is already settled".to_string());
+    }
+    let amount = ride.amount_due();
+    if amount == 0 {
+        return Err("Nothing is due for this ride".to_string());
+    }
+    let rider_account = account_of(&ride.rider.address)?;
+    receive_payment(block_height, rider_account, amount, || {
+        with_ride_mut("settle_ride", &ride_id, |ride| {
+            if ride.payment.is_some() {
+                return Err("Ride is already settled".to_string());
+            }
+            ride.payment = Some(Payment {
+                block_height,
+                amount,
+                paid_at: ic::time(),
+            });
+            post_settlement(ride);
+            emit(RideEvent::PaymentConfirmed, ride, amount);
+            Ok(())
+        })
+    })
+    .await
+}
+
+///rider tips the driver of a completed ride with a ledger transfer to the canister,
+///the whole tip is credited to the driver
+#[update]
+#[candid_method(update)]
+async fn tip_driver(ride_id: String, amount: u64, block_height: BlockHeight) -> Result<(), String> {
+    let caller = ic::caller().to_text();
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    ride.check_tip(&caller, amount, ic::time())?;
+    let rider_account = account_of(&ride.rider.address)?;
+    receive_payment(block_height, rider_account, amount, || {
+        with_ride_mut("tip_driver", &ride_id, |ride| {
+            record_tip(ride, &caller, amount, block_height)
+        })
+    })
+    .await
+}
+
+/// put a verified tip on the ride and credit the driver
+fn record_tip(
+    ride: &mut Ride,
+    rider: &str,
+    amount: u64,
+    block_height: BlockHeight,
+) -> Result<(), String> {
+    let now = ic::time();
+    ride.check_tip(rider, amount, now)?;
+    ride.tip = Some(Tip {
+        block_height,
+        amount,
+        tipped_at: now,
+    });
+    let tip = amount as i64;
+    post_earning(&ride.driver.address, Some(&ride.rideid), EarningKind::Tip, tip, "");
+    emit(RideEvent::TipReceived, ride, amount);
+    Ok(())
+}
+
+/// add a line to a driver's earnings
+fn post_earning(driver: &str, ride_id: Option<&str>, kind: EarningKind, amount: i64, note: &str) {
+    EARNINGS.with(|earnings| {
+        let mut earnings = earnings.borrow_mut();
+        let entry = EarningEntry {
+            seq: earnings.values().map(|entries| entries.len() as u64).sum(),
+            driver: driver.to_string(),
+            ride_id: ride_id.map(str::to_string),
+            kind,
+            amount,
+            note: note.to_string(),
+            created_at: ic::time(),
+        };
+        audit("post_earning", driver, format!("{} {} for {:?}", kind, amount, ride_id));
+        earnings.entry(driver.to_string()).or_default().push(entry);
+    });
+}
+
+/// credit the driver for a ride the rider has paid for, less the platform fee on fares
+fn post_settlement(ride: &Ride) {
+    let config = get_fare_config();
+    let driver = &ride.driver.address;
+    let ride_id = Some(ride.rideid.as_str());
+    if ride.status == RideStatus::Completed {
+        post_earning(driver, ride_id, EarningKind::Fare, ride.fare as i64, "");
+        let fee = ride.fare * config.platform_fee_percent() / 100;
+        if fee > 0 {
+            let note = format!("{}%", config.platform_fee_percent());
+            post_earning(driver, ride_id, EarningKind::PlatformFee, -(fee as i64), &note);
+        }
+    } else if let Some(cancellation) = &ride.cancellation {
+        let fee = cancellation.fee as i64;
+        post_earning(driver, ride_id, EarningKind::CancellationFee, fee, &cancellation.reason);
+    }
+}
+
+/// a driver's earnings in the time range [start, end) in nanoseconds
+fn earnings_between(driver: &str, start: u64, end: u64) -> Vec<EarningEntry> {
+    EARNINGS.with(|earnings| {
+        let earnings = earnings.borrow();
+        let entries = earnings.get(driver).map_or(&[][..], |entries| &entries[..]);
+        //entries are posted in time order
+        let from = entries.partition_point(|entry| entry.created_at < start);
+        let to = entries.partition_point(|entry| entry.created_at < end);
+        entries[from..to.max(from)].to_vec()
+    })
+}
+
+/// a driver's balance in e8s from everything posted before `end`
+fn balance_at(driver: &str, end: u64) -> i64 {
+    EARNINGS.with(|earnings| {
+        let earnings = earnings.borrow();
+        let entries = earnings.get(driver).map_or(&[][..], |entries| &entries[..]);
+        let to = entries.partition_point(|entry| entry.created_at < end);
+        entries[..to].iter().map(|entry| entry.amount).sum()
+    })
+}
+
+///driver's balance in e8s owed by the canister
+#[query]
+#[candid_method(query)]
+fn get_my_balance() -> i64 {
+    balance_at(&ic::caller().to_text(), u64::MAX)
+}
+
+///driver's earnings in the time range [start, end) in nanoseconds
+#[query]
+#[candid_method(query)]
+fn get_my_earnings(start: u64, end: u64) -> Vec<EarningEntry> {
+    earnings_between(&ic::caller().to_text(), start, end)
+}
+
+///a driver's earnings in the time range [start, end) in nanoseconds
+#[query(guard = "is_finance")]
+#[candid_method(query)]
+fn get_driver_earnings(driver: String, start: u64, end: u64) -> Vec<EarningEntry> {
+    earnings_between(&driver, start, end)
+}
+
+///correct a driver's earnings by a signed amount in e8s
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn adjust_driver_earnings(
+    driver: String,
+    ride_id: Option<String>,
+    amount: i64,
+    note: String,
+) -> Result<(), String> {
+    if note.trim().is_empty() {
+        return Err("Adjustments need a note".to_string());
+    }
+    find_driver(&driver).ok_or_else(|| format!("Driver not found: {}", driver))?;
+    post_earning(&driver, ride_id.as_deref(), EarningKind::Adjustment, amount, &note);
+    Ok(())
+}
+
+///driver's statement for a finished week, `week` is any time in nanoseconds inside it
+#[query]
+#[candid_method(query)]
+fn get_my_statement(week: u64) -> Result<EarningsStatement, String> {
+    let driver = ic::caller().to_text();
+    let start = week_start(week);
+    let end = start + NANOS_PER_WEEK;
+    if end > ic::time() {
+        return Err("Statements are available once the week is over".to_string());
+    }
+    let entries = earnings_between(&driver, start, end);
+    let opening_balance = balance_at(&driver, start);
+    Ok(EarningsStatement {
+        closing_balance: opening_balance + entries.iter().map(|entry| entry.amount).sum::<i64>(),
+        driver,
+        week_start: start,
+        week_end: end,
+        opening_balance,
+        entries,
+    })
+}
+
+///driver's statement for a finished week as csv to download
+#[query]
+#[candid_method(query)]
+fn get_my_statement_csv(week: u64) -> Result<String, String> {
+    get_my_statement(week).map(|statement| statement.to_csv())
+}
+
+///driver sets the ledger account payouts go to, a 32 byte account identifier
+#[update]
+#[candid_method(update)]
+fn set_payout_account(account: Vec<u8>) -> Result<(), String> {
+    let address = ic::caller().to_text();
+    find_driver(&address).ok_or_else(|| "Caller is not a registered driver".to_string())?;
+    let bytes: [u8; 32] = account
+        .try_into()
+        .map_err(|_| "Account identifiers are 32 bytes".to_string())?;
+    let account = AccountIdentifier::try_from(bytes)?;
+    audit("set_payout_account", &address, format!("{}", account));
+    PAYOUT_ACCOUNTS.with(|accounts| accounts.borrow_mut().insert(address, account));
+    Ok(())
+}
+
+/// where a driver's payouts go, the default account of their principal until they set one
+fn payout_account(driver: &str) -> Result<AccountIdentifier, String> {
+    match PAYOUT_ACCOUNTS.with(|accounts| accounts.borrow().get(driver).copied()) {
+        Some(account) => Ok(account),
+        None => account_of(driver),
+    }
+}
+
+///get the payout policy
+#[query]
+#[candid_method(query)]
+fn get_payout_policy() -> PayoutPolicy {
+    PAYOUT_POLICY.with(|policy| policy.borrow().clone())
+}
+
+///replace the payout policy
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_payout_policy(policy: PayoutPolicy) -> Result<(), String> {
+    if policy.threshold <= DEFAULT_FEE.e8s() {
+        return Err("Payout threshold must be more than the ledger fee".to_string());
+    }
+    PAYOUT_POLICY.with(|current| {
+        let summary = diff_summary(&*current.borrow(), &policy);
+        audit("set_payout_policy", "payout_policy", summary);
+        *current.borrow_mut() = policy;
+    });
+    Ok(())
+}
+
+///driver's payouts, newest first
+#[query]
+#[candid_method(query)]
+fn get_my_payouts() -> Vec<Payout> {
+    let address = ic::caller().to_text();
+    let mut payouts: Vec<Payout> = PAYOUTS.with(|payouts| {
+        payouts.borrow().iter().filter(|payout| payout.driver == address).cloned().collect()
+    });
+    payouts.reverse();
+    payouts
+}
+
+///get payouts, optionally only those with `status`
+#[query(guard = "is_finance")]
+#[candid_method(query)]
+fn get_payouts(status: Option<PayoutStatus>) -> Vec<Payout> {
+    PAYOUTS.with(|payouts| {
+        payouts
+            .borrow()
+            .iter()
+            .filter(|payout| status.is_none_or(|status| payout.status == status))
+            .cloned()
+            .collect()
+    })
+}
+
+///start a payout batch now, a dry run only returns the payouts it would create
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn run_payouts(dry_run: bool) -> Vec<Payout> {
+    let now = ic::time();
+    if dry_run {
+        plan_payouts(now)
+    } else {
+        create_payouts(now)
+    }
+}
+
+/// payouts for every driver whose balance has reached the threshold
+fn plan_payouts(now: u64) -> Vec<Payout> {
+    let policy = get_payout_policy();
+    let drivers: Vec<String> =
+        EARNINGS.with(|earnings| earnings.borrow().keys().cloned().collect());
+    let next_id = PAYOUTS.with(|payouts| payouts.borrow().len() as u64);
+    drivers
+        .into_iter()
+        .filter_map(|driver| {
+            let balance = balance_at(&driver, u64::MAX);
+            if balance < policy.threshold as i64 {
+                return None;
+            }
+            let to = payout_account(&driver).ok()?;
+            Some((driver, to, balance as u64))
+        })
+        .enumerate()
+        .map(|(index, (driver, to, amount))| Payout {
+            payout_id: next_id + index as u64,
+            driver,
+            to,
+            amount,
+            status: PayoutStatus::Pending,
+            block_height: None,
+            attempts: 0,
+            last_error: None,
+            created_at: now,
+            in_flight: false,
+            next_attempt_at: None,
+        })
+        .collect()
+}
+
+/// record a payout batch, taking each payout from the driver's balance until it is sent
+fn create_payouts(now: u64) -> Vec<Payout> {
+    let batch = plan_payouts(now);
+    for payout in &batch {
+        let note = format!("payout {}", payout.payout_id);
+        post_earning(&payout.driver, None, EarningKind::Payout, -(payout.amount as i64), &note);
+    }
+    PAYOUTS.with(|payouts| payouts.borrow_mut().extend(batch.iter().cloned()));
+    LAST_PAYOUT_BATCH.with(|last| *last.borrow_mut() = now);
+    batch
+}
+
+/// create a payout batch when the interval has passed and send the pending payouts
+fn schedule_payouts(now: u64) {
+    let last = LAST_PAYOUT_BATCH.with(|last| *last.borrow());
+    if now >= last.saturating_add(get_payout_policy().interval) {
+        create_payouts(now);
+    }
+    let pending: Vec<Payout> = PAYOUTS.with(|payouts| {
+        payouts
+            .borrow()
+            .iter()
+            .filter(|payout| payout.status == PayoutStatus::Pending && !payout.in_flight)
+            .filter(|payout| payout.next_attempt_at.is_none_or(|at| now >= at))
+            .cloned()
+            .collect()
+    });
+    for payout in pending {
+        set_payout_in_flight(payout.payout_id, true);
+        ic_cdk::block_on(async move {
+            let args = ic_ledger_types::TransferArgs {
+                memo: Memo(payout.payout_id),
+                amount: Tokens::from_e8s(payout.amount.saturating_sub(DEFAULT_FEE.e8s())),
+                fee: DEFAULT_FEE,
+                from_subaccount: None,
+                to: payout.to,
+                //the same memo and creation time make the ledger reject a repeat of a payout
+                //that went through but whose reply was lost
+                created_at_time: Some(ic_ledger_types::Timestamp {
+                    timestamp_nanos: payout.created_at,
+                }),
+            };
+            let outcome = match ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, args).await {
+                Ok(Ok(block_height)) => Ok(block_height),
+                Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+                //past the window the ledger no longer checks for duplicates
+                Ok(Err(error @ TransferError::TxTooOld { .. })) => {
+                    Err(PayoutFailure::Unknown(error.to_string()))
+                }
+                Ok(Err(error)) => Err(PayoutFailure::Rejected(error.to_string())),
+                Err((_, message)) => Err(PayoutFailure::Unknown(message)),
+            };
+            finish_payout(payout.payout_id, outcome, ic::time());
+        });
+    }
+}
+
+fn set_payout_in_flight(payout_id: u64, in_flight: bool) {
+    PAYOUTS.with(|payouts| {
+        if let Some(payout) = payouts.borrow_mut().get_mut(payout_id as usize) {
+            payout.in_flight = in_flight;
         }
-    } // delete the field of the driver
-    //delete whole driver
-    pub fn delete_driver(&mut self) {
-        self.name = "".to_string();
-        self.contact = "".to_string();
-        self.email = "".to_string();
-        self.role = "".to_string();
-        self.vehicleplatenumber = "".to_string();
-        self.vehicleseatnumber = "".to_string();
-        self.vehiclemake = "".to_string();
-        self.vehiclemodel = "".to_string();
-        self.vehiclecolor = "".to_string();
-        self.vehicletype = "".to_string();
-        self.vehicleyear = "".to_string();
-        self.rating = 0.0;
-        self.currentstatus = CurrentStatus::Inactive;
-        self.address = "".to_string();
-    } // delete the whole driver
-    
-} // end of impl Driver
+    });
+}
+
+/// record the ledger's answer to a payout transfer, backing off before the next attempt and
+/// returning the balance only once the ledger has refused it and retries have run out
+fn finish_payout(payout_id: u64, outcome: Result<BlockHeight, PayoutFailure>, now: u64) {
+    let max_attempts = get_payout_policy().max_attempts;
+    let failed = PAYOUTS.with(|payouts| {
+        let mut payouts = payouts.borrow_mut();
+        let payout = payouts.get_mut(payout_id as usize)?;
+        payout.in_flight = false;
+        payout.attempts += 1;
+        match outcome {
+            Ok(block_height) => {
+                payout.status = PayoutStatus::Paid;
+                payout.block_height = Some(block_height);
+                audit("heartbeat", &payout.driver, format!("payout {} paid", payout_id));
+                None
+            }
+            Err(failure) => {
+                let (error, rejected) = match failure {
+                    PayoutFailure::Rejected(error) => (error, true),
+                    PayoutFailure::Unknown(error) => (error, false),
+                };
+                audit("heartbeat", &payout.driver, format!("payout {}: {}", payout_id, error));
+                payout.last_error = Some(error);
+                let delay = PAYOUT_RETRY_DELAY.saturating_mul(1 << (payout.attempts - 1).min(30));
+                let retry_at = now.saturating_add(delay);
+                let in_window = retry_at < payout.created_at.saturating_add(PAYOUT_RETRY_WINDOW);
+                if in_window && (!rejected || payout.attempts < max_attempts) {
+                    payout.next_attempt_at = Some(retry_at);
+                    return None;
+                }
+                if !rejected {
+                    payout.status = PayoutStatus::Unconfirmed;
+                    let summary = format!("payout {} unconfirmed", payout_id);
+                    audit("heartbeat", &payout.driver, summary);
+                    return None;
+                }
+                payout.status = PayoutStatus::Failed;
+                Some(payout.clone())
+            }
+        }
+    });
+    if let Some(payout) = failed {
+        let note = format!("payout {} failed", payout.payout_id);
+        post_earning(&payout.driver, None, EarningKind::Payout, payout.amount as i64, &note);
+    }
+}
+
+///support refunds all or part of a paid ride to the rider from escrow or the treasury,
+///optionally taking the driver's share back from their earnings
+#[update(guard = "is_support")]
+#[candid_method(update)]
+async fn issue_refund(
+    ride_id: String,
+    amount: Option<u64>,
+    reason: AdjustmentReason,
+    note: String,
+    from_treasury: bool,
+    charge_driver: bool,
+) -> Result<BlockHeight, String> {
+    let refund = reserve_refund(&ride_id, amount, reason, note, charge_driver)?;
+    let subaccount = if from_treasury {
+        TREASURY_SUBACCOUNT
+    } else {
+        DEFAULT_SUBACCOUNT
+    };
+    let args = ic_ledger_types::TransferArgs {
+        memo: Memo(refund.refund_id),
+        amount: Tokens::from_e8s(refund.amount),
+        fee: DEFAULT_FEE,
+        from_subaccount: Some(subaccount),
+        to: refund.rider,
+        //lets the ledger spot a repeat of a refund that already went through
+        created_at_time: Some(ic_ledger_types::Timestamp {
+            timestamp_nanos: refund.issued_at,
+        }),
+    };
+    let outcome = match ic_ledger_types::transfer(MAINNET_LEDGER_CANISTER_ID, args).await {
+        Ok(Ok(block_height)) => Ok(block_height),
+        Ok(Err(TransferError::TxDuplicate { duplicate_of })) => Ok(duplicate_of),
+        Ok(Err(error)) => Err(error.to_string()),
+        Err((_, message)) => Err(message),
+    };
+    finish_refund(&ride_id, refund.index, outcome.clone())?;
+    outcome
+}
+
+/// a refund recorded on a ride and about to be sent
+struct ReservedRefund {
+    /// position of the refund in the ride's adjustments
+    index: usize,
+    refund_id: u64,
+    amount: u64,
+    rider: AccountIdentifier,
+    issued_at: u64,
+}
+
+/// record a pending refund on a paid, completed ride before it is sent
+fn reserve_refund(
+    ride_id: &str,
+    amount: Option<u64>,
+    reason: AdjustmentReason,
+    note: String,
+    charge_driver: bool,
+) -> Result<ReservedRefund, String> {
+    let fee_percent = get_fare_config().platform_fee_percent();
+    with_ride_mut("issue_refund", ride_id, |ride| {
+        if ride.status != RideStatus::Completed || ride.payment.is_none() {
+            return Err("Only paid, completed rides can be refunded".to_string());
+        }
+        let refundable = ride.refundable();
+        let amount = amount.unwrap_or(refundable);
+        if amount == 0 || amount > refundable {
+            return Err(format!("Refund must be between 1 and {} e8s", refundable));
+        }
+        let rider = account_of(&ride.rider.address)?;
+        //the driver gives back what they were credited for the refunded part of the fare
+        let driver_charged = if charge_driver {
+            amount * (100 - fee_percent) / 100
+        } else {
+            0
+        };
+        if driver_charged > 0 {
+            let (driver, charge) = (&ride.driver.address, -(driver_charged as i64));
+            let note = format!("refund: {:?}", reason);
+            post_earning(driver, Some(ride_id), EarningKind::Adjustment, charge, &note);
+        }
+        let refund_id = REFUND_COUNTER.with(|counter| {
+            let mut counter = counter.borrow_mut();
+            *counter += 1;
+            *counter
+        });
+        let issued_at = ic::time();
+        ride.adjustments_mut().push(FareAdjustment {
+            amount: amount as i64,
+            reason,
+            note,
+            issued_by: ic::caller(),
+            issued_at,
+            driver_charged,
+            refund_status: Some(RefundStatus::Pending),
+            block_height: None,
+            refund_id: Some(refund_id),
+        });
+        Ok(ReservedRefund {
+            index: ride.adjustments().len() - 1,
+            refund_id,
+            amount,
+            rider,
+            issued_at,
+        })
+    })
+}
+
+/// record the ledger's answer to a refund, giving the driver back anything charged if it failed
+fn finish_refund(
+    ride_id: &str,
+    index: usize,
+    outcome: Result<BlockHeight, String>,
+) -> Result<(), String> {
+    with_ride_mut("issue_refund", ride_id, |ride| {
+        let adjustment = ride
+            .adjustments_mut()
+            .get_mut(index)
+            .ok_or_else(|| format!("Adjustment not found: {}", index))?;
+        match outcome {
+            Ok(block_height) => {
+                adjustment.refund_status = Some(RefundStatus::Paid);
+                adjustment.block_height = Some(block_height);
+                let amount = adjustment.amount as u64;
+                emit(RideEvent::FareAdjusted, ride, amount);
+            }
+            Err(_) => {
+                adjustment.refund_status = Some(RefundStatus::Failed);
+                if adjustment.driver_charged > 0 {
+                    let (charged, note) = (adjustment.driver_charged as i64, "refund failed");
+                    let driver = &ride.driver.address;
+                    post_earning(driver, Some(ride_id), EarningKind::Adjustment, charged, note);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+///support changes the fare of a completed ride the rider has not paid yet
+#[update(guard = "is_support")]
+#[candid_method(update)]
+fn adjust_fare(
+    ride_id: String,
+    fare: u64,
+    reason: AdjustmentReason,
+    note: String,
+) -> Result<(), String> {
+    with_ride_mut("adjust_fare", &ride_id, |ride| {
+        if ride.status != RideStatus::Completed {
+            return Err(format!("Fare cannot be adjusted while {}", ride.status));
+        }
+        if ride.payment.is_some() {
+            return Err("Ride is already paid, issue a refund instead".to_string());
+        }
+        let amount = fare as i64 - ride.fare as i64;
+        ride.adjustments_mut().push(FareAdjustment {
+            amount,
+            reason,
+            note,
+            issued_by: ic::caller(),
+            issued_at: ic::time(),
+            driver_charged: 0,
+            refund_status: None,
+            block_height: None,
+            refund_id: None,
+        });
+        ride.fare = fare;
+        emit(RideEvent::FareAdjusted, ride, fare);
+        Ok(())
+    })
+}
+
+/// reserve a ledger block and check it moves `amount` e8s from `from` into escrow, then `record`
+/// the payment; the block is released again if either step fails
+async fn receive_payment(
+    block_height: BlockHeight,
+    from: AccountIdentifier,
+    amount: u64,
+    record: impl FnOnce() -> Result<(), String>,
+) -> Result<(), String> {
+    //reserve the block so it cannot be used twice while the ledger is queried
+    if !USED_BLOCKS.with(|used_blocks| used_blocks.borrow_mut().insert(block_height)) {
+        return Err("Block has already been used for a payment".to_string());
+    }
+    let verified = match get_block_from_ledger(block_height, MAINNET_LEDGER_CANISTER_ID).await {
+        Some(block) => verify_transfer(&block, from, escrow_account(), amount),
+        None => Err(format!("Block not found: {}", block_height)),
+    }
+    .and_then(|_| record());
+    if verified.is_err() {
+        USED_BLOCKS.with(|used_blocks| used_blocks.borrow_mut().remove(&block_height));
+    }
+    verified
+}
+
+///get the fare configuration
+#[query]
+#[candid_method(query)]
+fn get_fare_config() -> FareConfig {
+    FARE_CONFIG.with(|fare_config| fare_config.borrow().clone())
+}
+
+///get the distance and travel time settings
+#[query]
+#[candid_method(query)]
+fn get_geo_config() -> GeoConfig {
+    GEO_CONFIG.with(|geo_config| geo_config.borrow().clone())
+}
+
+///replace the distance and travel time settings
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_geo_config(config: GeoConfig) -> Result<(), String> {
+    config.validate()?;
+    GEO_CONFIG.with(|geo_config| {
+        audit("set_geo_config", "geo_config", diff_summary(&*geo_config.borrow(), &config));
+        *geo_config.borrow_mut() = config;
+    });
+    Ok(())
+}
+
+/// estimated fare for a request before it is confirmed
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct FareQuote {
+    pub distance_km: f64,
+    /// estimated trip time in nanoseconds
+    pub duration: u64,
+    /// zone rules the fare includes
+    pub pricing: Pricing,
+    /// estimated fare in e8s
+    pub fare: u64,
+}
+
+///quote the fare for a request from the estimated road distance and travel time
+#[query]
+#[candid_method(query)]
+fn quote_fare(request: RideRequest) -> Result<FareQuote, String> {
+    request.validate()?;
+    let mut pricing = zone_pricing(&request)?;
+    let rider = ic::caller().to_text();
+    //the vehicle is not known until the ride is dispatched
+    apply_promo(&request.promo_code, &rider, None, &mut pricing)?;
+    let config = get_geo_config();
+    let route = request.route();
+    let distance_km = route
+        .windows(2)
+        .map(|leg| config.road_distance_km(&leg[0], &leg[1]))
+        .sum();
+    let duration = config.route_eta(&route);
+    Ok(FareQuote {
+        distance_km,
+        duration,
+        fare: pricing.apply(get_fare_config().fare_for_trip(duration, distance_km)),
+        pricing,
+    })
+}
+
+///get the service zones
+#[query]
+#[candid_method(query)]
+fn get_service_zones() -> Vec<ServiceZone> {
+    ZONE_STORE.with(|zone_store| zone_store.borrow().values().cloned().collect())
+}
+
+///add a service zone or replace the one with the same id
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_service_zone(zone: ServiceZone) -> Result<(), String> {
+    zone.validate()?;
+    ZONE_STORE.with(|zone_store| {
+        let mut zone_store = zone_store.borrow_mut();
+        let summary = match zone_store.get(&zone.zone_id) {
+            Some(before) => diff_summary(before, &zone),
+            None => format!("added {}", zone.name),
+        };
+        audit("set_service_zone", &zone.zone_id, summary);
+        zone_store.insert(zone.zone_id.clone(), zone);
+    });
+    Ok(())
+}
+
+///remove a service zone, rides already requested keep their pricing
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn remove_service_zone(zone_id: String) -> Result<(), String> {
+    ZONE_STORE
+        .with(|zone_store| zone_store.borrow_mut().remove(&zone_id))
+        .ok_or_else(|| format!("Zone not found: {}", zone_id))?;
+    audit("remove_service_zone", &zone_id, "removed".to_string());
+    Ok(())
+}
+
+/// zone pricing for a request, refused when surge has risen above what the rider was quoted
+fn zone_pricing(request: &RideRequest) -> Result<Pricing, String> {
+    let pricing = zone_rules(request)?;
+    if let Some(quoted) = request.quoted_surge {
+        if pricing.surge_multiplier > quoted {
+            let surge = pricing.surge_multiplier;
+            return Err(format!("Surge has risen to {}x since the {}x quote", surge, quoted));
+        }
+    }
+    Ok(pricing)
+}
+
+/// check the pickup is served and work out the zone pricing for a request, the first enabled
+/// zone by id containing the pickup sets the rules, anywhere is served until a zone is defined
+fn zone_rules(request: &RideRequest) -> Result<Pricing, String> {
+    let zones = get_service_zones();
+    if zones.is_empty() {
+        return Ok(Pricing {
+            surge_multiplier: surge_for(""),
+            ..Pricing::default()
+        });
+    }
+    let enabled = || zones.iter().filter(|zone| zone.enabled);
+    let zone = enabled()
+        .find(|zone| zone.contains(&request.pickup))
+        .ok_or_else(|| "Pickup is outside the service area".to_string())?;
+    //overlapping zones each keep their restrictions, not just the one pricing the ride
+    let restricted = enabled()
+        .filter(|zone| zone.contains(&request.pickup))
+        .find_map(|zone| zone.restricted_at(&request.pickup));
+    if let Some(restricted) = restricted {
+        return Err(format!("Pickups are not allowed at {}", restricted.name));
+    }
+    let surcharge = enabled()
+        .filter(|zone| zone.contains(&request.pickup) || zone.contains(&request.dropoff))
+        .map(|zone| zone.airport_surcharge)
+        .max()
+        .unwrap_or(0);
+    Ok(Pricing {
+        zone_id: Some(zone.zone_id.clone()),
+        fare_multiplier: zone.fare_multiplier,
+        surge_multiplier: surge_for(&zone.zone_id),
+        surcharge,
+        ..Pricing::default()
+    })
+}
+
+///create a promo code
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn create_promo_code(promo: PromoCode) -> Result<(), String> {
+    let code = promo.code.trim().to_uppercase();
+    if code.is_empty() {
+        return Err("Promo code is required".to_string());
+    }
+    if let Discount::Percent(percent) = promo.discount {
+        if percent == 0 || percent > 100 {
+            return Err("Percentage discount must be between 1 and 100".to_string());
+        }
+    }
+    PROMO_CODES.with(|promo_codes| {
+        let mut promo_codes = promo_codes.borrow_mut();
+        if promo_codes.contains_key(&code) {
+            return Err(format!("Promo code already exists: {}", code));
+        }
+        audit("create_promo_code", &code, format!("{:?}", promo.discount));
+        promo_codes.insert(code.clone(), PromoCode { code, ..promo });
+        Ok(())
+    })
+}
+
+///end a promo code now, rides that already used it keep their discount
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn expire_promo_code(code: String) -> Result<(), String> {
+    let code = code.trim().to_uppercase();
+    let now = ic::time();
+    PROMO_CODES.with(|promo_codes| {
+        let mut promo_codes = promo_codes.borrow_mut();
+        let promo = promo_codes
+            .get_mut(&code)
+            .ok_or_else(|| format!("Promo code not found: {}", code))?;
+        audit("expire_promo_code", &code, format!("expires_at: {} -> {}", promo.expires_at, now));
+        promo.expires_at = promo.expires_at.min(now);
+        Ok(())
+    })
+}
+
+///get the promo codes and their redemptions
+#[query(guard = "is_finance")]
+#[candid_method(query)]
+fn get_promo_codes() -> Vec<(PromoCode, Vec<PromoRedemption>)> {
+    let redemptions = PROMO_REDEMPTIONS.with(|redemptions| redemptions.borrow().clone());
+    PROMO_CODES.with(|promo_codes| {
+        promo_codes
+            .borrow()
+            .values()
+            .map(|promo| {
+                let used = redemptions.iter().filter(|redemption| redemption.code == promo.code);
+                (promo.clone(), used.cloned().collect())
+            })
+            .collect()
+    })
+}
+
+/// redemptions of `code` on rides that were not cancelled
+fn live_redemptions(code: &str) -> Vec<PromoRedemption> {
+    PROMO_REDEMPTIONS.with(|redemptions| {
+        redemptions
+            .borrow()
+            .iter()
+            .filter(|redemption| redemption.code == code)
+            .filter(|redemption| {
+                search_ride_by_id(redemption.ride_id.clone())
+                    .is_some_and(|ride| ride.status != RideStatus::Cancelled)
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+/// check `rider` can use the promo code on a ride priced with `pricing` and add its discount,
+/// the vehicle check is skipped while the vehicle is not known
+fn apply_promo(
+    code: &Option<String>,
+    rider: &str,
+    vehicletype: Option<&str>,
+    pricing: &mut Pricing,
+) -> Result<(), String> {
+    let code = match code {
+        Some(code) => code.trim().to_uppercase(),
+        None => return Ok(()),
+    };
+    let promo = PROMO_CODES
+        .with(|promo_codes| promo_codes.borrow().get(&code).cloned())
+        .ok_or_else(|| format!("Promo code not found: {}", code))?;
+    if ic::time() >= promo.expires_at {
+        return Err(format!("Promo code {} has expired", code));
+    }
+    let redemptions = live_redemptions(&code);
+    if promo.max_uses.is_some_and(|max_uses| redemptions.len() as u64 >= max_uses) {
+        return Err(format!("Promo code {} has been used up", code));
+    }
+    let used = redemptions.iter().filter(|redemption| redemption.rider == rider).count();
+    if promo.per_user_limit.is_some_and(|limit| used as u64 >= limit) {
+        return Err(format!("You have already used promo code {}", code));
+    }
+    let in_zone = match &pricing.zone_id {
+        Some(zone_id) => promo.zone_ids.contains(zone_id),
+        None => false,
+    };
+    if !promo.zone_ids.is_empty() && !in_zone {
+        return Err(format!("Promo code {} does not apply in this area", code));
+    }
+    if let Some(vehicletype) = vehicletype {
+        if !promo.vehicle_types.is_empty() && !promo.vehicle_types.iter().any(|t| t == vehicletype)
+        {
+            return Err(format!("Promo code {} does not apply to this vehicle", code));
+        }
+    }
+    if promo.first_ride_only {
+        let has_ridden = RIDES_STORE.with(|rides_store| {
+            rides_store
+                .borrow()
+                .iter()
+                .any(|ride| ride.rider.address == rider && ride.status != RideStatus::Cancelled)
+        });
+        if has_ridden {
+            return Err(format!("Promo code {} is for a first ride only", code));
+        }
+    }
+    pricing.promo_code = Some(code);
+    pricing.discount = Some(promo.discount);
+    Ok(())
+}
+
+/// whether the promo code on the ride, if any, covers `vehicletype`
+fn promo_allows_vehicle(ride: &Ride, vehicletype: &str) -> bool {
+    let vehicle_types = ride.pricing().promo_code.and_then(|code| {
+        PROMO_CODES.with(|promo_codes| {
+            promo_codes.borrow().get(&code).map(|promo| promo.vehicle_types.clone())
+        })
+    });
+    vehicle_types
+        .is_none_or(|types| types.is_empty() || types.iter().any(|t| t == vehicletype))
+}
+
+/// drivers whose vehicle the promo code on a scheduled ride does not cover
+fn promo_excluded_drivers(ride: &Ride) -> BTreeSet<String> {
+    DRIVER_STORE.with(|driver_store| {
+        driver_store
+            .borrow()
+            .iter()
+            .filter(|driver| !promo_allows_vehicle(ride, &driver.vehicletype))
+            .map(|driver| driver.address.clone())
+            .collect()
+    })
+}
+
+/// record the promo code used on a new ride
+fn record_redemption(ride: &Ride) {
+    if let Some(code) = &ride.pricing().promo_code {
+        audit("redeem_promo_code", code, format!("ride {}", ride.rideid));
+        PROMO_REDEMPTIONS.with(|redemptions| {
+            redemptions.borrow_mut().push(PromoRedemption {
+                code: code.clone(),
+                rider: ride.rider.address.clone(),
+                ride_id: ride.rideid.clone(),
+                redeemed_at: ride.requested_at,
+            })
+        });
+    }
+}
+
+///get the surge pricing policy
+#[query]
+#[candid_method(query)]
+fn get_surge_policy() -> SurgePolicy {
+    SURGE_POLICY.with(|policy| policy.borrow().clone())
+}
+
+///replace the surge pricing policy, the cap applies from the next update
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_surge_policy(policy: SurgePolicy) -> Result<(), String> {
+    policy.validate()?;
+    SURGE_POLICY.with(|current| {
+        let summary = diff_summary(&*current.borrow(), &policy);
+        audit("set_surge_policy", "surge_policy", summary);
+        *current.borrow_mut() = policy;
+    });
+    Ok(())
+}
+
+///get the current surge multipliers
+#[query]
+#[candid_method(query)]
+fn get_surge() -> SurgeTable {
+    SURGE.with(|surge| surge.borrow().clone())
+}
+
+/// current surge multiplier for a zone
+fn surge_for(zone_id: &str) -> f64 {
+    SURGE.with(|surge| surge.borrow().multipliers.get(zone_id).copied().unwrap_or(1.0))
+}
+
+/// recompute the surge in each zone from open requests and on-duty drivers there,
+/// at most once per update interval
+fn update_surge(now: u64) {
+    let policy = get_surge_policy();
+    let updated_at = SURGE.with(|surge| surge.borrow().updated_at);
+    if now < updated_at.saturating_add(policy.update_interval) {
+        return;
+    }
+    let zones: Vec<ServiceZone> =
+        get_service_zones().into_iter().filter(|zone| zone.enabled).collect();
+    //drivers count towards the zone they last reported from
+    let mut located: BTreeSet<String> = BTreeSet::new();
+    let mut drivers: BTreeMap<String, u64> = BTreeMap::new();
+    for driver in DRIVER_STORE.with(|driver_store| driver_store.borrow().clone()) {
+        if driver.currentstatus != CurrentStatus::Active || !is_approved_driver(&driver.address) {
+            continue;
+        }
+        let zone_id = if zones.is_empty() {
+            Some(String::new())
+        } else {
+            DRIVER_LOCATIONS
+                .with(|locations| locations.borrow().get(&driver.address).cloned())
+                .and_then(|position| zones.iter().find(|zone| zone.contains(&position.location)))
+                .map(|zone| zone.zone_id.clone())
+        };
+        if let Some(zone_id) = zone_id {
+            located.insert(driver.address);
+            *drivers.entry(zone_id).or_default() += 1;
+        }
+    }
+    //requests offered to a driver who cannot be placed in a zone are left out with the driver
+    let mut requests: BTreeMap<String, u64> = BTreeMap::new();
+    RIDES_STORE.with(|rides_store| {
+        for ride in rides_store.borrow().iter() {
+            if ride.status == RideStatus::Requested && located.contains(&ride.driver.address) {
+                let zone_id = ride.pricing().zone_id.unwrap_or_default();
+                *requests.entry(zone_id).or_default() += 1;
+            }
+        }
+    });
+    let zone_ids = if zones.is_empty() {
+        vec![String::new()]
+    } else {
+        zones.into_iter().map(|zone| zone.zone_id).collect()
+    };
+    let multipliers: BTreeMap<String, f64> = zone_ids
+        .into_iter()
+        .map(|zone_id| {
+            let requests = requests.get(&zone_id).copied().unwrap_or(0);
+            let drivers = drivers.get(&zone_id).copied().unwrap_or(0);
+            let multiplier = policy.multiplier(requests, drivers);
+            (zone_id, multiplier)
+        })
+        .collect();
+    SURGE.with(|surge| {
+        let mut surge = surge.borrow_mut();
+        for (zone_id, multiplier) in &multipliers {
+            let before = surge.multipliers.get(zone_id).copied().unwrap_or(1.0);
+            if before != *multiplier {
+                let summary = format!("surge {} -> {}", before, multiplier);
+                audit("heartbeat", &format!("surge:{}", zone_id), summary);
+            }
+        }
+        *surge = SurgeTable {
+            updated_at: now,
+            multipliers,
+        };
+    });
+}
+
+///replace the fare configuration
+#[update(guard = "is_finance")]
+#[candid_method(update)]
+fn set_fare_config(config: FareConfig) {
+    FARE_CONFIG.with(|fare_config| {
+        audit("set_fare_config", "fare_config", diff_summary(&*fare_config.borrow(), &config));
+        *fare_config.borrow_mut() = config;
+    });
+}
 
-///search for riders by field and return the results
+///get rides requested in the time range [start, end) in nanoseconds
 #[query]
 #[candid_method(query)]
-fn search_rider_by_field(field: String, value: String) -> Vec<Option<Rider>> {
-    RIDER_STORE.with(|rider_store| {
-        let mut result = Vec::new();
-        for rider in rider_store.borrow().iter() {
-            if rider.get_field(field.clone()) == value {
-                result.push(Some(rider.clone()));
+fn get_rides_between(start: u64, end: u64) -> RidesStore {
+    let viewer = Viewer::caller();
+    RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.requested_at >= start && ride.requested_at < end)
+            .map(|ride| viewer.redacted_ride(ride))
+            .collect()
+    })
+}
+
+/// cancel requests that no driver accepted within RIDE_REQUEST_TIMEOUT
+fn expire_stale_requests(now: u64) {
+    RIDES_STORE.with(|rides_store| {
+        for ride in rides_store.borrow_mut().iter_mut() {
+            let offered_at = ride.dispatched_at.unwrap_or(ride.requested_at);
+            if ride.status == RideStatus::Requested
+                && now.saturating_sub(offered_at) > RIDE_REQUEST_TIMEOUT
+            {
+                ride.status = RideStatus::Cancelled;
+                ride.cancelled_at = Some(now);
+                ride.cancellation = Some(Cancellation {
+                    cancelled_by: CancelledBy::System,
+                    canceller: ic::id().to_text(),
+                    reason: "No driver accepted the request".to_string(),
+                    fee: 0,
+                    driver_penalty: 0,
+                });
+                audit("heartbeat", &ride.rideid, "request expired".to_string());
+                emit(RideEvent::Cancelled, ride, 0);
+                if let Some(pool_id) = &ride.pool_id {
+                    leave_pool(pool_id, &ride.rideid);
+                }
             }
         }
-        result
-    })
+    });
 }
 
-///search for drivers by field and return the results  
-#[query]
-#[candid_method(query)]
-fn search_driver_by_field(field: String, value: String) -> Vec<Option<Driver>> {
-    DRIVER_STORE.with(|driver_store| {
-        let mut results = Vec::new();
-        for driver in driver_store.borrow().iter() {
-            if driver.get_field(field.clone()) == value {
-                results.push(Some(driver.clone()));
-            } else {
-                results.push(None);
+///on-duty driver reports where they are, at most once per LOCATION_REPORT_INTERVAL
+#[update]
+#[candid_method(update)]
+fn report_location(lat: f64, lng: f64, heading: f64) -> Result<(), String> {
+    let address = ic::caller().to_text();
+    let driver =
+        find_driver(&address).ok_or_else(|| "Caller is not a registered driver".to_string())?;
+    if driver.currentstatus != CurrentStatus::Active || !is_approved_driver(&address) {
+        return Err("Only on-duty drivers can report their location".to_string());
+    }
+    let location = Location {
+        address_text: "".to_string(),
+        lat,
+        lng,
+    };
+    location.validate()?;
+    if !(0.0..360.0).contains(&heading) {
+        return Err("Heading must be in [0, 360) degrees".to_string());
+    }
+    let now = ic::time();
+    let last = DRIVER_LOCATIONS.with(|locations| {
+        locations.borrow().get(&address).map(|position| position.reported_at)
+    });
+    if last.is_some_and(|last| now < last + LOCATION_REPORT_INTERVAL) {
+        return Err("Location reported too often".to_string());
+    }
+    let position = DriverPosition {
+        location,
+        heading,
+        reported_at: now,
+    };
+    DRIVER_LOCATIONS.with(|locations| {
+        locations.borrow_mut().insert(address.clone(), position.clone());
+    });
+    //positions are not audited, the breadcrumb trail keeps them for the rides that need them
+    let on_trip: Vec<String> = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.driver.address == address)
+            .filter(|ride| matches!(ride.status, RideStatus::Accepted | RideStatus::Active))
+            .map(|ride| ride.rideid.clone())
+            .collect()
+    });
+    BREADCRUMBS.with(|breadcrumbs| {
+        let mut breadcrumbs = breadcrumbs.borrow_mut();
+        for ride_id in on_trip {
+            let trail = breadcrumbs.entry(ride_id).or_default();
+            if trail.len() >= MAX_BREADCRUMBS {
+                //keep the whole route at half the resolution
+                let mut index = 0;
+                trail.retain(|_| {
+                    index += 1;
+                    index % 2 == 1
+                });
             }
+            trail.push(position.clone());
         }
-        results
-    })
+    });
+    Ok(())
 }
 
-//search for rides by field and return all results
+///rider of an accepted or active ride sees where the driver is and when they will arrive
 #[query]
 #[candid_method(query)]
-fn search_ride_by_field(field: String, value: String) -> Vec<Option<Ride>> {
-    RIDES_STORE.with(|ride_store| {
-        let mut result = Vec::new();
-        for ride in ride_store.borrow().iter() {
-            if ride.get_field(field.clone()) == value {
-                result.push(Some(ride.clone()));
-            }
-        }
-        result
+fn get_ride_tracking(ride_id: String) -> Result<RideTracking, String> {
+    let ride = search_ride_by_id(ride_id.clone())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    if ride.rider.address != ic::caller().to_text() {
+        return Err("Caller is not the rider for this ride".to_string());
+    }
+    //waiting riders track the driver to the pickup, riding ones to the next stop
+    let heading_to = match ride.status {
+        RideStatus::Accepted => ride.waypoints.first(),
+        RideStatus::Active => ride
+            .waypoints
+            .iter()
+            .find(|waypoint| waypoint.status == StopStatus::Pending),
+        _ => return Err(format!("Ride is not tracked while {}", ride.status)),
+    }
+    .map(|waypoint| waypoint.location.clone())
+    .ok_or_else(|| "Ride has no stop left to reach".to_string())?;
+    let position = DRIVER_LOCATIONS
+        .with(|locations| locations.borrow().get(&ride.driver.address).cloned())
+        .ok_or_else(|| "Driver has not reported a location yet".to_string())?;
+    let eta = get_geo_config().eta(&position.location, &heading_to);
+    Ok(RideTracking {
+        ride_id,
+        position,
+        heading_to,
+        eta,
     })
 }
 
-/// ridestatus enum for ride struct to represent the status of the ride
-#[derive(PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
-pub enum RideStatus {
-    Active,
-    Completed,
-    Cancelled,
+///the driver's positions during a ride, kept for dispute resolution
+#[query(guard = "is_support")]
+#[candid_method(query)]
+fn get_ride_breadcrumbs(ride_id: String) -> Vec<DriverPosition> {
+    BREADCRUMBS.with(|breadcrumbs| {
+        breadcrumbs.borrow().get(&ride_id).cloned().unwrap_or_default()
+    })
 }
 
+/// the ride, if the caller is its rider, its driver or support
+fn ride_for_chat(ride_id: &str) -> Result<Ride, String> {
+    let ride = search_ride_by_id(ride_id.to_string())
+        .ok_or_else(|| format!("Ride not found: {}", ride_id))?;
+    let caller = ic::caller().to_text();
+    if caller != ride.rider.address && caller != ride.driver.address && is_support().is_err() {
+        return Err("Caller is not on this ride".to_string());
+    }
+    Ok(ride)
+}
 
-//remove a ride from the ride store
-#[update]
-#[candid_method(update)]
-fn remove_ride(ride_id: String) {
-    RIDES_STORE.with(|ride_store| {
-        let mut ride_store = ride_store.borrow_mut();
-        let mut index = 0;
-        for ride in ride_store.iter() {
-            if ride.rideid == ride_id {
-                ride_store.remove(index);
-                break;
-            }
-            index += 1;
-        }
+/// which of the ride's rider, driver or support a principal is in its chat
+fn chat_party(ride: &Ride, address: &str) -> &'static str {
+    if address == ride.rider.address {
+        "rider"
+    } else if address == ride.driver.address {
+        "driver"
+    } else {
+        "support"
+    }
+}
+
+fn post_chat_message(
+    ride_id: String,
+    text: String,
+    quick_reply: Option<QuickReply>,
+) -> Result<u64, String> {
+    let ride = ride_for_chat(&ride_id)?;
+    if !matches!(ride.status, RideStatus::Accepted | RideStatus::Active) {
+        return Err(format!("Chat is closed while the ride is {}", ride.status));
+    }
+    if text.trim().is_empty() || text.chars().count() > MAX_CHAT_MESSAGE {
+        return Err(format!("Messages must be 1 to {} characters", MAX_CHAT_MESSAGE));
+    }
+    let now = ic::time();
+    let message_id = CHAT_THREADS.with(|chat_threads| {
+        let mut chat_threads = chat_threads.borrow_mut();
+        let thread = chat_threads
+            .entry(ride_id.clone())
+            .or_insert_with(|| ChatThread {
+                ride_id: ride_id.clone(),
+                messages: vec![],
+                archived_at: None,
+            });
+        let message_id = thread.messages.len() as u64;
+        thread.messages.push(ChatMessage {
+            message_id,
+            sender: relay_id(&ride_id, chat_party(&ride, &ic::caller().to_text())),
+            text,
+            quick_reply,
+            sent_at: now,
+            read_at: None,
+        });
+        message_id
     });
+    audit("send_chat_message", &ride_id, format!("sent message {}", message_id));
+    Ok(message_id)
 }
 
-//remove a ride in the store then add the new one
+///send a message to the other party of an accepted or active ride
 #[update]
 #[candid_method(update)]
-fn update_ride(ride_id: String, ride: Ride) {
-    RIDES_STORE.with(|ride_store| {
-        let mut ride_store = ride_store.borrow_mut();
-        let mut index = 0;
-        for ride_ in ride_store.iter() {
-            if ride_.rideid == ride_id {
-                ride_store.remove(index);
-                break;
-            }
-            index += 1;
-        }
-    });
-    RIDES_STORE.with(|ride_store| {
-        let mut ride_store = ride_store.borrow_mut();
-        ride_store.push(ride);
-    });
+fn send_chat_message(ride_id: String, text: String) -> Result<u64, String> {
+    post_chat_message(ride_id, text, None)
 }
 
-//remove a rider from the store by address
+///send one of the canned quick replies
 #[update]
 #[candid_method(update)]
-fn remove_rider(address: String) {
-    RIDER_STORE.with(|rider_store| {
-        let mut rider_store = rider_store.borrow_mut();
-        let mut index = 0;
-        for rider in rider_store.iter() {
-            if rider.address == address {
-                rider_store.remove(index);
-                break;
-            }
-            index += 1;
-        }
-    });
+fn send_quick_reply(ride_id: String, reply: QuickReply) -> Result<u64, String> {
+    post_chat_message(ride_id, reply.text().to_string(), Some(reply))
+}
+
+///the ride's chat thread
+#[query]
+#[candid_method(query)]
+fn get_chat(ride_id: String) -> Result<ChatThread, String> {
+    ride_for_chat(&ride_id)?;
+    Ok(CHAT_THREADS.with(|chat_threads| {
+        chat_threads.borrow().get(&ride_id).cloned().unwrap_or(ChatThread {
+            ride_id: ride_id.clone(),
+            messages: vec![],
+            archived_at: None,
+        })
+    }))
 }
 
-//remove a driver from the store by address and add the new one
+///mark the other party's messages up to `message_id` as read, for the rider and driver only
 #[update]
 #[candid_method(update)]
-fn update_driver(address: String, driver: Driver) {
-    DRIVER_STORE.with(|driver_store| {
-        let mut driver_store = driver_store.borrow_mut();
-        let mut index = 0;
-        for driver_ in driver_store.iter() {
-            if driver_.address == address {
-                driver_store.remove(index);
-                break;
+fn mark_chat_read(ride_id: String, message_id: u64) -> Result<(), String> {
+    let ride = ride_for_chat(&ride_id)?;
+    let party = chat_party(&ride, &ic::caller().to_text());
+    if party == "support" {
+        return Err("Only the rider or driver can mark messages read".to_string());
+    }
+    let reader = relay_id(&ride_id, party);
+    let now = ic::time();
+    CHAT_THREADS.with(|chat_threads| {
+        if let Some(thread) = chat_threads.borrow_mut().get_mut(&ride_id) {
+            if thread.archived_at.is_some() {
+                return Err("Chat is archived".to_string());
+            }
+            for message in thread.messages.iter_mut() {
+                if message.message_id <= message_id
+                    && message.sender != reader
+                    && message.read_at.is_none()
+                {
+                    message.read_at = Some(now);
+                }
             }
-            index += 1;
         }
+        Ok(())
+    })?;
+    audit("mark_chat_read", &ride_id, format!("read up to message {}", message_id));
+    Ok(())
+}
+
+/// archive the threads of rides that have ended
+fn archive_chats(now: u64) {
+    let statuses: BTreeMap<String, RideStatus> = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .map(|ride| (ride.rideid.clone(), ride.status))
+            .collect()
     });
-    DRIVER_STORE.with(|driver_store| {
-        let mut driver_store = driver_store.borrow_mut();
-        driver_store.push(driver);
+    CHAT_THREADS.with(|chat_threads| {
+        for thread in chat_threads.borrow_mut().values_mut() {
+            if thread.archived_at.is_some() {
+                continue;
+            }
+            let ended = statuses.get(&thread.ride_id).is_none_or(|status| {
+                matches!(status, RideStatus::Completed | RideStatus::Cancelled)
+            });
+            if ended {
+                thread.archived_at = Some(now);
+            }
+        }
     });
 }
 
+/// ids of the rides with an open or investigating dispute
+fn disputed_rides() -> BTreeSet<String> {
+    DISPUTES.with(|disputes| {
+        disputes
+            .borrow()
+            .iter()
+            .filter(|dispute| {
+                matches!(dispute.status, DisputeStatus::Open | DisputeStatus::Investigating)
+            })
+            .map(|dispute| dispute.ride_id.clone())
+            .collect()
+    })
+}
 
-///Ride struct for the ride table
-#[derive(Debug, Deserialize, Clone, CandidType)]
-pub struct Ride {
-    pub rideid: String,
-    pub driver: Driver,
-    pub rider: Rider,
-    pub pickup: String,
-    pub dropoff: String,
-    pub status: RideStatus,
-    pub timestamp: String,
-    pub rating: f64,
-    pub driverrating: f64,
-    pub riderrating: f64,
-    pub driverfeedback: String,
-    pub riderfeedback: String,
-    pub riderconfirmation: String,
-    pub driverconfirmation: String,
+/// drop the breadcrumbs of rides that ended over BREADCRUMB_RETENTION ago and are not disputed
+fn prune_breadcrumbs(now: u64) {
+    let disputed = disputed_rides();
+    let expired: BTreeSet<String> = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| {
+                ride.completed_at
+                    .or(ride.cancelled_at)
+                    .is_some_and(|ended| now.saturating_sub(ended) > BREADCRUMB_RETENTION)
+            })
+            .map(|ride| ride.rideid.clone())
+            .collect()
+    });
+    BREADCRUMBS.with(|breadcrumbs| {
+        breadcrumbs
+            .borrow_mut()
+            .retain(|ride_id, _| disputed.contains(ride_id) || !expired.contains(ride_id))
+    });
 }
 
-#[allow(dead_code)]
-/// implement the ride struct with the following functions
-impl Ride {
-    fn update_rider_confirmation(&mut self, confirmation: String) {
-        self.riderconfirmation = confirmation;
-    }
+/// append a domain event for the ride, sequence numbers start at 1
+fn emit(event: RideEvent, ride: &Ride, amount: u64) {
+    EVENT_LOG.append(&DomainEvent {
+        seq: EVENT_LOG.len() + 1,
+        event,
+        ride_id: ride.rideid.clone(),
+        rider: relay_id(&ride.rideid, "rider"),
+        driver: relay_id(&ride.rideid, "driver"),
+        status: ride.status,
+        amount,
+        timestamp: ic::time(),
+    });
+}
 
-    fn update_driver_confirmation(&mut self, confirmation: String) {
-        self.driverconfirmation = confirmation;
-    }
-    fn update_driver_rating(&mut self, rating: f64) {
-        self.driverrating = rating;
-    }
-    fn update_rider_rating(&mut self, rating: f64) {
-        self.riderrating = rating;
-    }
-    fn update_driver_feedback(&mut self, feedback: String) {
-        self.driverfeedback = feedback;
-    }
-    fn update_rider_feedback(&mut self, feedback: String) {
-        self.riderfeedback = feedback;
-    }
-    fn update_rating(&mut self, rating: f64) {
-        self.rating = rating;
-    }
-    fn update_status(&mut self, status: RideStatus) {
-        self.status = status;
-    }
-    fn update_timestamp(&mut self, timestamp: String) {
-        self.timestamp = timestamp;
-    }
-    fn update_dropoff(&mut self, dropoff: String) {
-        self.dropoff = dropoff;
-    }
-    fn update_pickup(&mut self, pickup: String) {
-        self.pickup = pickup;
-    }
-    fn update_rider(&mut self, rider: Rider) {
-        self.rider = rider;
-    }
-    fn update_driver(&mut self, driver: Driver) {
-        self.driver = driver;
-    }
-    fn update_rideid(&mut self, rideid: String) {
-        self.rideid = rideid;
+///domain events after `seq` for rides the caller is on, support sees every ride
+#[query]
+#[candid_method(query)]
+fn get_events_since(seq: u64, filter: EventFilter) -> EventPage {
+    let caller = ic::caller().to_text();
+    let support = is_support().is_ok();
+    let on_ride: BTreeSet<String> = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.rider.address == caller || ride.driver.address == caller)
+            .map(|ride| ride.rideid.clone())
+            .collect()
+    });
+    let end = EVENT_LOG.len().min(seq.saturating_add(MAX_EVENT_SCAN));
+    let mut events = vec![];
+    for index in seq..end {
+        let event = EVENT_LOG.get(index).expect("domain event below the log length");
+        if !(support || on_ride.contains(&event.ride_id)) || !filter.matches(&event) {
+            continue;
+        }
+        events.push(event);
+        if events.len() == MAX_EVENT_BATCH {
+            return EventPage {
+                events,
+                next: index + 1,
+            };
+        }
     }
+    //a scan that found nothing still moves the caller past what it read
+    EventPage { events, next: end }
+}
 
-    fn get_rider_confirmation(&self) -> String {
-        self.riderconfirmation.clone()
-    }
-    fn get_driver_confirmation(&self) -> String {
-        self.driverconfirmation.clone()
-    }
-    fn get_driver_rating(&self) -> f64 {
-        self.driverrating
+///push domain events to a canister's `on_ride_events` method from now on
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn register_subscriber(canister: Principal) {
+    let delivered = EVENT_LOG.len();
+    SUBSCRIBERS.with(|subscribers| {
+        subscribers.borrow_mut().insert(
+            canister,
+            Subscriber {
+                canister,
+                delivered,
+                in_flight: false,
+            },
+        );
+    });
+    audit("register_subscriber", &canister.to_text(), "subscribed".to_string());
+}
+
+///stop pushing domain events to a canister
+#[update(guard = "is_controller")]
+#[candid_method(update)]
+fn unregister_subscriber(canister: Principal) {
+    SUBSCRIBERS.with(|subscribers| subscribers.borrow_mut().remove(&canister));
+    audit("unregister_subscriber", &canister.to_text(), "unsubscribed".to_string());
+}
+
+/// events the subscriber has not received yet, up to one batch
+fn pending_events(subscriber: &Subscriber) -> Vec<DomainEvent> {
+    let end = EVENT_LOG.len().min(subscriber.delivered + MAX_EVENT_BATCH as u64);
+    (subscriber.delivered..end).filter_map(|index| EVENT_LOG.get(index)).collect()
+}
+
+/// send each subscriber its next batch, one call in flight per subscriber
+fn push_events_to_subscribers() {
+    let ready: Vec<Subscriber> = SUBSCRIBERS.with(|subscribers| {
+        subscribers
+            .borrow()
+            .values()
+            .filter(|subscriber| !subscriber.in_flight)
+            .cloned()
+            .collect()
+    });
+    for subscriber in ready {
+        let events = pending_events(&subscriber);
+        let last = match events.last() {
+            Some(event) => event.seq,
+            None => continue,
+        };
+        set_in_flight(subscriber.canister, true);
+        ic_cdk::block_on(async move {
+            let result: Result<(), _> =
+                ic::call(subscriber.canister, "on_ride_events", (events,)).await;
+            SUBSCRIBERS.with(|subscribers| {
+                if let Some(subscriber) = subscribers.borrow_mut().get_mut(&subscriber.canister) {
+                    subscriber.in_flight = false;
+                    //failed pushes are retried from the same event on the next heartbeat
+                    if result.is_ok() {
+                        subscriber.delivered = last;
+                    }
+                }
+            });
+        });
     }
-    fn get_rider_rating(&self) -> f64 {
-        self.riderrating
+}
+
+fn set_in_flight(canister: Principal, in_flight: bool) {
+    SUBSCRIBERS.with(|subscribers| {
+        if let Some(subscriber) = subscribers.borrow_mut().get_mut(&canister) {
+            subscriber.in_flight = in_flight;
+        }
+    });
+}
+
+/// append an event to the audit log
+fn audit(endpoint: &str, entity_id: &str, summary: String) {
+    AUDIT_LOG.append(&AuditEvent {
+        seq: AUDIT_LOG.len(),
+        caller: ic::caller(),
+        timestamp: ic::time(),
+        endpoint: endpoint.to_string(),
+        entity_id: entity_id.to_string(),
+        summary,
+    });
+}
+
+/// top level fields of a value's pretty debug output with their values on one line
+fn debug_fields<T: fmt::Debug>(value: &T) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in format!("{:#?}", value).lines().skip(1) {
+        let is_field = line.starts_with("    ") && !line.starts_with("     ");
+        match line.trim().split_once(": ") {
+            Some((name, value)) if is_field => {
+                fields.push((name.to_string(), value.trim_end_matches(',').to_string()))
+            }
+            _ => {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim().trim_end_matches(','));
+                }
+            }
+        }
     }
-    fn get_driver_feedback(&self) -> String {
-        self.driverfeedback.clone()
+    fields
+}
+
+/// the names of the fields that differ between two versions of a record, values are left out
+/// so contact details and principals never reach the audit log
+fn diff_summary<T: fmt::Debug>(before: &T, after: &T) -> String {
+    let after = debug_fields(after);
+    let changed: Vec<String> = debug_fields(before)
+        .into_iter()
+        .zip(after)
+        .filter(|(before, after)| before != after)
+        .map(|((name, _), _)| name)
+        .collect();
+    if changed.is_empty() {
+        return String::new();
     }
-    fn get_rider_feedback(&self) -> String {
-        self.riderfeedback.clone()
+    format!("changed: {}", changed.join(", "))
+}
+
+///page through audit events matching the filter, oldest first from `cursor`
+#[query(guard = "is_support")]
+#[candid_method(query)]
+fn get_audit_events(filter: AuditFilter, cursor: u64, limit: u64) -> AuditPage {
+    let limit = limit.clamp(1, MAX_AUDIT_PAGE) as usize;
+    let end = AUDIT_LOG.len().min(cursor.saturating_add(MAX_AUDIT_SCAN));
+    let mut events = vec![];
+    for seq in cursor..end {
+        let event = AUDIT_LOG.get(seq).expect("audit event below the log length");
+        if !filter.matches(&event) {
+            continue;
+        }
+        if events.len() == limit {
+            return AuditPage {
+                events,
+                next: Some(seq),
+            };
+        }
+        events.push(event);
     }
-    fn get_rating(&self) -> f64 {
-        self.rating
+    //a scan that stopped short of the end carries on from there
+    let next = if end < AUDIT_LOG.len() { Some(end) } else { None };
+    AuditPage { events, next }
+}
+
+/// everything the canister holds about one principal
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct MyData {
+    pub account: Account,
+    pub roles: Vec<Role>,
+    pub driver_application: Option<DriverApplication>,
+    pub sanctions: Vec<Sanction>,
+    pub rides: RidesStore,
+    pub payments: Vec<Payment>,
+    pub chats: Vec<ChatThread>,
+    pub promo_redemptions: Vec<PromoRedemption>,
+    pub earnings: Vec<EarningEntry>,
+    pub payouts: Vec<Payout>,
+    pub disputes: Vec<Dispute>,
+    pub notifications: Vec<Notification>,
+}
+
+///export everything the canister holds about the caller
+#[query]
+#[candid_method(query)]
+fn export_my_data() -> MyData {
+    let address = ic::caller().to_text();
+    let viewer = Viewer::caller();
+    let rides: RidesStore = RIDES_STORE.with(|rides_store| {
+        rides_store
+            .borrow()
+            .iter()
+            .filter(|ride| ride.rider.address == address || ride.driver.address == address)
+            .map(|ride| viewer.redacted_ride(ride))
+            .collect()
+    });
+    let payments = rides
+        .iter()
+        .filter(|ride| ride.rider.address == address)
+        .filter_map(|ride| ride.payment.clone())
+        .collect();
+    MyData {
+        account: get_self(),
+        roles: get_my_roles(),
+        driver_application: get_my_driver_application(),
+        sanctions: get_my_sanctions(),
+        chats: rides.iter().filter_map(|ride| get_chat(ride.rideid.clone()).ok()).collect(),
+        promo_redemptions: PROMO_REDEMPTIONS.with(|redemptions| {
+            let redemptions = redemptions.borrow();
+            redemptions.iter().filter(|redemption| redemption.rider == address).cloned().collect()
+        }),
+        earnings: earnings_between(&address, 0, u64::MAX),
+        payouts: get_my_payouts(),
+        disputes: get_my_disputes(),
+        notifications: get_my_notifications(false),
+        rides,
+        payments,
     }
-    fn get_status(&self) -> RideStatus {
-        self.status
+}
+
+///delete the caller's accounts, anonymizing ride history but keeping fares and payments
+#[update]
+#[candid_method(update)]
+fn delete_my_account() -> Result<(), String> {
+    let caller = authenticated_caller()?;
+    let address = caller.to_text();
+    let in_progress = RIDES_STORE.with(|rides_store| {
+        rides_store.borrow().iter().any(|ride| {
+            (ride.rider.address == address || ride.driver.address == address)
+                && !matches!(ride.status, RideStatus::Completed | RideStatus::Cancelled)
+        })
+    });
+    if in_progress {
+        return Err("Finish or cancel your rides before deleting your account".to_string());
     }
-    fn get_timestamp(&self) -> String {
-        self.timestamp.clone()
+    //rides under an open dispute keep their chat and breadcrumbs until it is closed
+    let disputed = disputed_rides();
+    let mut ride_ids = BTreeSet::new();
+    RIDES_STORE.with(|rides_store| {
+        for ride in rides_store.borrow_mut().iter_mut() {
+            if ride.rider.address != address && ride.driver.address != address {
+                continue;
+            }
+            ride_ids.insert(ride.rideid.clone());
+            if ride.rider.address == address {
+                ride.rider.anonymize();
+            }
+            if ride.driver.address == address {
+                ride.driver.anonymize();
+            }
+            if let Some(cancellation) = ride.cancellation.as_mut() {
+                if cancellation.canceller == address {
+                    cancellation.canceller = DELETED_ADDRESS.to_string();
+                }
+            }
+            ride.scrub_locations();
+            ride.riderfeedback = "".to_string();
+            ride.driverfeedback = "".to_string();
+        }
+    });
+    POOL_STORE.with(|pool_store| {
+        for pool in pool_store.borrow_mut().iter_mut() {
+            if pool.driver == address {
+                pool.driver = DELETED_ADDRESS.to_string();
+            }
+            for stop in pool.route.iter_mut().filter(|stop| ride_ids.contains(&stop.ride_id)) {
+                stop.location = Location::scrubbed();
+            }
+        }
+    });
+    DRIVER_LOCATIONS.with(|locations| locations.borrow_mut().remove(&address));
+    NOTIFICATIONS.with(|notifications| {
+        notifications.borrow_mut().retain(|notification| notification.recipient != address)
+    });
+    let discard = |ride_id: &String| ride_ids.contains(ride_id) && !disputed.contains(ride_id);
+    CHAT_THREADS.with(|chat_threads| chat_threads.borrow_mut().retain(|id, _| !discard(id)));
+    BREADCRUMBS.with(|breadcrumbs| breadcrumbs.borrow_mut().retain(|id, _| !discard(id)));
+    RIDER_STORE.with(|rider_store| {
+        rider_store.borrow_mut().retain(|rider| rider.address != address)
+    });
+    DRIVER_STORE.with(|driver_store| {
+        driver_store.borrow_mut().retain(|driver| driver.address != address)
+    });
+    DRIVER_APPLICATIONS.with(|applications| applications.borrow_mut().remove(&address));
+    PROFILE_STORE.with(|profile_store| profile_store.borrow_mut().remove(&caller));
+    ID_STORE.with(|id_store| id_store.borrow_mut().retain(|_, owner| *owner != caller));
+    revoke_role(caller, Role::Rider);
+    revoke_role(caller, Role::Driver);
+    //sanctions, disputes and promo redemptions are kept so a deleted account cannot be used to
+    //escape a ban or an investigation or to reset promo limits, the audit log is an append-only
+    //record of who did what and domain events only carry relay ids
+    audit("delete_my_account", &address, "deleted account".to_string());
+    Ok(())
+}
+
+/// every store kept on the heap, written to stable memory so it survives upgrades; stores
+/// added after the first release are optional so older saved states still decode
+#[derive(Default, CandidType, Deserialize)]
+struct StableState {
+    profiles: ProfileStore,
+    ids: IdStore,
+    drivers: DriverStore,
+    riders: RiderStore,
+    rides: RidesStore,
+    ride_counter: u64,
+    fare_config: FareConfig,
+    geo_config: Option<GeoConfig>,
+    zones: Option<ZoneStore>,
+    surge_policy: Option<SurgePolicy>,
+    surge: Option<SurgeTable>,
+    promo_codes: Option<PromoStore>,
+    promo_redemptions: Option<Vec<PromoRedemption>>,
+    earnings: Option<Vec<EarningEntry>>,
+    disputes: Option<DisputeStore>,
+    notifications: Option<NotificationStore>,
+    payouts: Option<Vec<Payout>>,
+    payout_accounts: Option<PayoutAccountStore>,
+    payout_policy: Option<PayoutPolicy>,
+    last_payout_batch: Option<u64>,
+    cancellation_policy: CancellationPolicy,
+    scheduling_policy: SchedulingPolicy,
+    pools: PoolStore,
+    pool_policy: PoolPolicy,
+    start_codes: StartCodeStore,
+    used_blocks: UsedBlocks,
+    roles: RoleStore,
+    controllers: ControllerStore,
+    driver_applications: DriverApplicationStore,
+    sanctions: SanctionStore,
+    audit_log: Option<AuditLog>,
+    event_log: Option<EventLog>,
+    subscribers: Option<SubscriberStore>,
+    chat_threads: Option<ChatStore>,
+    driver_locations: Option<DriverLocationStore>,
+    breadcrumbs: Option<BreadcrumbStore>,
+    refund_counter: Option<u64>,
+}
+
+impl StableState {
+    fn capture() -> StableState {
+        StableState {
+            profiles: PROFILE_STORE.with(|store| store.borrow().clone()),
+            ids: ID_STORE.with(|store| store.borrow().clone()),
+            drivers: DRIVER_STORE.with(|store| store.borrow().clone()),
+            riders: RIDER_STORE.with(|store| store.borrow().clone()),
+            rides: RIDES_STORE.with(|store| store.borrow().clone()),
+            ride_counter: RIDE_COUNTER.with(|store| *store.borrow()),
+            fare_config: FARE_CONFIG.with(|store| store.borrow().clone()),
+            geo_config: Some(GEO_CONFIG.with(|store| store.borrow().clone())),
+            zones: Some(ZONE_STORE.with(|store| store.borrow().clone())),
+            surge_policy: Some(SURGE_POLICY.with(|store| store.borrow().clone())),
+            surge: Some(SURGE.with(|store| store.borrow().clone())),
+            promo_codes: Some(PROMO_CODES.with(|store| store.borrow().clone())),
+            promo_redemptions: Some(PROMO_REDEMPTIONS.with(|store| store.borrow().clone())),
+            earnings: Some(EARNINGS.with(|store| {
+                let mut entries: Vec<EarningEntry> =
+                    store.borrow().values().flatten().cloned().collect();
+                entries.sort_by_key(|entry| entry.seq);
+                entries
+            })),
+            disputes: Some(DISPUTES.with(|store| store.borrow().clone())),
+            notifications: Some(NOTIFICATIONS.with(|store| store.borrow().clone())),
+            payouts: Some(PAYOUTS.with(|store| store.borrow().clone())),
+            payout_accounts: Some(PAYOUT_ACCOUNTS.with(|store| store.borrow().clone())),
+            payout_policy: Some(PAYOUT_POLICY.with(|store| store.borrow().clone())),
+            last_payout_batch: Some(LAST_PAYOUT_BATCH.with(|store| *store.borrow())),
+            cancellation_policy: CANCELLATION_POLICY.with(|store| store.borrow().clone()),
+            scheduling_policy: SCHEDULING_POLICY.with(|store| store.borrow().clone()),
+            pools: POOL_STORE.with(|store| store.borrow().clone()),
+            pool_policy: POOL_POLICY.with(|store| store.borrow().clone()),
+            start_codes: START_CODES.with(|store| store.borrow().clone()),
+            used_blocks: USED_BLOCKS.with(|store| store.borrow().clone()),
+            roles: ROLE_STORE.with(|store| store.borrow().clone()),
+            controllers: CONTROLLERS.with(|store| store.borrow().clone()),
+            driver_applications: DRIVER_APPLICATIONS.with(|store| store.borrow().clone()),
+            sanctions: SANCTIONS.with(|store| store.borrow().clone()),
+            audit_log: None,
+            event_log: None,
+            subscribers: Some(SUBSCRIBERS.with(|store| store.borrow().clone())),
+            chat_threads: Some(CHAT_THREADS.with(|store| store.borrow().clone())),
+            driver_locations: Some(DRIVER_LOCATIONS.with(|store| store.borrow().clone())),
+            breadcrumbs: Some(BREADCRUMBS.with(|store| store.borrow().clone())),
+            refund_counter: Some(REFUND_COUNTER.with(|store| *store.borrow())),
+        }
     }
-    fn get_dropoff(&self) -> String {
-        self.dropoff.clone()
+
+    fn restore(self) {
+        let StableState {
+            profiles,
+            ids,
+            drivers,
+            riders,
+            rides,
+            ride_counter,
+            fare_config,
+            geo_config,
+            zones,
+            surge_policy,
+            surge,
+            promo_codes,
+            promo_redemptions,
+            earnings,
+            disputes,
+            notifications,
+            payouts,
+            payout_accounts,
+            payout_policy,
+            last_payout_batch,
+            cancellation_policy,
+            scheduling_policy,
+            pools,
+            pool_policy,
+            start_codes,
+            used_blocks,
+            roles,
+            controllers,
+            driver_applications,
+            sanctions,
+            audit_log,
+            event_log,
+            subscribers,
+            chat_threads,
+            driver_locations,
+            breadcrumbs,
+            refund_counter,
+        } = self;
+        PROFILE_STORE.with(|store| *store.borrow_mut() = profiles);
+        ID_STORE.with(|store| *store.borrow_mut() = ids);
+        DRIVER_STORE.with(|store| *store.borrow_mut() = drivers);
+        RIDER_STORE.with(|store| *store.borrow_mut() = riders);
+        RIDES_STORE.with(|store| *store.borrow_mut() = rides);
+        RIDE_COUNTER.with(|store| *store.borrow_mut() = ride_counter);
+        REFUND_COUNTER.with(|store| *store.borrow_mut() = refund_counter.unwrap_or_default());
+        FARE_CONFIG.with(|store| *store.borrow_mut() = fare_config);
+        GEO_CONFIG.with(|store| *store.borrow_mut() = geo_config.unwrap_or_default());
+        ZONE_STORE.with(|store| *store.borrow_mut() = zones.unwrap_or_default());
+        SURGE_POLICY.with(|store| *store.borrow_mut() = surge_policy.unwrap_or_default());
+        SURGE.with(|store| *store.borrow_mut() = surge.unwrap_or_default());
+        PROMO_CODES.with(|store| *store.borrow_mut() = promo_codes.unwrap_or_default());
+        PROMO_REDEMPTIONS.with(|store| {
+            *store.borrow_mut() = promo_redemptions.unwrap_or_default()
+        });
+        EARNINGS.with(|store| {
+            let mut by_driver = EarningStore::new();
+            for entry in earnings.unwrap_or_default() {
+                by_driver.entry(entry.driver.clone()).or_default().push(entry);
+            }
+            *store.borrow_mut() = by_driver;
+        });
+        DISPUTES.with(|store| *store.borrow_mut() = disputes.unwrap_or_default());
+        NOTIFICATIONS.with(|store| *store.borrow_mut() = notifications.unwrap_or_default());
+        PAYOUT_ACCOUNTS.with(|store| {
+            *store.borrow_mut() = payout_accounts.unwrap_or_default()
+        });
+        PAYOUT_POLICY.with(|store| *store.borrow_mut() = payout_policy.unwrap_or_default());
+        LAST_PAYOUT_BATCH.with(|store| {
+            *store.borrow_mut() = last_payout_batch.unwrap_or_default()
+        });
+        CANCELLATION_POLICY.with(|store| *store.borrow_mut() = cancellation_policy);
+        SCHEDULING_POLICY.with(|store| *store.borrow_mut() = scheduling_policy);
+        POOL_STORE.with(|store| *store.borrow_mut() = pools);
+        POOL_POLICY.with(|store| *store.borrow_mut() = pool_policy);
+        START_CODES.with(|store| *store.borrow_mut() = start_codes);
+        USED_BLOCKS.with(|store| *store.borrow_mut() = used_blocks);
+        ROLE_STORE.with(|store| *store.borrow_mut() = roles);
+        CONTROLLERS.with(|store| *store.borrow_mut() = controllers);
+        DRIVER_APPLICATIONS.with(|store| *store.borrow_mut() = driver_applications);
+        SANCTIONS.with(|store| *store.borrow_mut() = sanctions);
+        //releases before the stable log kept the audit log in the upgrade state
+        for event in audit_log.unwrap_or_default() {
+            AUDIT_LOG.append(&event);
+        }
+        //as was the event log, whose entries named the parties by principal
+        for event in event_log.unwrap_or_default() {
+            EVENT_LOG.append(&DomainEvent {
+                rider: relay_id(&event.ride_id, "rider"),
+                driver: relay_id(&event.ride_id, "driver"),
+                ..event
+            });
+        }
+        //chat messages named their sender by principal before relay ids
+        let mut chat_threads = chat_threads.unwrap_or_default();
+        for thread in chat_threads.values_mut() {
+            let ride = search_ride_by_id(thread.ride_id.clone());
+            for message in thread.messages.iter_mut() {
+                if !message.sender.starts_with("relay-") {
+                    let party = ride.as_ref().map_or("support", |ride| {
+                        chat_party(ride, &message.sender)
+                    });
+                    message.sender = relay_id(&thread.ride_id, party);
+                }
+            }
+        }
+        CHAT_THREADS.with(|store| *store.borrow_mut() = chat_threads);
+        DRIVER_LOCATIONS.with(|store| {
+            *store.borrow_mut() = driver_locations.unwrap_or_default()
+        });
+        BREADCRUMBS.with(|store| *store.borrow_mut() = breadcrumbs.unwrap_or_default());
+        //calls in flight during the upgrade never reply, resend their batches
+        SUBSCRIBERS.with(|store| {
+            *store.borrow_mut() = subscribers
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(canister, subscriber)| {
+                    (canister, Subscriber { in_flight: false, ..subscriber })
+                })
+                .collect()
+        });
+        PAYOUTS.with(|store| {
+            *store.borrow_mut() = payouts
+                .unwrap_or_default()
+                .into_iter()
+                .map(|payout| Payout { in_flight: false, ..payout })
+                .collect()
+        });
     }
-    fn get_pickup(&self) -> String {
-        self.pickup.clone()
+}
+
+/// the saved state, a new variant is added whenever a change cannot be decoded from the last
+#[derive(CandidType, Deserialize)]
+enum VersionedState {
+    V1(StableState),
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = candid::encode_one(VersionedState::V1(StableState::capture()))
+        .expect("failed to encode state for stable memory");
+    memory::save_blob(memory::UPGRADES, &state);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    //trapping rolls the upgrade back instead of carrying on with empty stores
+    let state = load_state().unwrap_or_else(|error| panic!("failed to restore state: {}", error));
+    restore_upgraded(state.unwrap_or_default());
+}
+
+/// the state saved by the previous release, none only when stable memory is empty
+fn load_state() -> Result<Option<StableState>, String> {
+    if let Some(bytes) = memory::legacy_blob() {
+        //releases before the memory layout wrote a bare candid tuple from offset 0, it is only
+        //laid out over once the tuple decodes
+        let mut state = candid::de::IDLDeserialize::new(&bytes).map_err(|e| e.to_string())?;
+        let state = state.get_value::<StableState>().map_err(|e| e.to_string())?;
+        memory::format();
+        return Ok(Some(state));
     }
-    fn get_rider(&self) -> Rider {
-        self.rider.clone()
+    if memory::is_empty() {
+        return Ok(None);
     }
-    fn get_driver(&self) -> Driver {
-        self.driver.clone()
+    if !memory::is_formatted() {
+        return Err("stable memory holds neither a saved state nor the layout".to_string());
     }
-    fn get_rideid(&self) -> String {
-        self.rideid.clone()
+    let bytes = memory::load_blob(memory::UPGRADES).ok_or("stable memory holds no saved state")?;
+    match candid::decode_one(&bytes).map_err(|e| e.to_string())? {
+        VersionedState::V1(state) => Ok(Some(state)),
     }
+}
 
-    fn get_id(&self) -> String {
-        self.rideid.clone()
+/// restore the saved state, canisters installed before controllers were tracked start with
+/// the upgrading principal as their controller
+fn restore_upgraded(state: StableState) {
+    state.restore();
+    if CONTROLLERS.with(|controllers| controllers.borrow().is_empty()) {
+        seed_controller("post_upgrade", ic::caller());
     }
+}
 
-    fn get_type(&self) -> String {
-        "Ride".to_string()
-    }
+#[heartbeat]
+fn heartbeat() {
+    let now = ic::time();
+    dispatch_scheduled_rides(now);
+    expire_stale_requests(now);
+    purge_start_codes(now);
+    suspend_expired_drivers(now);
+    push_events_to_subscribers();
+    archive_chats(now);
+    prune_breadcrumbs(now);
+    update_surge(now);
+    schedule_payouts(now);
+}
 
-    fn get_field(&self, field: String) -> String {
-        match field.as_str() {
-            "rideid" => self.rideid.clone(),
-            "driver" => self.driver.get_name(),
-            "rider" => self.rider.get_name(),
-            "pickup" => self.pickup.clone(),
-            "dropoff" => self.dropoff.clone(),
-            "status" => self.status.to_string(),
-            "timestamp" => self.timestamp.clone(),
-            "rating" => self.rating.to_string(),
-            "driverrating" => self.driverrating.to_string(),
-            "riderrating" => self.riderrating.to_string(),
-            "driverfeedback" => self.driverfeedback.clone(),
-            "riderfeedback" => self.riderfeedback.clone(),
-            "riderconfirmation" => self.riderconfirmation.clone(),
-            "driverconfirmation" => self.driverconfirmation.clone(),
-            "driveraddress" => self.driver.address.clone(),
-            "rideraddress" => self.rider.address.clone(),
-            _ => "".to_string(),
-        }
-    }
+export_service!();
+
+#[query]
+fn export_candid() -> String {
+    __export_service()
 }
 
-///register ride to RIDES_STORE
-#[update]
-#[candid_method(update)]
-fn register_ride(ride: Ride) {
-    RIDES_STORE.with(|rides_store| {
-        rides_store.borrow_mut().push(ride);
-    });
+#[cfg(any(target_arch = "wasm32", test))]
+fn main() {}
+
+#[cfg(not(any(target_arch = "wasm32", test)))]
+fn main() {
+    candid::export_service!();
+    std::print!("{}", __export_service());
 }
 
-///search ride by
-#[allow(dead_code)]
-#[candid_method(query)]
-fn search_ride_by_id(rideid: String) -> Option<Ride> {
-    let mut rides = get_rides();
-    for ride in rides.iter_mut() {
-        if ride.rideid == rideid {
-            return Some(ride.clone());
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_kit::{mock_principals, MockContext};
+    use ic_kit::candid::parser::value::{IDLArgs, IDLField, IDLValue, VariantValue};
+    use ic_kit::candid::types::{Field, Label, Type};
+    use ic_kit::candid::TypeEnv;
+    use ic_ledger_types::{Timestamp, Transaction};
+
+    /// test registerRider
+    #[test]
+    fn test_register_rider() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider).unwrap();
+        assert_eq!(get_riders().len(), 1);
+        //check if the rider is in the store
+        assert_eq!(get_riders()[0].name, "Kelsey");
+    }
+
+    ///test register driver
+    #[test]
+    fn test_register_driver() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(get_drivers().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_drivers()[0].name, "Kelsey");
+    }
+    /// test update_driver_rating
+    #[test]
+    fn test_update_driver_rating() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(get_drivers().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_drivers()[0].name, "Kelsey");
+        //drivers start unrated and are rated through rides
+        assert_eq!(get_drivers()[0].rating, 0.0);
+        assert!(update_driver_rating("no-such-ride".to_string(), 5.0).is_err());
+    }
+    ///test update_driver_status
+    #[test]
+    fn test_update_driver_status() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(get_drivers().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_drivers()[0].name, "Kelsey");
+        update_driver_status(TEST_ADDRESS.to_string(), CurrentStatus::Inactive).unwrap();
+        assert_eq!(get_drivers()[0].currentstatus, CurrentStatus::Inactive);
+
+        update_driver_status(TEST_ADDRESS.to_string(), CurrentStatus::Active).unwrap();
+        assert_eq!(get_drivers()[0].currentstatus, CurrentStatus::Active);
+        let audited = AUDIT_LOG.get(AUDIT_LOG.len() - 1).unwrap();
+        assert_eq!(audited.endpoint, "update_driver_status");
+        assert_eq!(audited.summary, "currentstatus: Inactive -> Active");
+        //other callers need support to change a driver's status
+        MockContext::new().with_caller(mock_principals::bob()).inject();
+        DRIVER_STORE.with(|store| store.borrow_mut().push(test_driver()));
+        let status = || update_driver_status(TEST_ADDRESS.to_string(), CurrentStatus::Inactive);
+        assert!(status().is_err());
+        grant_role(mock_principals::bob(), Role::Support);
+        status().unwrap();
+        assert!(update_driver_status("nobody".to_string(), CurrentStatus::Active).is_err());
+    }
+    ///test search for driver by address
+    #[test]
+    fn test_search_driver_by_address() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(get_drivers().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_drivers()[0].name, "Kelsey");
+        assert_eq!(
+            search_driver_by_address(
+                "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string()
+            )
+            .unwrap()
+            .name,
+            "Kelsey"
+        );
+    }
+    ///test search for rider by address
+    #[test]
+    fn test_search_rider_by_address() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider).unwrap();
+        assert_eq!(get_riders().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_riders()[0].name, "Kelsey");
+        assert_eq!(
+            search_rider_by_address(
+                "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string()
+            )
+            .unwrap()
+            .name,
+            "Kelsey"
+        );
+    }
+
+    /// test search for driver_by_contact
+    #[test]
+    fn test_search_driver_by_contact() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(get_drivers().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_drivers()[0].name, "Kelsey");
+        assert_eq!(
+            search_driver_by_contact("1234567890".to_string())
+                .unwrap()
+                .name,
+            "Kelsey"
+        );
     }
-    None
-}
+    ///test create ride
+    #[test]
+    fn test_create_ride() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver.clone()).unwrap();
+        //create rider
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider.clone()).unwrap();
 
-///update a driver for a ride by rideid
-#[update]
-#[candid_method(update)]
-fn update_driver_for_ride(rideid: String, driver: Driver) {
-    let mut rides = get_rides();
-    for ride in rides.iter_mut() {
-        if ride.rideid == rideid {
-            ride.update_driver(driver.clone());
-        }
+        //create ride
+        let ride = Ride {
+            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+            driver,
+            rider,
+            pickup: "new york".to_string(),
+            dropoff: "san francisco".to_string(),
+            status: RideStatus::Active,
+            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
+            rating: 0.0,
+            driverrating: 0.0,
+            riderrating: 0.0,
+            driverfeedback: "".to_string(),
+            riderfeedback: "".to_string(),
+            riderconfirmation: "".to_string(),
+            driverconfirmation: "".to_string(),
+            requested_at: 0,
+            scheduled_for: None,
+            dispatched_at: None,
+            accepted_at: None,
+            picked_up_at: None,
+            completed_at: None,
+            cancelled_at: None,
+            fare: 0,
+            cancellation: None,
+            payment: None,
+            tip: None,
+            adjustments: Some(vec![]),
+            waypoints: vec![],
+            pending_stop_change: None,
+            pool_id: None,
+            pricing: Some(Pricing::default()),
+        };
+        //register ride
+        register_ride(ride);
+        //get list of all rides
+        let rides = get_rides();
+        //assert
+        assert_eq!(rides.len(), 1);
     }
-}
-
-
-///update a rider for a ride by rideid
-#[update]
-#[candid_method(update)]
-fn update_rider_for_ride(rideid: String, rider: Rider) {
-    let mut rides = get_rides();
-    for ride in rides.iter_mut() {
-        if ride.rideid == rideid {
-            ride.update_rider(rider.clone());
-        }
+    ///test search riders by field and return the rider
+    #[test]
+    fn test_search_rider_by_field() {
+        //contact details are only searchable by support operators
+        MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        grant_role(mock_principals::alice(), Role::Support);
+        let rider = Rider::new(
+            "Kelsey".to_string(),
+            "1234567890".to_string(),
+            "test@email.com".to_string(),
+            "rider".to_string(),
+            "123 Main St".to_string(),
+        );
+        RIDER_STORE.with(|rider_store| {
+            rider_store.borrow_mut().push(rider.clone());
+        });
+        assert_eq!(get_riders().len(), 1);
+        //check the data was written to the store
+        assert_eq!(get_riders()[0].name, "Kelsey");
+        assert_eq!(
+            //get the first rider
+            search_rider_by_field("name".to_string(), "Kelsey".to_string())[0].as_ref()
+                .unwrap().name,
+            "Kelsey"
+        );
+        assert_eq!(
+            //get the first rider
+            search_rider_by_field("contact".to_string(), "1234567890".to_string())[0].as_ref()
+                .unwrap().name,
+            "Kelsey"
+        );
     }
-}
-
-
-export_service!();
-
-#[query]
-fn export_candid() -> String {
-    __export_service()
-}
-
-#[cfg(any(target_arch = "wasm32", test))]
-fn main() {}
-
-#[cfg(not(any(target_arch = "wasm32", test)))]
-fn main() {
-    candid::export_service!();
-    std::print!("{}", __export_service());
-}
-
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash)]
-pub struct TransferArgs {
-    amount: Tokens,
-    to_principal: Principal,
-    to_subaccount: Option<Subaccount>,
-}
-
-///get one
-pub type BlockHeight = u64;
 
-///get block from ledger with height
-#[allow(dead_code)]
-#[candid_method(query)]
-async fn get_block_from_ledger(
-    block_height: BlockHeight,
-    ledger_canister_id: Principal,
-) -> Option<Block> {
-    //!set arguments for get blocks
-    let args = GetBlocksArgs {
-        start: block_height,
-        length: 1,
-    };
-    //set ledger to mainnet
-    if let Ok(result) = query_blocks(ledger_canister_id, args.clone()).await {
-        //get block from result
-        if !result.blocks.is_empty() {
-            return result.blocks.first().cloned();
-        }
-        //get block from archived blocks
-        if let Some(b) = result
-            .archived_blocks
-            .into_iter()
-            .find(|b| (b.start <= block_height && (block_height - b.start) < b.length))
-        {
-            if let Ok(Ok(range)) = query_archived_blocks(&b.callback, args).await {
-                return range.blocks.get((block_height - b.start) as usize).cloned();
+    ///test search for driver by field and return the driver
+    #[test]
+    fn test_search_driver_by_field() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
+        assert_eq!(
+            //get the first driver
+            search_driver_by_field("name".to_string(), "Kelsey".to_string())[0].as_ref()
+                .unwrap().name,
+            "Kelsey"
+        );
+        
             }
-        }
-    }
-    None
-}
-
-///request a ride
-#[update]
-#[candid_method(query)]
-pub fn request_ride(rider: Rider, pickup: String, dropoff: String, timestamp: String) -> () {
-    //find an available driver
-    let mut drivers = get_drivers();
-    let mut driver = None;
-    for d in drivers.iter_mut() {
-        if d.currentstatus == CurrentStatus::Active {
-            driver = Some(d.clone());
-            break;
-        }
-    }
-    //assert
-    assert!(driver.is_some());
-    //create a ride with the driver and rider
-    let ride = Ride {
-        rideid: "".to_string(),
-        driver: driver.unwrap().clone(),
-        rider: rider.clone(),
-        pickup: pickup.clone(),
-        dropoff: dropoff.clone(),
-        status: RideStatus::Active,
-        timestamp: timestamp.clone(),
-        rating: 0.0,
-        driverrating: 0.0,
-        riderrating: 0.0,
-        driverfeedback: "".to_string(),
-        riderfeedback: "".to_string(),
-        riderconfirmation: "".to_string(),
-        driverconfirmation: "".to_string(),
-    };
-    //register ride
-    register_ride(ride.clone());
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    ///test search for driver by name and return the driver
+    #[test]
+    fn test_search_driver_by_name() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver).unwrap();
 
-    /// test registerRider
+        //search for driver
+        let driver_found = search_driver_by_name("Kelsey".to_string());
+        //assert
+        assert_eq!(driver_found.unwrap().name, "Kelsey");
+    }
+    ///test search ride by id
+    #[test]
+    fn test_search_ride_by_id() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver.clone()).unwrap();
+        //create rider
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider.clone()).unwrap();
+        //create ride
+        let ride = Ride {
+            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+            driver,
+            rider,
+            pickup: "new york".to_string(),
+            dropoff: "san francisco".to_string(),
+            status: RideStatus::Active,
+            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
+            rating: 0.0,
+            driverrating: 0.0,
+            riderrating: 0.0,
+            driverfeedback: "".to_string(),
+            riderfeedback: "".to_string(),
+            riderconfirmation: "".to_string(),
+            driverconfirmation: "".to_string(),
+            requested_at: 0,
+            scheduled_for: None,
+            dispatched_at: None,
+            accepted_at: None,
+            picked_up_at: None,
+            completed_at: None,
+            cancelled_at: None,
+            fare: 0,
+            cancellation: None,
+            payment: None,
+            tip: None,
+            adjustments: Some(vec![]),
+            waypoints: vec![],
+            pending_stop_change: None,
+            pool_id: None,
+            pricing: Some(Pricing::default()),
+        };
+        //register ride
+        register_ride(ride.clone());
+        //search ride by id
+        let search_ride = search_ride_by_id(ride.clone().rideid);
+        //assert
+        assert_eq!(search_ride.unwrap().rideid, ride.clone().rideid);
+    }
+    ///test request ride
     #[test]
-    fn test_register_rider() {
+    fn test_request_ride() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
+        let driver = Driver {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "driver".to_string(),
+            vehicleplatenumber: "ABC123".to_string(),
+            vehicleseatnumber: "1".to_string(),
+            vehiclemake: "Toyota".to_string(),
+            vehiclemodel: "Corolla".to_string(),
+            vehiclecolor: "Black".to_string(),
+            vehicletype: "SUV".to_string(),
+            vehicleyear: "2020".to_string(),
+            rating: 0.0,
+            currentstatus: CurrentStatus::Active,
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_driver(driver.clone()).unwrap();
+        approve_caller_as_driver();
+        //create rider
         let rider = Rider {
             name: "Kelsey".to_string(),
             contact: "1234567890".to_string(),
@@ -1054,15 +6796,24 @@ mod test {
             role: "rider".to_string(),
             address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
         };
-        register_rider(rider);
-        assert_eq!(get_riders().len(), 1);
-        //check if the rider is in the store
-        assert_eq!(get_riders()[0].name, "Kelsey");
+        register_rider(rider.clone()).unwrap();
+        //request a ride
+        request_ride(test_request()).unwrap();
+        //get rides
+        let rides = get_rides();
+        //get first ride
+        let ride = rides.first().unwrap();
+        //assert ride exists
+        assert_eq!(ride.rider.name, rider.name);
+        assert_eq!(ride.driver.name, driver.name);
     }
-
-    ///test register driver
+    //test search ride by field
     #[test]
-    fn test_register_driver() {
+    fn test_search_ride_by_field() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
         let driver = Driver {
             name: "Kelsey".to_string(),
             contact: "1234567890".to_string(),
@@ -1079,18 +6830,41 @@ mod test {
             currentstatus: CurrentStatus::Active,
             address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
         };
-        register_driver(driver);
-        assert_eq!(get_drivers().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_drivers()[0].name, "Kelsey");
+
+        register_driver(driver.clone()).unwrap();
+        approve_caller_as_driver();
+        //create rider
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider.clone()).unwrap();
+        //request a ride
+        request_ride(test_request()).unwrap();
+        //get rides
+        let rides = get_rides();
+        //get first ride
+        let ride = rides.first().unwrap();
+        //assert ride exists
+        assert_eq!(ride.rider.name, rider.name);
+        assert_eq!(ride.driver.name, driver.name);
+        //search ride by pickup
     }
-    /// test update_driver_rating
+
+    ///test update_driver_for_ride
     #[test]
-    fn test_update_driver_rating() {
+    fn test_update_driver_for_ride() {
+        MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //create driver
         let driver = Driver {
             name: "Kelsey".to_string(),
             contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
+            email: "test@gmail.com".to_string(),
             role: "driver".to_string(),
             vehicleplatenumber: "ABC123".to_string(),
             vehicleseatnumber: "1".to_string(),
@@ -1103,23 +6877,89 @@ mod test {
             currentstatus: CurrentStatus::Active,
             address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
         };
-        register_driver(driver);
-        assert_eq!(get_drivers().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_drivers()[0].name, "Kelsey");
-        update_driver_rating("Kelsey".to_string(), 5.0);
-        assert_eq!(get_drivers()[0].rating, 5.0);
+        register_driver(driver.clone()).unwrap();
+        //create rider
+        let rider = Rider {
+            name: "Kelsey".to_string(),
+            contact: "1234567890".to_string(),
+            email: "test@email.com".to_string(),
+            role: "rider".to_string(),
+            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        };
+        register_rider(rider.clone()).unwrap();
+        //create ride for register_ride
+        let ride = Ride {
+            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+            rider: rider.clone(),
+            driver: driver.clone(),
+            pickup: "new york".to_string(),
+            dropoff: "san francisco".to_string(),
+            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
+            status: RideStatus::Active,
+            driverrating: 0.0,
+            riderrating: 0.0,
+            driverconfirmation: "".to_string(),
+            riderconfirmation: "".to_string(),
+            driverfeedback: "".to_string(),
+            riderfeedback: "".to_string(),
+            rating: 0.0,
+            requested_at: 0,
+            scheduled_for: None,
+            dispatched_at: None,
+            accepted_at: None,
+            picked_up_at: None,
+            completed_at: None,
+            cancelled_at: None,
+            fare: 0,
+            cancellation: None,
+            payment: None,
+            tip: None,
+            adjustments: Some(vec![]),
+            waypoints: vec![],
+            pending_stop_change: None,
+            pool_id: None,
+            pricing: Some(Pricing::default()),
+        };
+
+        register_ride(ride.clone());
+        let mut new_driver = driver.clone();
+        new_driver.update_vehiclemake("Honda".to_string());
+        update_driver(driver.address.clone(), new_driver.clone()).unwrap();
+        let mut new_ride = ride.clone();
+        new_ride.driver = new_driver.clone();
+        //records are only replaced in place under their own id
+        assert!(update_driver("nobody".to_string(), new_driver.clone()).is_err());
+        let forged = Driver { address: "nobody".to_string(), ..new_driver.clone() };
+        assert!(update_driver(driver.address, forged).is_err());
+        assert!(update_ride("no-such-ride".to_string(), new_ride.clone()).is_err());
+        let forged = Ride { rideid: "no-such-ride".to_string(), ..new_ride.clone() };
+        assert!(update_ride(ride.rideid.clone(), forged).is_err());
+
+        //update_ride with new ride
+        update_ride(ride.rideid, new_ride).unwrap();
+        assert_eq!(get_rides().len(), 1);
+
+        //get rides
+        let rides = get_rides();
+        //get first ride
+        let check_ride = rides.first().unwrap();
+        //assert ride exists
+        assert_eq!(check_ride.rider.name, rider.name);
+        //check for honda
+        assert_eq!(check_ride.driver.vehiclemake, "Honda".to_string());
     }
-    ///test update_driver_status
-    #[test]
-    fn test_update_driver_status() {
-        let driver = Driver {
+
+    const TEST_ADDRESS: &str = "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae";
+
+    /// active driver used by the ride lifecycle tests
+    fn test_driver() -> Driver {
+        Driver {
             name: "Kelsey".to_string(),
             contact: "1234567890".to_string(),
             email: "test@email.com".to_string(),
             role: "driver".to_string(),
             vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
+            vehicleseatnumber: "4".to_string(),
             vehiclemake: "Toyota".to_string(),
             vehiclemodel: "Corolla".to_string(),
             vehiclecolor: "Black".to_string(),
@@ -1127,457 +6967,1764 @@ mod test {
             vehicleyear: "2020".to_string(),
             rating: 0.0,
             currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+            address: TEST_ADDRESS.to_string(),
+        }
+    }
+
+    /// rider used by the ride lifecycle tests
+    fn test_rider() -> Rider {
+        Rider::new(
+            "Dixie".to_string(),
+            "0987654321".to_string(),
+            "rider@email.com".to_string(),
+            "rider".to_string(),
+            mock_principals::bob().to_text(),
+        )
+    }
+
+    ///test a principal's profile, rider and driver accounts are linked
+    #[test]
+    fn test_account_linking() {
+        let ctx = MockContext::new().inject();
+        let profile = |name: &str| Profile {
+            name: name.to_string(),
+            description: "".to_string(),
+            keywords: vec![],
         };
-        register_driver(driver);
-        assert_eq!(get_drivers().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_drivers()[0].name, "Kelsey");
-        update_driver_status("Kelsey".to_string(), CurrentStatus::Inactive);
-        assert_eq!(get_drivers()[0].currentstatus, CurrentStatus::Inactive);
+        assert!(register_rider(test_rider()).is_err());
+        assert!(update(profile("kelsey")).is_err());
+
+        //registration is bound to the caller, not the address sent in
+        ctx.update_caller(mock_principals::alice());
+        let mut rider = test_rider();
+        rider.address = mock_principals::john().to_text();
+        register_rider(rider).unwrap();
+        assert!(register_rider(test_rider()).is_err());
+        register_driver(test_driver()).unwrap();
+        update(profile("Kelsey")).unwrap();
+        let account = get_self();
+        assert_eq!(account.profile.unwrap().name, "Kelsey");
+        assert_eq!(account.rider.unwrap().address, mock_principals::alice().to_text());
+        assert_eq!(account.driver.unwrap().address, mock_principals::alice().to_text());
+
+        //another principal cannot take the handle, in any case
+        ctx.update_caller(mock_principals::bob());
+        assert!(update(profile("KELSEY")).is_err());
+        assert!(update(profile("bad handle")).is_err());
+        assert!(get_self().rider.is_none());
+        assert_eq!(get("kelsey".to_string()).name, "Kelsey");
+
+        //renaming frees the old handle
+        ctx.update_caller(mock_principals::alice());
+        update(profile("kels")).unwrap();
+        ctx.update_caller(mock_principals::bob());
+        update(profile("kelsey")).unwrap();
+        assert_eq!(get("kels".to_string()).name, "kels");
+    }
+
+    ///test controllers manage admin roles and guards check them
+    #[test]
+    fn test_roles() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        init();
+        assert!(is_controller().is_ok());
+        assert!(is_support().is_ok() && is_finance().is_ok());
+        assert!(add_admin(mock_principals::bob(), Role::Rider).is_err());
+        add_admin(mock_principals::bob(), Role::Support).unwrap();
+
+        ctx.update_caller(mock_principals::bob());
+        assert!(is_controller().is_err());
+        assert!(is_support().is_ok());
+        assert!(is_finance().is_err());
+        register_rider(test_rider()).unwrap();
+        assert_eq!(get_my_roles(), vec![Role::Support, Role::Rider]);
+        assert_eq!(get_riders()[0].role, "rider");
+
+        ctx.update_caller(mock_principals::alice());
+        revoke_admin(mock_principals::bob(), Role::Support).unwrap();
+        ctx.update_caller(mock_principals::bob());
+        assert!(is_support().is_err());
+        assert_eq!(get_my_roles(), vec![Role::Rider]);
+    }
+
+    ///test a driver is only dispatched once their documents are approved
+    #[test]
+    fn test_driver_onboarding() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_driver(test_driver()).unwrap();
+        let status = || get_my_driver_application().unwrap().status;
+        assert_eq!(status(), ApplicationStatus::Submitted);
+        assert!(as_test_rider(ctx, || request_ride(test_request())).is_err());
+
+        let mut bad_hash = test_document(DocumentKind::Licence);
+        bad_hash.content_hash = "not a hash".to_string();
+        assert!(submit_driver_document(bad_hash).is_err());
+        submit_driver_document(test_document(DocumentKind::Licence)).unwrap();
+        assert_eq!(status(), ApplicationStatus::DocumentsPending);
+        assert!(review_driver_application(TEST_ADDRESS.to_string(), true, "".to_string())
+            .is_err());
+        submit_driver_document(test_document(DocumentKind::Insurance)).unwrap();
+        let mut registration = test_document(DocumentKind::Registration);
+        registration.expires_at = ic::time() + NANOS_PER_MINUTE;
+        submit_driver_document(registration).unwrap();
+        assert_eq!(status(), ApplicationStatus::UnderReview);
+
+        review_driver_application(TEST_ADDRESS.to_string(), true, "ok".to_string()).unwrap();
+        assert_eq!(status(), ApplicationStatus::Approved);
+        as_test_rider(ctx, || request_ride(test_request())).unwrap();
+
+        //the heartbeat suspends the driver once the registration lapses
+        suspend_expired_drivers(ic::time() + 2 * NANOS_PER_MINUTE);
+        assert_eq!(status(), ApplicationStatus::Suspended);
+        assert!(as_test_rider(ctx, || request_ride(test_request())).is_err());
+
+        //renewing the document sends the driver back for review
+        submit_driver_document(test_document(DocumentKind::Registration)).unwrap();
+        assert_eq!(status(), ApplicationStatus::UnderReview);
+        review_driver_application(TEST_ADDRESS.to_string(), false, "blurry".to_string())
+            .unwrap();
+        assert_eq!(status(), ApplicationStatus::Rejected);
+        assert_eq!(get_driver_applications(Some(ApplicationStatus::Rejected)).len(), 1);
+    }
+
+    ///test suspended and banned accounts are refused until the sanction ends
+    #[test]
+    fn test_sanctions() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let now = ic::time();
+        let bob = mock_principals::bob();
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+
+        assert!(suspend_account(bob, SanctionScope::Rider, " ".to_string(), None).is_err());
+        assert!(suspend_account(bob, SanctionScope::Rider, "spam".to_string(), Some(now)).is_err());
+        let expires_at = now + NANOS_PER_MINUTE;
+        let suspension =
+            suspend_account(bob, SanctionScope::Rider, "spam".to_string(), Some(expires_at))
+                .unwrap();
+        assert!(as_test_rider(ctx, || request_ride(test_request())).is_err());
+        assert_eq!(active_sanctions(bob, Role::Rider, expires_at).len(), 0);
+        assert!(ensure_not_sanctioned(bob, Role::Driver).is_ok());
+
+        //the rider appeals and support lifts the suspension
+        as_caller(ctx, bob, || appeal_sanction(suspension, "sorry".to_string())).unwrap();
+        assert!(as_caller(ctx, driver, || appeal_sanction(suspension, "x".to_string())).is_err());
+        lift_sanction(suspension, "first offence".to_string()).unwrap();
+        as_test_rider(ctx, || cancel_ride(ride_id.clone(), "rebook".to_string())).unwrap();
+        let ride_id = as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        let sanction = as_caller(ctx, bob, get_my_sanctions).remove(0);
+        assert_eq!(sanction.appeal_notes.len(), 2);
+        assert!(!sanction.is_active(now));
+
+        //a banned driver is no longer dispatched and cannot register again
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        ban_account(driver, SanctionScope::Both, "fraud".to_string()).unwrap();
+        assert!(as_test_rider(ctx, || request_ride(test_request())).is_err());
+        assert!(as_caller(ctx, driver, || register_rider(test_rider())).is_err());
+        assert_eq!(get_sanctions(driver)[0].kind, SanctionKind::Ban);
+
+        //nor act on the rides they hold or edit their profile
+        assert!(as_caller(ctx, driver, || arrive_at_stop(ride_id.clone(), 0)).is_err());
+        assert!(as_caller(ctx, driver, || cancel_ride(ride_id.clone(), "x".to_string())).is_err());
+        let profile = Profile {
+            name: "kelsey".to_string(),
+            description: String::new(),
+            keywords: vec![],
+        };
+        assert!(as_caller(ctx, driver, || update(profile)).is_err());
+        let on_duty = |status| update_driver_status(TEST_ADDRESS.to_string(), status);
+        assert!(as_caller(ctx, driver, || on_duty(CurrentStatus::Active)).is_err());
+        as_caller(ctx, driver, || on_duty(CurrentStatus::Inactive)).unwrap();
+        as_test_rider(ctx, || cancel_ride(ride_id, "banned driver".to_string())).unwrap();
+    }
+
+    ///test contact details are only shown to the owner, the counterparty and support
+    #[test]
+    fn test_redacted_reads() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let mut rider = test_rider();
+        rider.name = "Dixie Flatline".to_string();
+        RIDER_STORE.with(|rider_store| rider_store.borrow_mut()[0] = rider);
+
+        //a stranger sees first names and no contact details
+        let stranger = get_riders().remove(0);
+        assert_eq!(stranger.name, "Dixie");
+        assert!(stranger.contact.is_empty() && stranger.address.is_empty());
+        let driver = get_drivers().remove(0);
+        assert!(driver.vehicleplatenumber.is_empty() && driver.email.is_empty());
+        assert!(search_rider_by_field("contact".to_string(), "0987654321".to_string())
+            .is_empty());
+        assert!(get_rides()[0].rider.address.is_empty());
+
+        //the rider sees themselves in full
+        let own = as_test_rider(ctx, || search_rider_by_address(mock_principals::bob().to_text()));
+        assert_eq!(own.unwrap().contact, "0987654321");
+
+        //the driver only sees the rider through the relay once the ride is accepted
+        let driver_principal = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver_principal, || accept_ride(ride_id.clone())).unwrap();
+        let ride = as_caller(ctx, driver_principal, || get_rides().remove(0));
+        assert_eq!(ride.rider.email, "");
+        assert_eq!(ride.rider.contact, relay_id(&ride_id, "rider"));
+        assert_eq!(ride.driver.contact, "1234567890");
+        let ride = as_test_rider(ctx, || get_rides().remove(0));
+        assert_eq!(ride.driver.address, relay_id(&ride_id, "driver"));
+        assert_eq!(ride.driver.vehicleplatenumber, "ABC123");
+
+        //support sees everything
+        grant_role(mock_principals::alice(), Role::Support);
+        assert_eq!(get_riders()[0].email, "rider@email.com");
+    }
+
+    ///test a rider can export their data and delete their account
+    #[test]
+    fn test_export_and_delete_account() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let bob = mock_principals::bob();
+        as_caller(ctx, bob, || {
+            update(Profile {
+                name: "dixie".to_string(),
+                description: "".to_string(),
+                keywords: vec![],
+            })
+        })
+        .unwrap();
+
+        let data = as_caller(ctx, bob, export_my_data);
+        assert_eq!(data.account.rider.unwrap().email, "rider@email.com");
+        assert_eq!(data.account.profile.unwrap().name, "dixie");
+        assert_eq!(data.roles, vec![Role::Rider]);
+        assert_eq!(data.rides.len(), 1);
+        assert!(data.payments.is_empty());
+
+        //rides in progress must be finished first
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        as_caller(ctx, driver, || send_chat_message(ride_id.clone(), "outside".to_string()))
+            .unwrap();
+        assert!(as_caller(ctx, bob, delete_my_account).is_err());
+        as_caller(ctx, bob, || cancel_ride(ride_id.clone(), "testing".to_string())).unwrap();
+        as_caller(ctx, bob, delete_my_account).unwrap();
+
+        let data = as_caller(ctx, bob, export_my_data);
+        assert!(data.account.rider.is_none() && data.account.profile.is_none());
+        assert!(data.rides.is_empty() && data.roles.is_empty());
+        assert_eq!(get("dixie".to_string()).name, "");
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.rider.address, DELETED_ADDRESS);
+        assert!(ride.rider.contact.is_empty());
+        assert_eq!(ride.cancellation.unwrap().canceller, DELETED_ADDRESS);
+        assert_eq!(ride.driver.address, TEST_ADDRESS);
+        //the rider's places and the whole chat go with the account
+        assert!(ride.pickup.is_empty() && ride.dropoff.is_empty());
+        assert!(ride.waypoints.iter().all(|waypoint| waypoint.location == Location::scrubbed()));
+        assert!(CHAT_THREADS.with(|chat_threads| chat_threads.borrow().is_empty()));
+    }
+
+    ///test state changes are audited with a diff and can be paged through
+    #[test]
+    fn test_audit_log() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        assert!(as_caller(ctx, driver, || accept_ride(ride_id.clone())).is_err());
+
+        let by_ride = AuditFilter {
+            entity_id: Some(ride_id.clone()),
+            ..AuditFilter::default()
+        };
+        let events = get_audit_events(by_ride.clone(), 0, 10).events;
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].endpoint, "request_ride");
+        assert_eq!(events[1].endpoint, "accept_ride");
+        assert_eq!(events[1].caller, driver);
+        assert!(events[1].summary.contains("status"));
+        assert!(events[1].summary.contains("accepted_at"));
+        assert!(!events[1].summary.contains("rideid"));
+        assert!(!events[1].summary.contains("->"));
+        assert!(!events[1].summary.contains(TEST_ADDRESS));
+
+        //page one event at a time
+        let first = get_audit_events(by_ride.clone(), 0, 1);
+        assert_eq!(first.events[0].endpoint, "request_ride");
+        let second = get_audit_events(by_ride.clone(), first.next.unwrap(), 1);
+        assert_eq!(second.events[0].endpoint, "accept_ride");
+        assert_eq!(second.next, None);
+
+        let by_driver = AuditFilter {
+            principal: Some(driver),
+            ..AuditFilter::default()
+        };
+        let endpoints: Vec<String> = get_audit_events(by_driver, 0, 10)
+            .events
+            .into_iter()
+            .map(|event| event.endpoint)
+            .collect();
+        assert_eq!(
+            endpoints,
+            vec!["register_driver", "submit_driver_document", "submit_driver_document",
+                "submit_driver_document", "review_driver_application", "accept_ride"]
+        );
+        let later = AuditFilter {
+            from: Some(ic::time() + 1),
+            ..AuditFilter::default()
+        };
+        assert!(get_audit_events(later, 0, 10).events.is_empty());
+
+        //the log lives in stable memory, an upgrade neither drops nor duplicates it
+        pre_upgrade();
+        post_upgrade();
+        assert_eq!(get_audit_events(by_ride, 0, 10).events.len(), 2);
+    }
+
+    ///test ride changes are published as numbered domain events to the ride's parties
+    #[test]
+    fn test_domain_events() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        ctx.update_caller(driver);
+        accept_ride(ride_id.clone()).unwrap();
+        start_test_ride(ctx, &ride_id);
+        let fare = complete_ride(ride_id.clone()).unwrap();
+        ctx.update_caller(mock_principals::alice());
+
+        let page = as_test_rider(ctx, || get_events_since(0, EventFilter::default()));
+        assert_eq!(page.next, 5);
+        let events = page.events;
+        let kinds: Vec<RideEvent> = events.iter().map(|event| event.event).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                RideEvent::RideRequested,
+                RideEvent::OfferSent,
+                RideEvent::RideAccepted,
+                RideEvent::PickedUp,
+                RideEvent::Completed
+            ]
+        );
+        assert!(events.iter().zip(1..).all(|(event, seq)| event.seq == seq));
+        assert_eq!(events[4].amount, fare);
+        assert_eq!(events[4].status, RideStatus::Completed);
+        assert_eq!(events[4].rider, relay_id(&ride_id, "rider"));
+        assert_eq!(events[4].driver, relay_id(&ride_id, "driver"));
+
+        //clients resume from the last sequence number they saw
+        let filter = EventFilter {
+            ride_id: Some(ride_id.clone()),
+            events: vec![RideEvent::Completed, RideEvent::PickedUp],
+        };
+        let since = as_caller(ctx, driver, || get_events_since(3, filter)).events;
+        assert_eq!(since.len(), 2);
+        assert_eq!(since[0].event, RideEvent::PickedUp);
+
+        //strangers see nothing but still move on, support sees everything
+        let page = get_events_since(0, EventFilter::default());
+        assert!(page.events.is_empty());
+        assert_eq!(page.next, 5);
+        grant_role(mock_principals::alice(), Role::Support);
+        assert_eq!(get_events_since(0, EventFilter::default()).events.len(), 5);
+        assert_eq!(get_events_since(9, EventFilter::default()).next, 5);
+
+        //new subscribers only receive events from when they subscribed
+        register_subscriber(mock_principals::john());
+        let subscriber = SUBSCRIBERS
+            .with(|subscribers| subscribers.borrow()[&mock_principals::john()].clone());
+        assert_eq!(subscriber.delivered, 5);
+        assert!(pending_events(&subscriber).is_empty());
+    }
+
+    ///test the rider and driver can chat while matched and the thread is archived after
+    #[test]
+    fn test_ride_chat() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        let chat = |text: &str| send_chat_message(ride_id.clone(), text.to_string());
+
+        //chat opens once the ride is accepted
+        assert!(as_test_rider(ctx, || chat("hello")).is_err());
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        as_test_rider(ctx, || chat("hello")).unwrap();
+        let on_my_way = || send_quick_reply(ride_id.clone(), QuickReply::OnMyWay);
+        let reply = as_caller(ctx, driver, on_my_way).unwrap();
+        assert_eq!(reply, 1);
+        assert!(as_test_rider(ctx, || chat(&"x".repeat(MAX_CHAT_MESSAGE + 1))).is_err());
+        assert!(chat("not on this ride").is_err());
+        assert!(get_chat(ride_id.clone()).is_err());
+
+        //read receipts only mark the other party's messages
+        as_test_rider(ctx, || mark_chat_read(ride_id.clone(), 1)).unwrap();
+        let thread = as_caller(ctx, driver, || get_chat(ride_id.clone())).unwrap();
+        assert_eq!(thread.messages[0].read_at, None);
+        assert!(thread.messages[1].read_at.is_some());
+        assert_eq!(thread.messages[0].sender, relay_id(&ride_id, "rider"));
+        assert_eq!(thread.messages[1].sender, relay_id(&ride_id, "driver"));
+        assert_eq!(thread.messages[1].text, "On my way");
+        assert_eq!(thread.messages[1].quick_reply, Some(QuickReply::OnMyWay));
+
+        //support can read, and the thread is archived once the ride ends
+        grant_role(mock_principals::alice(), Role::Support);
+        assert_eq!(get_chat(ride_id.clone()).unwrap().messages.len(), 2);
+        assert!(mark_chat_read(ride_id.clone(), 1).is_err());
+        as_test_rider(ctx, || cancel_ride(ride_id.clone(), "testing".to_string())).unwrap();
+        assert!(as_test_rider(ctx, || chat("still there?")).is_err());
+        archive_chats(ic::time());
+        assert!(get_chat(ride_id.clone()).unwrap().archived_at.is_some());
+        assert!(as_caller(ctx, driver, || mark_chat_read(ride_id.clone(), 1)).is_err());
+        assert_eq!(as_test_rider(ctx, export_my_data).chats.len(), 1);
+    }
+
+    ///test the rider starts a ride with the driver's start code
+    #[test]
+    fn test_start_code_handshake() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let now = ic::time();
+
+        //a ride must be accepted before it starts
+        ctx.update_caller(mock_principals::bob());
+        assert!(confirm_pickup(ride_id.clone(), "000000".to_string()).is_err());
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        accept_ride(ride_id.clone()).unwrap();
+        let start_code = issue_start_code(&ride_id, &[1, 2, 3, 4, 5, 6, 7, 8], now);
+        assert_eq!(start_code.code.len(), 6);
+        assert!(start_code.is_valid(now));
+        assert!(!start_code.is_valid(now + START_CODE_TTL + 1));
+
+        //only the rider can confirm and a wrong code counts as an attempt
+        assert!(confirm_pickup(ride_id.clone(), start_code.code.clone()).is_err());
+        ctx.update_caller(mock_principals::bob());
+        let wrong = format!("{:06}", (start_code.code.parse::<u64>().unwrap() + 1) % 1_000_000);
+        assert!(confirm_pickup(ride_id.clone(), wrong).is_err());
+        assert_eq!(START_CODES.with(|codes| codes.borrow()[&ride_id].attempts), 1);
+
+        confirm_pickup(ride_id.clone(), start_code.code.clone()).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.status, RideStatus::Active);
+        assert!(ride.picked_up_at.is_some());
+        assert_eq!(ride.riderconfirmation, "confirmed");
+
+        //a used code is gone once the heartbeat purges it
+        assert!(!START_CODES.with(|codes| codes.borrow()[&ride_id].is_valid(now)));
+        purge_start_codes(now);
+        assert!(START_CODES.with(|codes| codes.borrow().get(&ride_id).is_none()));
+    }
+
+    ///test the canister stamps every step of the ride lifecycle
+    #[test]
+    fn test_ride_lifecycle_timestamps() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        let ride_id = as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.status, RideStatus::Requested);
+        assert!(ride.requested_at > 0);
+        assert_eq!(ride.timestamp, ride.requested_at.to_string());
+
+        accept_ride(ride_id.clone()).unwrap();
+        start_test_ride(ctx, &ride_id);
+        let fare = complete_ride(ride_id.clone()).unwrap();
+
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.status, RideStatus::Completed);
+        assert!(ride.accepted_at.unwrap() >= ride.requested_at);
+        assert!(ride.picked_up_at.unwrap() >= ride.accepted_at.unwrap());
+        assert!(ride.completed_at.unwrap() >= ride.picked_up_at.unwrap());
+        assert_eq!(ride.cancelled_at, None);
+        assert_eq!(ride.fare, fare);
+        let expected = get_fare_config()
+            .fare_for_trip(ride.trip_duration().unwrap(), ride.route_distance_km());
+        assert_eq!(fare, expected);
+        assert!(ride.waypoints.iter().last().unwrap().arrived_at.is_some());
+    }
+
+    ///test only the assigned driver can move a ride forward
+    #[test]
+    fn test_accept_ride_wrong_driver() {
+        let ctx = MockContext::new().inject();
+        register_test_driver(ctx);
+        let ride_id = as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        assert!(accept_ride(ride_id.clone()).is_err());
+        assert_eq!(search_ride_by_id(ride_id).unwrap().status, RideStatus::Requested);
+    }
+
+    ///test unaccepted requests time out
+    #[test]
+    fn test_expire_stale_requests() {
+        let ctx = MockContext::new().inject();
+        register_test_driver(ctx);
+        let ride_id = as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        let requested_at = search_ride_by_id(ride_id.clone()).unwrap().requested_at;
+
+        expire_stale_requests(requested_at + RIDE_REQUEST_TIMEOUT);
+        assert_eq!(search_ride_by_id(ride_id.clone()).unwrap().status, RideStatus::Requested);
+
+        expire_stale_requests(requested_at + RIDE_REQUEST_TIMEOUT + 1);
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.status, RideStatus::Cancelled);
+        assert_eq!(ride.cancelled_at, Some(requested_at + RIDE_REQUEST_TIMEOUT + 1));
+        assert_eq!(get_rides_between(requested_at, requested_at + 1).len(), 1);
+        assert!(get_rides_between(0, requested_at).is_empty());
+    }
+
+    ///test the fare is billed per started minute
+    #[test]
+    fn test_fare_for_duration() {
+        let config = FareConfig {
+            base_fare: 100,
+            per_minute: 10,
+            per_km: 1_000,
+            platform_fee_percent: Some(0),
+        };
+        assert_eq!(config.fare_for_duration(0), 100);
+        assert_eq!(config.fare_for_duration(1), 110);
+        assert_eq!(config.fare_for_duration(NANOS_PER_MINUTE), 110);
+        assert_eq!(config.fare_for_duration(NANOS_PER_MINUTE + 1), 120);
+        assert_eq!(config.fare_for_trip(NANOS_PER_MINUTE, 2.5), 2_610);
+    }
+
+    /// ride request from new york to san francisco
+    fn test_request() -> RideRequest {
+        RideRequest {
+            pickup: Location {
+                address_text: "new york".to_string(),
+                lat: 40.7128,
+                lng: -74.0060,
+            },
+            dropoff: Location {
+                address_text: "san francisco".to_string(),
+                lat: 37.7749,
+                lng: -122.4194,
+            },
+            stops: vec![],
+            promo_code: None,
+            quoted_surge: None,
+        }
+    }
+
+    ///driver issues a start code and the rider confirms it to start the ride
+    fn start_test_ride(ctx: &mut MockContext, ride_id: &str) {
+        let start_code = issue_start_code(ride_id, &[7; 32], ic::time());
+        let driver = ic::caller();
+        ctx.update_caller(mock_principals::bob());
+        confirm_pickup(ride_id.to_string(), start_code.code).unwrap();
+        ctx.update_caller(driver);
+    }
+
+    /// run `f` as `caller`, then switch back to the previous caller
+    fn as_caller<T>(ctx: &mut MockContext, caller: Principal, f: impl FnOnce() -> T) -> T {
+        let previous = ic::caller();
+        ctx.update_caller(caller);
+        let result = f();
+        ctx.update_caller(previous);
+        result
+    }
+
+    /// run `f` as the test rider, registering them on first use
+    fn as_test_rider<T>(ctx: &mut MockContext, f: impl FnOnce() -> T) -> T {
+        as_caller(ctx, mock_principals::bob(), || {
+            if search_rider_by_address(mock_principals::bob().to_text()).is_none() {
+                register_rider(test_rider()).unwrap();
+            }
+            f()
+        })
+    }
+
+    /// document of `kind` valid for a year
+    fn test_document(kind: DocumentKind) -> DriverDocument {
+        DriverDocument {
+            kind,
+            content_hash: "ab".repeat(32),
+            expires_at: ic::time() + 365 * 24 * 60 * NANOS_PER_MINUTE,
+        }
+    }
+
+    /// upload the caller's driver documents and approve them
+    fn approve_caller_as_driver() {
+        for kind in REQUIRED_DOCUMENTS {
+            submit_driver_document(test_document(kind)).unwrap();
+        }
+        review_driver_application(ic::caller().to_text(), true, "".to_string()).unwrap();
+    }
+
+    /// register and approve the test driver under their own principal
+    fn register_test_driver(ctx: &mut MockContext) {
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver, || {
+            register_driver(test_driver()).unwrap();
+            approve_caller_as_driver();
+        });
+    }
+
+    /// request a ride with the test driver and rider, returning its id
+    fn request_test_ride(ctx: &mut MockContext) -> String {
+        register_test_driver(ctx);
+        as_test_rider(ctx, || request_ride(test_request())).unwrap()
+    }
+
+    ///test the cancellation fee policy
+    #[test]
+    fn test_cancellation_policy_assess() {
+        let ctx = MockContext::new().inject();
+        let policy = CancellationPolicy::default();
+        let ride_id = request_test_ride(ctx);
+        let mut ride = search_ride_by_id(ride_id).unwrap();
+        let requested_at = ride.requested_at;
+
+        //no driver en route yet
+        let late = requested_at + policy.free_window + 1;
+        assert_eq!(policy.assess(&ride, CancelledBy::Rider, late), (0, 0));
+
+        ride.status = RideStatus::Accepted;
+        ride.accepted_at = Some(requested_at);
+        //within the free window
+        let free = requested_at + policy.free_window;
+        assert_eq!(policy.assess(&ride, CancelledBy::Rider, free), (0, 0));
+        //driver en route
+        assert_eq!(policy.assess(&ride, CancelledBy::Rider, late), (policy.rider_fee, 0));
+        //driver did not show up
+        let no_show = requested_at + policy.no_show_wait + 1;
+        assert_eq!(
+            policy.assess(&ride, CancelledBy::Rider, no_show),
+            (0, policy.driver_no_show_penalty)
+        );
+        //arriving after the wait does not help the driver
+        ride.waypoints[0].arrived_at = Some(no_show);
+        assert_eq!(
+            policy.assess(&ride, CancelledBy::Rider, no_show),
+            (0, policy.driver_no_show_penalty)
+        );
+        //a rider who does not come out to a driver waiting at the pickup pays the fee
+        ride.waypoints[0].arrived_at = Some(late);
+        assert_eq!(policy.assess(&ride, CancelledBy::Rider, no_show), (policy.rider_fee, 0));
+        //drivers are never charged a rider fee, but pay the penalty after the free window
+        assert_eq!(policy.assess(&ride, CancelledBy::Driver, free), (0, 0));
+        assert_eq!(
+            policy.assess(&ride, CancelledBy::Driver, late),
+            (0, policy.driver_no_show_penalty)
+        );
+    }
 
-        update_driver_status("Kelsey".to_string(), CurrentStatus::Active);
-        assert_eq!(get_drivers()[0].currentstatus, CurrentStatus::Active);
+    ///test cancelling keeps the ride in history with the reason and canceller
+    #[test]
+    fn test_cancel_ride() {
+        let ctx = MockContext::new().inject();
+        let ride_id = request_test_ride(ctx);
+
+        //strangers cannot cancel
+        ctx.update_caller(mock_principals::john());
+        assert!(cancel_ride(ride_id.clone(), "changed my mind".to_string()).is_err());
+
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(cancel_ride(ride_id.clone(), "changed my mind".to_string()), Ok(0));
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.status, RideStatus::Cancelled);
+        assert!(ride.cancelled_at.is_some());
+        let cancellation = ride.cancellation.unwrap();
+        assert_eq!(cancellation.cancelled_by, CancelledBy::Rider);
+        assert_eq!(cancellation.canceller, mock_principals::bob().to_text());
+        assert_eq!(cancellation.reason, "changed my mind");
+        assert_eq!(get_rides().len(), 1);
+
+        //a cancelled ride cannot be cancelled again
+        assert!(cancel_ride(ride_id, "again".to_string()).is_err());
     }
-    ///test search for driver by address
+
+    ///test rider fees from the policy end up as the amount due
     #[test]
-    fn test_search_driver_by_address() {
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-        };
-        register_driver(driver);
-        assert_eq!(get_drivers().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_drivers()[0].name, "Kelsey");
-        assert_eq!(
-            search_driver_by_address(
-                "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string()
-            )
-            .unwrap()
-            .name,
-            "Kelsey"
-        );
+    fn test_cancel_ride_after_free_window() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        set_cancellation_policy(CancellationPolicy {
+            free_window: 0,
+            ..CancellationPolicy::default()
+        });
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+
+        ctx.update_caller(mock_principals::bob());
+        let fee = cancel_ride(ride_id.clone(), "too slow".to_string()).unwrap();
+        assert_eq!(fee, CancellationPolicy::default().rider_fee);
+        assert_eq!(search_ride_by_id(ride_id).unwrap().amount_due(), fee);
     }
-    ///test search for rider by address
+
+    ///test ledger blocks are checked against the expected transfer
     #[test]
-    fn test_search_rider_by_address() {
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_verify_transfer() {
+        let from = AccountIdentifier::new(&mock_principals::bob(), &DEFAULT_SUBACCOUNT);
+        let to = AccountIdentifier::new(&mock_principals::alice(), &DEFAULT_SUBACCOUNT);
+        let block = Block {
+            parent_hash: None,
+            transaction: Transaction {
+                memo: Memo(0),
+                operation: Some(Operation::Transfer {
+                    from,
+                    to,
+                    amount: Tokens::from_e8s(500),
+                    fee: Tokens::from_e8s(10_000),
+                }),
+                created_at_time: Timestamp { timestamp_nanos: 0 },
+            },
+            timestamp: Timestamp { timestamp_nanos: 0 },
         };
-        register_rider(rider);
-        assert_eq!(get_riders().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_riders()[0].name, "Kelsey");
-        assert_eq!(
-            search_rider_by_address(
-                "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string()
-            )
-            .unwrap()
-            .name,
-            "Kelsey"
-        );
+        assert!(verify_transfer(&block, from, to, 500).is_ok());
+        assert!(verify_transfer(&block, from, to, 501).is_err());
+        assert!(verify_transfer(&block, to, from, 500).is_err());
     }
 
-    /// test search for driver_by_contact
+    ///test scheduled rides are held until the dispatch lead time
     #[test]
-    fn test_search_driver_by_contact() {
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_schedule_ride() {
+        let ctx = MockContext::new().inject();
+        let policy = get_scheduling_policy();
+        let now = ic::time();
+        assert!(as_test_rider(ctx, || schedule_ride(test_request(), now)).is_err());
+
+        let pickup_at = now + 2 * policy.min_advance;
+        let ride_id =
+            as_test_rider(ctx, || schedule_ride(test_request(), pickup_at)).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.status, RideStatus::Scheduled);
+        assert_eq!(ride.scheduled_for, Some(pickup_at));
+        assert!(!ride.has_driver());
+        assert_eq!(get_upcoming_rides().len(), 1);
+
+        //nothing happens before the lead time, even with a driver online
+        register_test_driver(ctx);
+        dispatch_scheduled_rides(pickup_at - policy.dispatch_lead_time - 1);
+        assert_eq!(search_ride_by_id(ride_id.clone()).unwrap().status, RideStatus::Scheduled);
+
+        //the only driver is offered one of the two rides due in the same pass
+        let other_id =
+            as_test_rider(ctx, || schedule_ride(test_request(), pickup_at)).unwrap();
+        let dispatch_at = pickup_at - policy.dispatch_lead_time;
+        dispatch_scheduled_rides(dispatch_at);
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.status, RideStatus::Requested);
+        assert_eq!(ride.driver.address, TEST_ADDRESS);
+        assert_eq!(ride.dispatched_at, Some(dispatch_at));
+        assert_eq!(search_ride_by_id(other_id).unwrap().status, RideStatus::Scheduled);
+        assert_eq!(get_upcoming_rides().len(), 1);
+    }
+
+    ///test drivers can pre-accept scheduled rides and back out again
+    #[test]
+    fn test_pre_accept_scheduled_ride() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let policy = get_scheduling_policy();
+        let pickup_at = ic::time() + 2 * policy.min_advance;
+        let ride_id =
+            as_test_rider(ctx, || schedule_ride(test_request(), pickup_at)).unwrap();
+
+        //only registered drivers can pre-accept
+        assert!(pre_accept_ride(ride_id.clone()).is_err());
+        register_test_driver(ctx);
+        pre_accept_ride(ride_id.clone()).unwrap();
+        assert!(get_upcoming_rides().is_empty());
+        assert!(pre_accept_ride(ride_id.clone()).is_err());
+
+        //backing out puts the ride back on the upcoming list
+        cancel_ride(ride_id.clone(), "busy".to_string()).unwrap();
+        assert_eq!(get_upcoming_rides().len(), 1);
+        pre_accept_ride(ride_id.clone()).unwrap();
+
+        ctx.update_caller(mock_principals::john());
+        dispatch_scheduled_rides(pickup_at - policy.dispatch_lead_time);
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.status, RideStatus::Accepted);
+        assert_eq!(ride.accepted_at, Some(pickup_at - policy.dispatch_lead_time));
+
+        //a vehicle limited promo can be booked, and only a covered vehicle takes the ride
+        create_promo_code(PromoCode {
+            code: "SEDAN".to_string(),
+            discount: Discount::Percent(10),
+            max_uses: None,
+            per_user_limit: None,
+            expires_at: pickup_at,
+            zone_ids: vec![],
+            vehicle_types: vec!["Sedan".to_string()],
+            first_ride_only: false,
+        })
+        .unwrap();
+        let request = RideRequest {
+            promo_code: Some("sedan".to_string()),
+            ..test_request()
         };
-        register_driver(driver);
-        assert_eq!(get_drivers().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_drivers()[0].name, "Kelsey");
-        assert_eq!(
-            search_driver_by_contact("1234567890".to_string())
-                .unwrap()
-                .name,
-            "Kelsey"
-        );
+        let promo_ride = as_test_rider(ctx, || schedule_ride(request, pickup_at)).unwrap();
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        assert!(as_caller(ctx, driver, || pre_accept_ride(promo_ride.clone())).is_err());
+        dispatch_scheduled_rides(pickup_at - policy.dispatch_lead_time);
+        let ride = search_ride_by_id(promo_ride).unwrap();
+        assert_eq!(ride.status, RideStatus::Scheduled);
+        assert!(!ride.has_driver());
     }
-    ///test create ride
+
+    ///test stops can be added mid-ride once both parties agree
     #[test]
-    fn test_create_ride() {
-        //!create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_stop_changes() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        let chicago = Location {
+            address_text: "chicago".to_string(),
+            lat: 41.8781,
+            lng: -87.6298,
         };
-        register_driver(driver.clone());
-        //create rider
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+
+        //the pickup cannot be replaced and the dropoff cannot be removed
+        let before_pickup = StopChange::Add {
+            index: 0,
+            location: chicago.clone(),
         };
-        register_rider(rider.clone());
+        assert!(propose_stop_change(ride_id.clone(), before_pickup).is_err());
+        let remove_dropoff = StopChange::Remove { index: 1 };
+        assert!(propose_stop_change(ride_id.clone(), remove_dropoff).is_err());
 
-        //create ride
-        let ride = Ride {
-            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-            driver: driver,
-            rider: rider,
-            pickup: "new york".to_string(),
-            dropoff: "san francisco".to_string(),
-            status: RideStatus::Active,
-            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
-            rating: 0.0,
-            driverrating: 0.0,
-            riderrating: 0.0,
-            driverfeedback: "".to_string(),
-            riderfeedback: "".to_string(),
-            riderconfirmation: "".to_string(),
-            driverconfirmation: "".to_string(),
+        //rider proposes, the proposer cannot confirm their own change
+        ctx.update_caller(mock_principals::bob());
+        let add_chicago = StopChange::Add {
+            index: 1,
+            location: chicago,
         };
-        //register ride
-        register_ride(ride);
-        //get list of all rides
-        let rides = get_rides();
-        //assert
-        assert_eq!(rides.len(), 1);
+        propose_stop_change(ride_id.clone(), add_chicago).unwrap();
+        assert!(respond_stop_change(ride_id.clone(), true).is_err());
+        assert_eq!(search_ride_by_id(ride_id.clone()).unwrap().waypoints.len(), 2);
+
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        respond_stop_change(ride_id.clone(), true).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.waypoints.len(), 3);
+        assert_eq!(ride.waypoints[1].location.address_text, "chicago");
+        assert_eq!(ride.leg_distances_km().len(), 2);
+        let direct = distance_km(&test_request().pickup, &test_request().dropoff);
+        assert!(ride.route_distance_km() > direct);
+
+        //a change proposed before the stop it precedes was reached
+        let before_chicago = StopChange::Add {
+            index: 1,
+            location: test_request().dropoff,
+        };
+        propose_stop_change(ride_id.clone(), before_chicago).unwrap();
+
+        //stops are reached in order
+        assert!(arrive_at_stop(ride_id.clone(), 1).is_err());
+        arrive_at_stop(ride_id.clone(), 0).unwrap();
+        start_test_ride(ctx, &ride_id);
+        arrive_at_stop(ride_id.clone(), 1).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.waypoints[1].status, StopStatus::Arrived);
+        assert!(ride.waypoints[1].arrived_at.is_some());
+
+        //can no longer be applied but stays pending until it is declined
+        ctx.update_caller(mock_principals::bob());
+        assert!(respond_stop_change(ride_id.clone(), true).is_err());
+        assert!(search_ride_by_id(ride_id.clone()).unwrap().pending_stop_change.is_some());
+        respond_stop_change(ride_id.clone(), false).unwrap();
+        assert!(search_ride_by_id(ride_id.clone()).unwrap().pending_stop_change.is_none());
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+
+        //a reached stop can no longer be removed
+        let remove_chicago = StopChange::Remove { index: 1 };
+        assert!(propose_stop_change(ride_id, remove_chicago).is_err());
     }
-    ///test search riders by field and return the rider
+
+    /// ride request along the equator between two longitudes
+    fn equator_request(from_lng: f64, to_lng: f64) -> RideRequest {
+        let location = |lng: f64| Location {
+            address_text: format!("{}", lng),
+            lat: 0.0,
+            lng,
+        };
+        RideRequest {
+            pickup: location(from_lng),
+            dropoff: location(to_lng),
+            stops: vec![],
+            promo_code: None,
+            quoted_surge: None,
+        }
+    }
+
+    ///test new riders are inserted into a pool route within the detour limit
     #[test]
-    fn test_search_rider_by_field() {
-        let rider = Rider::new(
-            "Kelsey".to_string(),
-            "1234567890".to_string(),
-            "test@email.com".to_string(),
-            "rider".to_string(),
-            "123 Main St".to_string(),
-        );
-        RIDER_STORE.with(|rider_store| {
-            rider_store.borrow_mut().push(rider.clone());
-        });
-        assert_eq!(get_riders().len(), 1);
-        //check the data was written to the store
-        assert_eq!(get_riders()[0].name, "Kelsey");
-        assert_eq!(
-            //get the first rider
-            search_rider_by_field("name".to_string(), "Kelsey".to_string())[0].as_ref()
-                .unwrap().name,
-            "Kelsey"
-        );
-        assert_eq!(
-            //get the first rider
-            search_rider_by_field("contact".to_string(), "1234567890".to_string())[0].as_ref()
-                .unwrap().name,
-            "Kelsey"
+    fn test_pool_plan_insertion() {
+        let mut pool = Pool {
+            pool_id: "pool".to_string(),
+            driver: TEST_ADDRESS.to_string(),
+            seats: 2,
+            route: vec![],
+            created_at: 0,
+        };
+        let (route, _) = pool.plan_insertion("a", &equator_request(0.0, 1.0), 1.5).unwrap();
+        pool.route = route;
+
+        //a rider along the way is picked up and dropped off inside the first leg
+        let (route, added) = pool.plan_insertion("b", &equator_request(0.2, 0.8), 1.5).unwrap();
+        let order: Vec<&str> = route.iter().map(|stop| stop.ride_id.as_str()).collect();
+        assert_eq!(order, vec!["a", "b", "b", "a"]);
+        assert!(added.abs() < 1e-6);
+
+        //a detour to the north breaks the limit for the first rider
+        let mut detour = route.clone();
+        detour.insert(
+            1,
+            PoolStop {
+                ride_id: "c".to_string(),
+                kind: PoolStopKind::Pickup,
+                location: Location {
+                    address_text: "north".to_string(),
+                    lat: 1.0,
+                    lng: 0.5,
+                },
+                visited: false,
+            },
         );
+        assert!(!Pool::is_feasible(&detour, 3, 1.5));
+
+        //with two seats the third rider cannot ride along with the other two
+        pool.route = route;
+        let (route, added) = pool.plan_insertion("c", &equator_request(0.3, 0.7), 1.5).unwrap();
+        assert_eq!(route.len(), 6);
+        assert!(Pool::is_feasible(&route, 2, 1.5));
+        assert!(added > 0.1);
+        pool.seats = 3;
+        let (_, added) = pool.plan_insertion("c", &equator_request(0.3, 0.7), 1.5).unwrap();
+        assert!(added.abs() < 1e-6);
+
+        //stops already visited stay in front
+        pool.route[0].visited = true;
+        pool.route[1].visited = true;
+        let (route, _) = pool.plan_insertion("c", &equator_request(0.0, 0.1), 1.5).unwrap();
+        assert!(route.iter().position(|stop| stop.ride_id == "c").unwrap() >= 2);
     }
 
-    ///test search for driver by field and return the driver
+    ///test pooled requests join an in-progress pool and pay a discounted share
     #[test]
-    fn test_search_driver_by_field() {
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_request_pooled_ride() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        let first = as_test_rider(ctx, || request_pooled_ride(equator_request(0.0, 1.0))).unwrap();
+        let pool_id = search_ride_by_id(first.clone()).unwrap().pool_id.unwrap();
+
+        //nobody joins until the driver has accepted
+        let second = as_test_rider(ctx, || request_pooled_ride(equator_request(0.2, 0.8))).unwrap();
+        let second_pool = search_ride_by_id(second.clone()).unwrap().pool_id.unwrap();
+        assert_ne!(second_pool, pool_id);
+        ctx.update_caller(mock_principals::bob());
+        cancel_ride(second, "testing".to_string()).unwrap();
+        assert!(!get_pool(second_pool).unwrap().is_open());
+
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        accept_ride(first.clone()).unwrap();
+        let third = as_test_rider(ctx, || request_pooled_ride(equator_request(0.2, 0.8))).unwrap();
+        let ride = search_ride_by_id(third.clone()).unwrap();
+        assert_eq!(ride.pool_id, Some(pool_id.clone()));
+        assert_eq!(ride.status, RideStatus::Accepted);
+        assert_eq!(get_pool(pool_id.clone()).unwrap().route.len(), 4);
+        assert!(as_test_rider(ctx, || get_pool(pool_id.clone())).is_ok());
+        let stranger = mock_principals::john();
+        assert!(as_caller(ctx, stranger, || get_pool(pool_id.clone())).is_err());
+
+        start_test_ride(ctx, &first);
+        start_test_ride(ctx, &third);
+        let fare = complete_ride(third.clone()).unwrap();
+        let ride = search_ride_by_id(third).unwrap();
+        let full_fare = get_fare_config()
+            .fare_for_trip(ride.trip_duration().unwrap(), ride.route_distance_km());
+        assert_eq!(fare, get_pool_policy().discounted(full_fare));
+        assert!(fare < full_fare);
+
+        complete_ride(first).unwrap();
+        assert!(!get_pool(pool_id).unwrap().is_open());
+    }
+
+    ///test drivers on a ride can be tracked by their rider only
+    #[test]
+    fn test_ride_tracking() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        ctx.update_caller(mock_principals::bob());
+        assert!(get_ride_tracking(ride_id.clone()).is_err());
+
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        assert!(report_location(91.0, 0.0, 0.0).is_err());
+        assert!(report_location(0.0, 0.0, 360.0).is_err());
+        report_location(0.0, 0.0, 90.0).unwrap();
+        assert!(report_location(0.0, 0.0, 90.0).is_err());
+
+        ctx.update_caller(mock_principals::bob());
+        let tracking = get_ride_tracking(ride_id.clone()).unwrap();
+        assert_eq!(tracking.position.heading, 90.0);
+        assert!(tracking.eta > 0);
+        ctx.update_caller(mock_principals::alice());
+        assert!(get_ride_tracking(ride_id.clone()).is_err());
+        assert_eq!(get_ride_breadcrumbs(ride_id.clone()).len(), 1);
+
+        //breadcrumbs outlive the ride by BREADCRUMB_RETENTION, longer while it is disputed
+        as_test_rider(ctx, || cancel_ride(ride_id.clone(), "testing".to_string())).unwrap();
+        let expired = ic::time() + BREADCRUMB_RETENTION + 1;
+        prune_breadcrumbs(ic::time());
+        assert_eq!(get_ride_breadcrumbs(ride_id.clone()).len(), 1);
+        let kind = DisputeKind::Dispute;
+        let category = DisputeCategory::Route;
+        let report = || open_dispute(ride_id.clone(), kind, category, "x".to_string(), vec![]);
+        as_test_rider(ctx, report).unwrap();
+        prune_breadcrumbs(expired);
+        assert_eq!(get_ride_breadcrumbs(ride_id.clone()).len(), 1);
+        DISPUTES.with(|disputes| disputes.borrow_mut().clear());
+        prune_breadcrumbs(expired);
+        assert!(get_ride_breadcrumbs(ride_id).is_empty());
+    }
+
+    ///test quotes use road distances and dispatch offers the nearest driver first
+    #[test]
+    fn test_quote_and_nearest_dispatch() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let request = equator_request(0.0, 1.0);
+        let quote = quote_fare(request.clone()).unwrap();
+        let direct = distance_km(&request.pickup, &request.dropoff);
+        assert!((quote.distance_km - direct * get_geo_config().road_factor).abs() < 1e-9);
+        assert_eq!(quote.duration, get_geo_config().route_eta(&request.route()));
+        assert!(quote.fare > get_fare_config().base_fare);
+
+        register_test_driver(ctx);
+        report_location(10.0, 0.0, 0.0).unwrap();
+        ctx.update_caller(mock_principals::john());
+        register_driver(test_driver()).unwrap();
+        approve_caller_as_driver();
+        report_location(0.1, 0.0, 180.0).unwrap();
+        let ride_id = as_test_rider(ctx, || request_ride(request.clone())).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.driver.address, mock_principals::john().to_text());
+
+        //a driver holding a ride is passed over for the next request
+        accept_ride(ride_id).unwrap();
+        let next = as_test_rider(ctx, || request_ride(request.clone())).unwrap();
+        assert_eq!(search_ride_by_id(next).unwrap().driver.address, TEST_ADDRESS);
+        assert!(as_test_rider(ctx, || request_ride(request)).is_err());
+    }
+
+    /// a zone covering the rectangle between the given corners
+    fn test_zone(zone_id: &str, south_west: (f64, f64), north_east: (f64, f64)) -> ServiceZone {
+        let ((south, west), (north, east)) = (south_west, north_east);
+        let corner = |lat: f64, lng: f64| Location {
+            address_text: "".to_string(),
+            lat,
+            lng,
         };
-        register_driver(driver);
-        assert_eq!(
-            //get the first driver
-            search_driver_by_field("name".to_string(), "Kelsey".to_string())[0].as_ref()
-                .unwrap().name,
-            "Kelsey"
-        );
-        
-            }
+        ServiceZone {
+            zone_id: zone_id.to_string(),
+            name: zone_id.to_string(),
+            polygon: vec![
+                corner(south, west),
+                corner(north, west),
+                corner(north, east),
+                corner(south, east),
+            ],
+            enabled: true,
+            fare_multiplier: 1.0,
+            airport_surcharge: 0,
+            restricted_pickups: vec![],
+        }
+    }
 
-    ///test search for driver by name and return the driver
+    ///test pickups must be inside an enabled zone and pick up its pricing
     #[test]
-    fn test_search_driver_by_name() {
-        //!create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_service_zones() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        //no zones, no restrictions
+        assert_eq!(quote_fare(test_request()).unwrap().pricing.zone_id, None);
+
+        let mut city = test_zone("city", (-1.0, -1.0), (1.0, 2.0));
+        city.fare_multiplier = 1.5;
+        city.restricted_pickups.push(RestrictedPickup {
+            name: "stadium".to_string(),
+            center: equator_request(0.5, 1.0).pickup,
+            radius_km: 1.0,
+        });
+        set_service_zone(city.clone()).unwrap();
+        let mut airport = test_zone("airport", (-0.1, 0.9), (0.1, 1.1));
+        airport.airport_surcharge = 50_000_000;
+        set_service_zone(airport).unwrap();
+        assert!(set_service_zone(test_zone("", (0.0, 0.0), (1.0, 1.0))).is_err());
+
+        assert!(quote_fare(test_request()).is_err());
+        assert!(quote_fare(equator_request(0.5, 1.5)).is_err());
+        let quote = quote_fare(equator_request(1.0, 0.0)).unwrap();
+        assert_eq!(quote.pricing.zone_id, Some("airport".to_string()));
+        let quote = quote_fare(equator_request(-0.5, 1.0)).unwrap();
+        assert_eq!(quote.pricing.zone_id, Some("city".to_string()));
+        assert_eq!(quote.pricing.fare_multiplier, 1.5);
+        assert_eq!(quote.pricing.surcharge, 50_000_000);
+
+        let ride_id = as_test_rider(ctx, || request_ride(equator_request(-0.5, 1.0))).unwrap();
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.pricing().surcharge, 50_000_000);
+
+        //a restriction of the city still applies where the airport zone prices the ride
+        city.restricted_pickups.push(RestrictedPickup {
+            name: "cargo gate".to_string(),
+            center: equator_request(1.05, 0.0).pickup,
+            radius_km: 1.0,
+        });
+        set_service_zone(city.clone()).unwrap();
+        assert!(quote_fare(equator_request(1.05, 0.0)).is_err());
+        assert!(quote_fare(equator_request(0.95, 0.0)).is_ok());
+        let mut invalid = city.clone();
+        invalid.fare_multiplier = f64::NAN;
+        assert!(set_service_zone(invalid).is_err());
+        let mut invalid = city.clone();
+        invalid.restricted_pickups[0].radius_km = f64::INFINITY;
+        assert!(set_service_zone(invalid).is_err());
+
+        city.enabled = false;
+        set_service_zone(city).unwrap();
+        assert!(as_test_rider(ctx, || request_ride(equator_request(-0.5, 1.0))).is_err());
+        remove_service_zone("city".to_string()).unwrap();
+        remove_service_zone("airport".to_string()).unwrap();
+        assert!(quote_fare(test_request()).is_ok());
+    }
+
+    ///test surge follows requests per driver, is capped and is locked onto requested rides
+    #[test]
+    fn test_surge_pricing() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let policy = SurgePolicy {
+            update_interval: 0,
+            ..SurgePolicy::default()
         };
-        register_driver(driver);
+        assert_eq!(policy.multiplier(0, 0), 1.0);
+        assert_eq!(policy.multiplier(2, 4), 1.0);
+        assert_eq!(policy.multiplier(3, 2), 1.3);
+        assert_eq!(policy.multiplier(100, 1), policy.max_multiplier);
+        set_surge_policy(policy.clone()).unwrap();
+        assert!(set_surge_policy(SurgePolicy {
+            max_multiplier: 0.5,
+            ..policy.clone()
+        })
+        .is_err());
+        assert!(set_surge_policy(SurgePolicy {
+            sensitivity: f64::INFINITY,
+            ..policy.clone()
+        })
+        .is_err());
 
-        //search for driver
-        let driver_found = search_driver_by_name("Kelsey".to_string());
-        //assert
-        assert_eq!(driver_found.unwrap().name, "Kelsey");
+        //a driver is offered one request at a time, support registers the others waiting on them
+        let first = request_test_ride(ctx);
+        let waiting = search_ride_by_id(first.clone()).unwrap();
+        for n in 0..2 {
+            register_ride(Ride {
+                rideid: format!("waiting-{}", n),
+                ..waiting.clone()
+            });
+        }
+        update_surge(ic::time());
+        assert_eq!(get_surge().multipliers.get(""), Some(&2.0));
+        let quote = quote_fare(test_request()).unwrap();
+        assert_eq!(quote.pricing.surge_multiplier, 2.0);
+        //riders quoted before the surge rose have to confirm the new price
+        let quoted = |surge: f64| RideRequest {
+            quoted_surge: Some(surge),
+            ..test_request()
+        };
+        assert!(as_test_rider(ctx, || request_ride(quoted(1.0))).is_err());
+        as_caller(ctx, mock_principals::john(), || {
+            register_driver(test_driver()).unwrap();
+            approve_caller_as_driver();
+        });
+        let surged = as_test_rider(ctx, || request_ride(quoted(2.0))).unwrap();
+        assert_eq!(search_ride_by_id(surged.clone()).unwrap().pricing().surge_multiplier, 2.0);
+        assert_eq!(search_ride_by_id(first).unwrap().pricing().surge_multiplier, 1.0);
+
+        set_surge_policy(SurgePolicy {
+            max_multiplier: 1.5,
+            ..policy
+        })
+        .unwrap();
+        update_surge(ic::time());
+        assert_eq!(quote_fare(test_request()).unwrap().pricing.surge_multiplier, 1.5);
+        as_test_rider(ctx, || cancel_ride(surged, "too dear".to_string())).unwrap();
+
+        //a driver who has not reported a location takes their requests out of the count
+        set_service_zone(test_zone("nyc", (40.0, -75.0), (41.0, -73.0))).unwrap();
+        as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        update_surge(ic::time());
+        assert_eq!(get_surge().multipliers.get("nyc"), Some(&1.0));
     }
-    ///test search ride by id
+
+    ///test promo codes are validated against their limits and discount the fare
     #[test]
-    fn test_search_ride_by_id() {
-        //create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-        };
-        register_driver(driver.clone());
-        //create rider
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_promo_codes() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        let welcome = PromoCode {
+            code: "welcome".to_string(),
+            discount: Discount::Percent(20),
+            max_uses: Some(10),
+            per_user_limit: Some(1),
+            expires_at: ic::time() + 60 * NANOS_PER_MINUTE,
+            zone_ids: vec![],
+            vehicle_types: vec![],
+            first_ride_only: true,
         };
-        register_rider(rider.clone());
-        //create ride
-        let ride = Ride {
-            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-            driver: driver,
-            rider: rider,
-            pickup: "new york".to_string(),
-            dropoff: "san francisco".to_string(),
-            status: RideStatus::Active,
-            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
-            rating: 0.0,
-            driverrating: 0.0,
-            riderrating: 0.0,
-            driverfeedback: "".to_string(),
-            riderfeedback: "".to_string(),
-            riderconfirmation: "".to_string(),
-            driverconfirmation: "".to_string(),
+        create_promo_code(welcome.clone()).unwrap();
+        assert!(create_promo_code(welcome.clone()).is_err());
+        create_promo_code(PromoCode {
+            code: "SEDAN".to_string(),
+            discount: Discount::Flat(1_000_000),
+            vehicle_types: vec!["Sedan".to_string()],
+            first_ride_only: false,
+            ..welcome.clone()
+        })
+        .unwrap();
+        assert_eq!(Discount::Flat(5).apply(3), 0);
+        assert_eq!(Discount::Percent(20).apply(100), 80);
+
+        let promo_request = |code: &str| RideRequest {
+            promo_code: Some(code.to_string()),
+            ..test_request()
         };
-        //register ride
-        register_ride(ride.clone());
-        //search ride by id
-        let search_ride = search_ride_by_id(ride.clone().rideid);
-        //assert
-        assert_eq!(search_ride.unwrap().rideid, ride.clone().rideid);
+        let full = quote_fare(test_request()).unwrap().fare;
+        ctx.update_caller(mock_principals::bob());
+        assert_eq!(quote_fare(promo_request("Welcome")).unwrap().fare, full * 80 / 100);
+        assert!(quote_fare(promo_request("nope")).is_err());
+
+        let ride_id = as_test_rider(ctx, || request_ride(promo_request("welcome"))).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.pricing().promo_code, Some("WELCOME".to_string()));
+        assert_eq!(ride.pricing().discount, Some(Discount::Percent(20)));
+        assert!(as_test_rider(ctx, || request_ride(promo_request("welcome"))).is_err());
+        assert!(as_test_rider(ctx, || request_ride(promo_request("sedan"))).is_err());
+
+        //a cancelled ride gives the redemption back
+        ctx.update_caller(mock_principals::bob());
+        cancel_ride(ride_id, "testing".to_string()).unwrap();
+        as_test_rider(ctx, || request_ride(promo_request("welcome"))).unwrap();
+        assert_eq!(export_my_data().promo_redemptions.len(), 2);
+
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        expire_promo_code("sedan".to_string()).unwrap();
+        assert!(quote_fare(promo_request("sedan")).is_err());
+        assert_eq!(get_promo_codes().len(), 2);
     }
-    ///test request ride
+
+    ///test only the rider can tip a completed ride once, within the window, all to the driver
     #[test]
-    fn test_request_ride() {
-        //create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_tip_driver() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        let bob = mock_principals::bob().to_text();
+        let tip = |ride_id: &str, rider: &str, amount: u64| {
+            with_ride_mut("tip_driver", ride_id, |ride| record_tip(ride, rider, amount, 7))
         };
-        register_driver(driver.clone());
-        //create rider
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        assert!(tip(&ride_id, &bob, 1_000_000).is_err());
+        start_test_ride(ctx, &ride_id);
+        complete_ride(ride_id.clone()).unwrap();
+
+        assert!(tip(&ride_id, TEST_ADDRESS, 1_000_000).is_err());
+        assert!(tip(&ride_id, &bob, 0).is_err());
+        tip(&ride_id, &bob, 1_000_000).unwrap();
+        assert!(tip(&ride_id, &bob, 1_000_000).is_err());
+        let ride = search_ride_by_id(ride_id).unwrap();
+        assert_eq!(ride.tip.as_ref().map(|tip| tip.amount), Some(1_000_000));
+        assert_eq!(get_my_balance(), 1_000_000);
+
+        let late = ride.completed_at.unwrap() + TIP_WINDOW + 1;
+        let ride = Ride { tip: None, ..ride };
+        assert!(ride.check_tip(&bob, 1_000_000, late).is_err());
+    }
+
+    ///test settled rides, tips and adjustments build the driver's balance and statements
+    #[test]
+    fn test_driver_earnings() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        //2024-01-01 was a monday
+        let monday = 1_704_067_200 * 1_000_000_000;
+        assert_eq!(week_start(monday), monday);
+        assert_eq!(week_start(monday + 3 * NANOS_PER_WEEK / 7), monday);
+        assert_eq!(week_start(monday - 1), monday - NANOS_PER_WEEK);
+        //the first days of the epoch belong to a week that started before it
+        assert_eq!(week_start(0), 0);
+
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        start_test_ride(ctx, &ride_id);
+        let fare = complete_ride(ride_id.clone()).unwrap();
+        post_settlement(&search_ride_by_id(ride_id.clone()).unwrap());
+        let fee = fare * get_fare_config().platform_fee_percent() / 100;
+        assert_eq!(get_my_balance(), (fare - fee) as i64);
+
+        adjust_driver_earnings(TEST_ADDRESS.to_string(), Some(ride_id), -100, "fix".to_string())
+            .unwrap();
+        assert!(adjust_driver_earnings(TEST_ADDRESS.to_string(), None, 1, "".to_string()).is_err());
+        let entries = get_my_earnings(0, u64::MAX);
+        let kinds: Vec<EarningKind> = entries.iter().map(|entry| entry.kind).collect();
+        let expected = [EarningKind::Fare, EarningKind::PlatformFee, EarningKind::Adjustment];
+        assert_eq!(kinds, expected);
+        assert_eq!(get_my_balance(), (fare - fee) as i64 - 100);
+
+        assert!(get_my_statement(ic::time()).is_err());
+        let last_week = get_my_statement(ic::time() - NANOS_PER_WEEK).unwrap();
+        assert!(last_week.entries.is_empty());
+        let statement = EarningsStatement {
+            opening_balance: 0,
+            closing_balance: get_my_balance(),
+            entries: entries.clone(),
+            ..last_week
         };
-        register_rider(rider.clone());
-        //request a ride
-        request_ride(
-            rider.clone(),
-            "new york".to_string(),
-            "san francisco".to_string(),
-            "2020-01-01T00:00:00.000Z".to_string(),
-        );
-        //get rides
-        let rides = get_rides();
-        //get first ride
-        let ride = rides.first().unwrap();
-        //assert ride exists
-        assert_eq!(ride.rider.name, rider.name);
-        assert_eq!(ride.driver.name, driver.name);
+        let csv = statement.to_csv();
+        assert_eq!(csv.lines().count(), 4);
+        let fee_row = format!(",platform_fee,-{},20%", fee);
+        assert!(csv.lines().nth(2).unwrap().ends_with(&fee_row));
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
     }
-    //test search ride by field
+
+    ///test payout batches take balances above the threshold and retry until they give up
     #[test]
-    fn test_search_ride_by_field() {
-        //create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_payouts() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        let account = AccountIdentifier::new(&mock_principals::john(), &DEFAULT_SUBACCOUNT);
+        assert!(set_payout_account(vec![0; 32]).is_err());
+        set_payout_account(account.as_ref().to_vec()).unwrap();
+        let driver = TEST_ADDRESS.to_string();
+        adjust_driver_earnings(driver.clone(), None, 200_000_000, "bonus".to_string()).unwrap();
+
+        let planned = run_payouts(true);
+        assert_eq!(planned.len(), 1);
+        assert_eq!(planned[0].to, account);
+        assert_eq!(get_my_balance(), 200_000_000);
+        assert!(get_payouts(None).is_empty());
+
+        let payout = run_payouts(false).remove(0);
+        assert_eq!(get_my_balance(), 0);
+        assert!(run_payouts(false).is_empty());
+        let now = payout.created_at;
+        for attempt in 0..get_payout_policy().max_attempts {
+            assert_eq!(get_my_payouts()[0].status, PayoutStatus::Pending);
+            let refused = PayoutFailure::Rejected("insufficient funds".to_string());
+            finish_payout(payout.payout_id, Err(refused), now);
+            if attempt == 0 {
+                let next = get_my_payouts()[0].next_attempt_at;
+                assert_eq!(next, Some(now + PAYOUT_RETRY_DELAY));
+            }
+        }
+        assert_eq!(get_my_payouts()[0].status, PayoutStatus::Failed);
+        assert_eq!(get_my_balance(), 200_000_000);
+
+        //unanswered transfers keep their balance taken and are retried until the window closes
+        let retry = run_payouts(false).remove(0);
+        let lost = || PayoutFailure::Unknown("no reply".to_string());
+        for _ in 0..2 * get_payout_policy().max_attempts {
+            finish_payout(retry.payout_id, Err(lost()), retry.created_at);
+        }
+        let payouts = get_payouts(Some(PayoutStatus::Pending));
+        assert_eq!(payouts.len(), 1);
+        assert!(payouts[0].next_attempt_at.unwrap() > retry.created_at + PAYOUT_RETRY_DELAY);
+        finish_payout(retry.payout_id, Err(lost()), retry.created_at + PAYOUT_RETRY_WINDOW);
+        assert_eq!(get_payouts(Some(PayoutStatus::Unconfirmed)).len(), 1);
+        assert_eq!(get_my_balance(), 0);
+
+        adjust_driver_earnings(driver, None, 200_000_000, "bonus".to_string()).unwrap();
+        let retry = run_payouts(false).remove(0);
+        finish_payout(retry.payout_id, Ok(42), retry.created_at);
+        let paid = get_payouts(Some(PayoutStatus::Paid));
+        assert_eq!(paid.len(), 1);
+        assert_eq!(paid[0].block_height, Some(42));
+        assert_eq!(get_my_balance(), 0);
+    }
+
+    ///test support refunds paid rides up to what was paid and adjusts unpaid fares
+    #[test]
+    fn test_refunds_and_fare_adjustments() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        start_test_ride(ctx, &ride_id);
+        let fare = complete_ride(ride_id.clone()).unwrap();
+        let reason = AdjustmentReason::Overcharge;
+        assert!(reserve_refund(&ride_id, None, reason, "".to_string(), false).is_err());
+
+        adjust_fare(ride_id.clone(), fare - 100, reason, "detour".to_string()).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.fare, fare - 100);
+        assert_eq!(ride.adjustments()[0].amount, -100);
+
+        let paid = ride.fare;
+        with_ride_mut("settle_ride", &ride_id, |ride| {
+            ride.payment = Some(Payment {
+                block_height: 1,
+                amount: paid,
+                paid_at: ic::time(),
+            });
+            post_settlement(ride);
+            Ok(())
+        })
+        .unwrap();
+        assert!(adjust_fare(ride_id.clone(), fare, reason, "".to_string()).is_err());
+        let balance = get_my_balance();
+
+        let refund =
+            reserve_refund(&ride_id, Some(1_000), reason, "".to_string(), true).unwrap();
+        assert_eq!(refund.amount, 1_000);
+        let share = 1_000 * (100 - get_fare_config().platform_fee_percent()) / 100;
+        assert_eq!(get_my_balance(), balance - share as i64);
+        finish_refund(&ride_id, refund.index, Err("insufficient funds".to_string())).unwrap();
+        assert_eq!(get_my_balance(), balance);
+
+        assert!(reserve_refund(&ride_id, Some(paid + 1), reason, "".to_string(), false).is_err());
+        let second = reserve_refund(&ride_id, None, reason, "".to_string(), false).unwrap();
+        assert_eq!(second.amount, paid);
+        //each refund gets its own memo, the refunds of other rides included
+        assert_eq!(second.refund_id, refund.refund_id + 1);
+        finish_refund(&ride_id, second.index, Ok(9)).unwrap();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.refundable(), 0);
+        assert_eq!(ride.adjustments().len(), 3);
+        assert_eq!(ride.adjustments()[2].refund_status, Some(RefundStatus::Paid));
+        assert_eq!(ride.adjustments()[2].refund_id, Some(second.refund_id));
+        assert!(reserve_refund(&ride_id, None, reason, "".to_string(), false).is_err());
+    }
+
+    ///test a rider's report goes through the support queue and suspends the driver
+    #[test]
+    fn test_dispute_workflow() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let open = |description: &str, evidence: Vec<String>| {
+            let (kind, category) = (DisputeKind::Incident, DisputeCategory::Conduct);
+            open_dispute(ride_id.clone(), kind, category, description.to_string(), evidence)
         };
+        ctx.update_caller(mock_principals::john());
+        assert!(open("wrong ride", Vec::new()).is_err());
+        ctx.update_caller(mock_principals::bob());
+        assert!(open("no driver yet", Vec::new()).is_err());
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        assert!(open("  ", Vec::new()).is_err());
+        assert!(open("rude driver", vec!["not-a-hash".to_string()]).is_err());
+        let dispute_id = open("rude driver", vec!["ab".repeat(32)]).unwrap();
+        assert!(open("rude driver again", Vec::new()).is_err());
+        assert_eq!(get_my_disputes()[0].reporter_party, RideParty::Rider);
+        ctx.update_caller(Principal::from_text(TEST_ADDRESS).unwrap());
+        assert_eq!(get_my_notifications(true).len(), 1);
 
-        register_driver(driver.clone());
-        //create rider
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+        //only support agents take disputes, and rejections cannot carry outcomes
+        assert!(assign_dispute(dispute_id, mock_principals::john()).is_err());
+        grant_role(mock_principals::alice(), Role::Support);
+        assign_dispute(dispute_id, mock_principals::alice()).unwrap();
+        assert_eq!(get_disputes(Some(DisputeStatus::Investigating)).len(), 1);
+        let resolution = DisputeResolution {
+            upheld: false,
+            note: "confirmed by trip logs".to_string(),
+            refund: None,
+            charge_driver: false,
+            suspension: Some(DisputeSuspension {
+                party: RideParty::Driver,
+                expires_at: None,
+            }),
         };
-        register_rider(rider.clone());
-        //request a ride
-        request_ride(
-            rider.clone(),
-            "new york".to_string(),
-            "san francisco".to_string(),
-            "2020-01-01T00:00:00.000Z".to_string(),
-        );
-        //get rides
-        let rides = get_rides();
-        //get first ride
-        let ride = rides.first().unwrap();
-        //assert ride exists
-        assert_eq!(ride.rider.name, rider.name);
-        assert_eq!(ride.driver.name, driver.name);
-        //search ride by pickup
+        let resolve = |resolution| {
+            async_std::task::block_on(resolve_dispute(dispute_id, resolution))
+        };
+        assert!(resolve(resolution.clone()).is_err());
+        //a suspension that cannot be placed is refused before the dispute closes
+        let lapsed = DisputeResolution {
+            upheld: true,
+            suspension: Some(DisputeSuspension {
+                party: RideParty::Driver,
+                expires_at: Some(ic::time()),
+            }),
+            ..resolution.clone()
+        };
+        assert!(resolve(lapsed).is_err());
+        assert_eq!(get_disputes(Some(DisputeStatus::Investigating)).len(), 1);
+
+        resolve(DisputeResolution {
+            upheld: true,
+            ..resolution.clone()
+        })
+        .unwrap();
+        let dispute = get_disputes(None).remove(0);
+        assert_eq!(dispute.status, DisputeStatus::Resolved);
+        let sanctions = get_sanctions_for(Principal::from_text(TEST_ADDRESS).unwrap());
+        assert_eq!(Some(sanctions[0].sanction_id), dispute.sanction_id);
+        assert!(resolve(resolution).is_err());
+
+        //both parties hear the outcome and can clear their inbox
+        let inbox = as_test_rider(ctx, || get_my_notifications(false));
+        assert_eq!(inbox.len(), 3);
+        assert!(inbox[0].text.contains("resolved"));
+        mark_notifications_read(u64::MAX);
+        assert!(get_my_notifications(true).is_empty());
+        let audited = get_audit_events(AuditFilter::default(), 0, 100).events;
+        assert_eq!(audited.last().unwrap().endpoint, "mark_notifications_read");
+
+        //inboxes keep the newest MAX_NOTIFICATIONS
+        for n in 0..MAX_NOTIFICATIONS {
+            notify(TEST_ADDRESS, None, format!("note {}", n));
+        }
+        let inbox = get_my_notifications(false);
+        assert_eq!(inbox.len(), MAX_NOTIFICATIONS);
+        assert_eq!(inbox[0].text, format!("note {}", MAX_NOTIFICATIONS - 1));
+        assert!(inbox.windows(2).all(|pair| pair[0].seq > pair[1].seq));
+        assert_eq!(as_test_rider(ctx, export_my_data).disputes.len(), 1);
     }
 
-    ///test update_driver_for_ride
+    ///test riders rate the drivers of their completed rides
     #[test]
-    fn test_update_driver_for_ride() {
-        //create driver
-        let driver = Driver {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@gmail.com".to_string(),
-            role: "driver".to_string(),
-            vehicleplatenumber: "ABC123".to_string(),
-            vehicleseatnumber: "1".to_string(),
-            vehiclemake: "Toyota".to_string(),
-            vehiclemodel: "Corolla".to_string(),
-            vehiclecolor: "Black".to_string(),
-            vehicletype: "SUV".to_string(),
-            vehicleyear: "2020".to_string(),
-            rating: 0.0,
-            currentstatus: CurrentStatus::Active,
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-        };
-        register_driver(driver.clone());
-        //create rider
-        let rider = Rider {
-            name: "Kelsey".to_string(),
-            contact: "1234567890".to_string(),
-            email: "test@email.com".to_string(),
-            role: "rider".to_string(),
-            address: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
+    fn test_rider_rates_driver() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        accept_ride(ride_id.clone()).unwrap();
+        let rate = |ctx: &mut MockContext, rating: f64| {
+            as_test_rider(ctx, || update_driver_rating(ride_id.clone(), rating))
         };
-        register_rider(rider.clone());
-        //create ride for register_ride
-        let ride = Ride {
-            rideid: "cjr37-nxx7a-keiqq-efh5n-v47nd-ceddb-2c6hg-aseen-h66ih-so563-hae".to_string(),
-            rider: rider.clone(),
-            driver: driver.clone(),
-            pickup: "new york".to_string(),
-            dropoff: "san francisco".to_string(),
-            timestamp: "2020-01-01T00:00:00.000Z".to_string(),
-            status: RideStatus::Active,
-            driverrating: 0.0,
-            riderrating: 0.0,
-            driverconfirmation: "".to_string(),
-            riderconfirmation: "".to_string(),
-            driverfeedback: "".to_string(),
-            riderfeedback: "".to_string(),
-            rating: 0.0,
+        assert!(rate(ctx, 4.0).is_err());
+        start_test_ride(ctx, &ride_id);
+        complete_ride(ride_id.clone()).unwrap();
+        //only the ride's rider rates, once, from 1 to 5
+        assert!(update_driver_rating(ride_id.clone(), 4.0).is_err());
+        for rating in [0.5, 5.5, f64::NAN] {
+            assert!(rate(ctx, rating).is_err());
+        }
+        rate(ctx, 4.0).unwrap();
+        assert_eq!(find_driver(TEST_ADDRESS).unwrap().rating, 4.0);
+        assert!(rate(ctx, 5.0).is_err());
+
+        //the driver's rating averages their rated rides
+        let second = as_test_rider(ctx, || request_ride(test_request())).unwrap();
+        accept_ride(second.clone()).unwrap();
+        start_test_ride(ctx, &second);
+        complete_ride(second.clone()).unwrap();
+        as_test_rider(ctx, || update_driver_rating(second, 2.0)).unwrap();
+        assert_eq!(find_driver(TEST_ADDRESS).unwrap().rating, 3.0);
+    }
+
+    ///test controllers are seeded on upgrade and the last one cannot be removed
+    #[test]
+    fn test_controllers() {
+        let ctx = MockContext::new().with_caller(mock_principals::alice()).inject();
+        let state = candid::encode_one(StableState::capture()).unwrap();
+        restore_upgraded(candid::decode_one(&state).unwrap());
+        assert_eq!(get_controllers(), vec![mock_principals::alice()]);
+        assert!(has_role(mock_principals::alice(), Role::Finance));
+
+        add_controller(mock_principals::bob());
+        assert_eq!(get_controllers().len(), 2);
+        remove_controller(mock_principals::alice()).unwrap();
+        ctx.update_caller(mock_principals::bob());
+        assert!(remove_controller(mock_principals::bob()).is_err());
+        assert!(remove_controller(mock_principals::alice()).is_err());
+    }
+
+    ///test suspended drivers are not joined to pools or handed pre-accepted rides
+    #[test]
+    fn test_suspended_driver_dispatch() {
+        let ctx = MockContext::new()
+            .with_caller(Principal::from_text(TEST_ADDRESS).unwrap())
+            .inject();
+        register_test_driver(ctx);
+        let first = as_test_rider(ctx, || request_pooled_ride(equator_request(0.0, 1.0))).unwrap();
+        accept_ride(first).unwrap();
+        let policy = get_scheduling_policy();
+        let pickup_at = ic::time() + 2 * policy.min_advance;
+        let scheduled =
+            as_test_rider(ctx, || schedule_ride(test_request(), pickup_at)).unwrap();
+        pre_accept_ride(scheduled.clone()).unwrap();
+
+        //only approved drivers can be suspended
+        assert!(suspend_driver(mock_principals::john().to_text(), "test".to_string()).is_err());
+        suspend_driver(TEST_ADDRESS.to_string(), "lapsed".to_string()).unwrap();
+        assert!(suspend_driver(TEST_ADDRESS.to_string(), "again".to_string()).is_err());
+
+        assert!(as_test_rider(ctx, || request_pooled_ride(equator_request(0.2, 0.8))).is_err());
+        dispatch_scheduled_rides(pickup_at - policy.dispatch_lead_time);
+        let ride = search_ride_by_id(scheduled).unwrap();
+        assert_eq!(ride.status, RideStatus::Scheduled);
+        assert!(!ride.has_driver());
+    }
+
+    fn is_stripped(id: &Label, fields: &[&str]) -> bool {
+        fields.iter().any(|field| candid::idl_hash(field) == id.get_id())
+    }
+
+    /// drop the record fields named in `fields` from a candid type
+    fn strip_type(ty: Type, fields: &[&str]) -> Type {
+        let strip = |types: Vec<Field>| {
+            types
+                .into_iter()
+                .filter(|field| !is_stripped(&field.id, fields))
+                .map(|field| Field { id: field.id, ty: strip_type(field.ty, fields) })
+                .collect()
         };
+        match ty {
+            Type::Record(types) => Type::Record(strip(types)),
+            Type::Variant(types) => Type::Variant(strip(types)),
+            Type::Opt(ty) => Type::Opt(Box::new(strip_type(*ty, fields))),
+            Type::Vec(ty) => Type::Vec(Box::new(strip_type(*ty, fields))),
+            ty => ty,
+        }
+    }
 
-        register_ride(ride.clone());
-        let mut new_driver = driver.clone();
-        new_driver.update_vehiclemake("Honda".to_string());
-        update_driver(driver.address, new_driver.clone());
-        let mut new_ride = ride.clone();
-        new_ride.driver = new_driver.clone();
+    /// drop the record fields named in `fields` from a candid value
+    fn strip_value(value: IDLValue, fields: &[&str]) -> IDLValue {
+        match value {
+            IDLValue::Record(values) => IDLValue::Record(
+                values
+                    .into_iter()
+                    .filter(|field| !is_stripped(&field.id, fields))
+                    .map(|field| IDLField { id: field.id, val: strip_value(field.val, fields) })
+                    .collect(),
+            ),
+            IDLValue::Variant(VariantValue(field, index)) => {
+                let val = strip_value(field.val, fields);
+                IDLValue::Variant(VariantValue(Box::new(IDLField { id: field.id, val }), index))
+            }
+            IDLValue::Opt(value) => IDLValue::Opt(Box::new(strip_value(*value, fields))),
+            IDLValue::Vec(values) => {
+                IDLValue::Vec(values.into_iter().map(|value| strip_value(value, fields)).collect())
+            }
+            value => value,
+        }
+    }
 
-        //update_ride with new ride
-        update_ride(ride.rideid, new_ride);
+    ///test state saved by the release before versioned upgrades still restores
+    #[test]
+    fn test_upgrade_from_previous_release() {
+        let ctx = MockContext::new()
+            .with_caller(mock_principals::alice())
+            .inject();
+        let ride_id = request_test_ride(ctx);
+        let driver = Principal::from_text(TEST_ADDRESS).unwrap();
+        as_caller(ctx, driver, || accept_ride(ride_id.clone())).unwrap();
+        as_test_rider(ctx, || send_chat_message(ride_id.clone(), "hi".to_string())).unwrap();
+        let audited = get_audit_events(AuditFilter::default(), 0, 100).events.len();
 
-        //get rides
-        let rides = get_rides();
-        //get first ride
-        let check_ride = rides.first().unwrap();
-        //assert ride exists
-        assert_eq!(check_ride.rider.name, rider.name);
-        //check for honda
-        assert_eq!(check_ride.driver.vehiclemake, "Honda".to_string());
+        //that release wrote the state as a bare candid tuple from offset 0, without the fields
+        //added since, with the audit and event logs inside it and principals in place of relay ids
+        let mut state = StableState::capture();
+        state.audit_log = Some(
+            (0..audited as u64)
+                .map(|seq| AUDIT_LOG.get(seq).unwrap())
+                .collect(),
+        );
+        for thread in state.chat_threads.iter_mut().flat_map(|threads| threads.values_mut()) {
+            thread.messages[0].sender = mock_principals::bob().to_text();
+        }
+        let emitted = EVENT_LOG.len();
+        state.event_log = Some(
+            (0..emitted)
+                .map(|index| DomainEvent {
+                    driver: TEST_ADDRESS.to_string(),
+                    ..EVENT_LOG.get(index).unwrap()
+                })
+                .collect(),
+        );
+        let added = [
+            "geo_config", "zones", "surge_policy", "surge", "promo_codes", "promo_redemptions",
+            "earnings", "disputes", "notifications", "payouts", "payout_accounts",
+            "payout_policy", "last_payout_batch", "subscribers",
+            "driver_locations", "breadcrumbs", "pricing", "adjustments", "platform_fee_percent",
+        ];
+        let bytes = candid::encode_one(&state).unwrap();
+        let value = IDLArgs::from_bytes(&bytes).unwrap().args.remove(0);
+        let ty = strip_type(StableState::ty(), &added);
+        let legacy = IDLArgs::new(&[strip_value(value, &added)])
+            .to_bytes_with_types(&TypeEnv::new(), &[ty])
+            .unwrap();
+        memory::replace_raw(&legacy);
+        RIDES_STORE.with(|store| store.borrow_mut().clear());
+
+        post_upgrade();
+        let ride = search_ride_by_id(ride_id.clone()).unwrap();
+        assert_eq!(ride.status, RideStatus::Accepted);
+        assert!(ride.pricing().zone_id.is_none() && ride.pricing().promo_code.is_none());
+        assert!(ride.adjustments().is_empty());
+        assert_eq!(get_fare_config().platform_fee_percent(), DEFAULT_PLATFORM_FEE_PERCENT);
+        //the migrated log is followed by the upgrading principal being seeded as controller
+        let events = get_audit_events(AuditFilter::default(), 0, 100).events;
+        assert_eq!(events.len(), audited + 1);
+        assert_eq!(events[audited].endpoint, "post_upgrade");
+        let events = as_test_rider(ctx, || get_events_since(0, EventFilter::default())).events;
+        assert_eq!(events.len() as u64, emitted);
+        assert!(events.iter().all(|event| event.driver == relay_id(&ride_id, "driver")));
+        let thread = as_test_rider(ctx, || get_chat(ride_id.clone())).unwrap();
+        assert_eq!(thread.messages[0].sender, relay_id(&ride_id, "rider"));
+
+        //the next upgrade writes the versioned state into the laid out memory
+        pre_upgrade();
+        post_upgrade();
+        let events = get_audit_events(AuditFilter::default(), 0, 100).events;
+        assert_eq!(events.len(), audited + 1);
+        assert!(search_ride_by_id(ride_id).is_some());
+
+        //a canister upgraded with nothing saved keeps going with empty stores
+        memory::replace_raw(&[]);
+        assert!(load_state().unwrap().is_none());
+        //anything unreadable traps, leaving stable memory as it was for the rollback
+        memory::replace_raw(b"garbage");
+        assert!(load_state().is_err());
+        memory::replace_raw(&legacy[..legacy.len() / 2]);
+        assert!(std::panic::catch_unwind(post_upgrade).is_err());
+        assert!(memory::legacy_blob().is_some());
+        memory::replace_raw(&[]);
+        memory::save_blob(memory::UPGRADES, b"garbage");
+        assert!(load_state().is_err());
     }
-}
\ No newline at end of file
+}
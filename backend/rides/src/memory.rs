@@ -0,0 +1,364 @@
+/*
+* Module        :  backend/rides/src/memory.rs
+* Copyright     :  2022 Webi.ai
+* License       :  Not Yet Licensed for Public Use
+* Description   :  Stable memory split into growable virtual memories and append-only logs
+*/
+
+use ic_cdk::export::candid::{self, CandidType};
+use serde::de::DeserializeOwned;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+const MAGIC: &[u8; 3] = b"RDS";
+const LAYOUT_VERSION: u8 = 1;
+/// stable memory pages handed to a virtual memory at a time
+const BUCKET_PAGES: u64 = 128;
+const MAX_MEMORIES: usize = 16;
+const MAX_BUCKETS: usize = 32_000;
+const UNALLOCATED: u8 = u8::MAX;
+/// the header takes the first page: magic and version, the bucket count, the size in pages of
+/// each memory and the memory owning each bucket
+const HEADER_PAGES: u64 = 1;
+const BUCKET_COUNT_OFFSET: u64 = 8;
+const SIZES_OFFSET: u64 = 16;
+const BUCKET_TABLE_OFFSET: u64 = SIZES_OFFSET + 8 * MAX_MEMORIES as u64;
+
+/// the state blob written on every upgrade
+pub const UPGRADES: Memory = Memory(0);
+pub const AUDIT_INDEX: Memory = Memory(1);
+pub const AUDIT_DATA: Memory = Memory(2);
+pub const EVENT_INDEX: Memory = Memory(3);
+pub const EVENT_DATA: Memory = Memory(4);
+
+#[cfg(target_family = "wasm")]
+mod raw {
+    use ic_cdk::api::stable;
+
+    pub fn size() -> u64 {
+        stable::stable64_size()
+    }
+
+    pub fn grow(pages: u64) {
+        if stable::stable64_grow(pages).is_err() {
+            ic_cdk::api::trap("out of stable memory");
+        }
+    }
+
+    pub fn read(offset: u64, buf: &mut [u8]) {
+        stable::stable64_read(offset, buf)
+    }
+
+    pub fn write(offset: u64, buf: &[u8]) {
+        stable::stable64_write(offset, buf)
+    }
+}
+
+/// heap backed stand-in for stable memory outside the canister
+#[cfg(not(target_family = "wasm"))]
+mod raw {
+    use super::WASM_PAGE_SIZE;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static STABLE: RefCell<Vec<u8>> = RefCell::default();
+    }
+
+    pub fn size() -> u64 {
+        STABLE.with(|stable| stable.borrow().len() as u64 / WASM_PAGE_SIZE)
+    }
+
+    pub fn grow(pages: u64) {
+        STABLE.with(|stable| {
+            let mut stable = stable.borrow_mut();
+            let len = stable.len() + (pages * WASM_PAGE_SIZE) as usize;
+            stable.resize(len, 0);
+        })
+    }
+
+    pub fn read(offset: u64, buf: &mut [u8]) {
+        STABLE.with(|stable| {
+            let offset = offset as usize;
+            buf.copy_from_slice(&stable.borrow()[offset..offset + buf.len()]);
+        })
+    }
+
+    pub fn write(offset: u64, buf: &[u8]) {
+        STABLE.with(|stable| {
+            let offset = offset as usize;
+            stable.borrow_mut()[offset..offset + buf.len()].copy_from_slice(buf);
+        })
+    }
+
+    #[cfg(test)]
+    pub fn replace(bytes: &[u8]) {
+        STABLE.with(|stable| {
+            let pages = (bytes.len() as u64).div_ceil(WASM_PAGE_SIZE);
+            let mut stable = stable.borrow_mut();
+            *stable = bytes.to_vec();
+            stable.resize((pages * WASM_PAGE_SIZE) as usize, 0);
+        })
+    }
+}
+
+thread_local! {
+    /// the buckets each memory owns in order, read from the header on first use
+    static BUCKETS: RefCell<Option<Vec<Vec<u64>>>> = RefCell::default();
+}
+
+fn read_u64(offset: u64) -> u64 {
+    let mut bytes = [0; 8];
+    raw::read(offset, &mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+fn write_u64(offset: u64, value: u64) {
+    raw::write(offset, &value.to_le_bytes());
+}
+
+/// nothing was ever written to stable memory
+pub fn is_empty() -> bool {
+    raw::size() == 0
+}
+
+/// stable memory holds the layout written by `format`
+pub fn is_formatted() -> bool {
+    if raw::size() < HEADER_PAGES {
+        return false;
+    }
+    let mut magic = [0; 4];
+    raw::read(0, &mut magic);
+    magic[..3] == MAGIC[..] && magic[3] == LAYOUT_VERSION
+}
+
+/// the candid blob canisters wrote from offset 0 before the layout existed
+pub fn legacy_blob() -> Option<Vec<u8>> {
+    let size = raw::size();
+    if size == 0 {
+        return None;
+    }
+    let mut magic = [0; 4];
+    raw::read(0, &mut magic);
+    if &magic != b"DIDL" {
+        return None;
+    }
+    let mut bytes = vec![0; (size * WASM_PAGE_SIZE) as usize];
+    raw::read(0, &mut bytes);
+    Some(bytes)
+}
+
+/// replace stable memory with `bytes`, as left behind by an earlier release
+#[cfg(test)]
+pub fn replace_raw(bytes: &[u8]) {
+    raw::replace(bytes);
+    BUCKETS.with(|buckets| *buckets.borrow_mut() = None);
+}
+
+/// write an empty layout over the start of stable memory
+pub fn format() {
+    format_header();
+    BUCKETS.with(|buckets| *buckets.borrow_mut() = Some(vec![vec![]; MAX_MEMORIES]));
+}
+
+fn format_header() {
+    if raw::size() < HEADER_PAGES {
+        raw::grow(HEADER_PAGES - raw::size());
+    }
+    raw::write(0, &[MAGIC[0], MAGIC[1], MAGIC[2], LAYOUT_VERSION]);
+    write_u64(BUCKET_COUNT_OFFSET, 0);
+    for memory in 0..MAX_MEMORIES as u64 {
+        write_u64(SIZES_OFFSET + 8 * memory, 0);
+    }
+    raw::write(BUCKET_TABLE_OFFSET, &[UNALLOCATED; MAX_BUCKETS]);
+}
+
+fn with_buckets<T>(f: impl FnOnce(&mut Vec<Vec<u64>>) -> T) -> T {
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        if buckets.is_none() {
+            //a fresh canister starts with empty stable memory, anything else must be laid out
+            if raw::size() == 0 {
+                format_header();
+            } else if !is_formatted() {
+                ic_cdk::api::trap("stable memory is not laid out");
+            }
+        }
+        let buckets = buckets.get_or_insert_with(|| {
+            let count = read_u64(BUCKET_COUNT_OFFSET) as usize;
+            let mut owners = vec![0; count];
+            raw::read(BUCKET_TABLE_OFFSET, &mut owners);
+            let mut buckets = vec![vec![]; MAX_MEMORIES];
+            for (bucket, owner) in owners.into_iter().enumerate() {
+                buckets[owner as usize].push(bucket as u64);
+            }
+            buckets
+        });
+        f(buckets)
+    })
+}
+
+/// a growable region of stable memory made of the buckets allocated to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Memory(u8);
+
+impl Memory {
+    /// size in wasm pages
+    pub fn size(&self) -> u64 {
+        with_buckets(|_| ());
+        read_u64(SIZES_OFFSET + 8 * self.0 as u64)
+    }
+
+    pub fn grow(&self, pages: u64) {
+        let size = self.size();
+        let needed = (size + pages).div_ceil(BUCKET_PAGES);
+        with_buckets(|buckets| {
+            while (buckets[self.0 as usize].len() as u64) < needed {
+                let bucket = read_u64(BUCKET_COUNT_OFFSET);
+                assert!((bucket as usize) < MAX_BUCKETS, "out of stable memory buckets");
+                let end = HEADER_PAGES + (bucket + 1) * BUCKET_PAGES;
+                if raw::size() < end {
+                    raw::grow(end - raw::size());
+                }
+                raw::write(BUCKET_TABLE_OFFSET + bucket, &[self.0]);
+                write_u64(BUCKET_COUNT_OFFSET, bucket + 1);
+                buckets[self.0 as usize].push(bucket);
+            }
+        });
+        write_u64(SIZES_OFFSET + 8 * self.0 as u64, size + pages);
+    }
+
+    /// grow to hold at least `bytes`
+    pub fn reserve(&self, bytes: u64) {
+        let pages = bytes.div_ceil(WASM_PAGE_SIZE);
+        let size = self.size();
+        if pages > size {
+            self.grow(pages - size);
+        }
+    }
+
+    /// the stable memory ranges backing `len` bytes from `offset`
+    fn spans(&self, offset: u64, len: usize) -> Vec<(u64, usize)> {
+        assert!(offset + len as u64 <= self.size() * WASM_PAGE_SIZE, "read past end of memory");
+        let bucket_size = BUCKET_PAGES * WASM_PAGE_SIZE;
+        with_buckets(|buckets| {
+            let owned = &buckets[self.0 as usize];
+            let mut spans = vec![];
+            let (mut offset, mut left) = (offset, len);
+            while left > 0 {
+                let within = offset % bucket_size;
+                let take = left.min((bucket_size - within) as usize);
+                let bucket = owned[(offset / bucket_size) as usize];
+                let start = HEADER_PAGES * WASM_PAGE_SIZE + bucket * bucket_size + within;
+                spans.push((start, take));
+                offset += take as u64;
+                left -= take;
+            }
+            spans
+        })
+    }
+
+    pub fn read(&self, offset: u64, buf: &mut [u8]) {
+        let mut done = 0;
+        for (start, len) in self.spans(offset, buf.len()) {
+            raw::read(start, &mut buf[done..done + len]);
+            done += len;
+        }
+    }
+
+    pub fn write(&self, offset: u64, buf: &[u8]) {
+        let mut done = 0;
+        for (start, len) in self.spans(offset, buf.len()) {
+            raw::write(start, &buf[done..done + len]);
+            done += len;
+        }
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let mut bytes = [0; 8];
+        self.read(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, &value.to_le_bytes());
+    }
+}
+
+/// replace the blob held in `memory` with `bytes`
+pub fn save_blob(memory: Memory, bytes: &[u8]) {
+    memory.reserve(8 + bytes.len() as u64);
+    memory.write_u64(0, bytes.len() as u64);
+    memory.write(8, bytes);
+}
+
+/// the blob held in `memory`, if one was saved
+pub fn load_blob(memory: Memory) -> Option<Vec<u8>> {
+    if memory.size() == 0 {
+        return None;
+    }
+    let len = memory.read_u64(0);
+    let mut bytes = vec![0; len as usize];
+    memory.read(8, &mut bytes);
+    Some(bytes)
+}
+
+/// candid encoded entries appended to `data`, with `index` holding the entry count followed
+/// by the end offset of each entry
+pub struct StableLog<T> {
+    index: Memory,
+    data: Memory,
+    entry: PhantomData<T>,
+}
+
+impl<T: CandidType + DeserializeOwned> StableLog<T> {
+    pub const fn new(index: Memory, data: Memory) -> StableLog<T> {
+        StableLog {
+            index,
+            data,
+            entry: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        if self.index.size() == 0 {
+            0
+        } else {
+            self.index.read_u64(0)
+        }
+    }
+
+    /// byte range of the entry at `seq` in the data memory
+    fn bounds(&self, seq: u64) -> (u64, u64) {
+        let start = if seq == 0 {
+            0
+        } else {
+            self.index.read_u64(8 * seq)
+        };
+        (start, self.index.read_u64(8 * (seq + 1)))
+    }
+
+    /// append `entry`, returning its sequence number
+    pub fn append(&self, entry: &T) -> u64 {
+        let bytes = candid::encode_one(entry).expect("failed to encode log entry");
+        let seq = self.len();
+        let start = if seq == 0 { 0 } else { self.bounds(seq - 1).1 };
+        let end = start + bytes.len() as u64;
+        self.data.reserve(end);
+        self.data.write(start, &bytes);
+        self.index.reserve(8 * (seq + 2));
+        self.index.write_u64(8 * (seq + 1), end);
+        self.index.write_u64(0, seq + 1);
+        seq
+    }
+
+    pub fn get(&self, seq: u64) -> Option<T> {
+        if seq >= self.len() {
+            return None;
+        }
+        let (start, end) = self.bounds(seq);
+        let mut bytes = vec![0; (end - start) as usize];
+        self.data.read(start, &mut bytes);
+        Some(candid::decode_one(&bytes).expect("failed to decode log entry"))
+    }
+}
@@ -4,29 +4,9 @@
 // License : Not currently licensed for public use
 // Copyright : Webi.ai (c) 2022
 
-import { v4 as uuidv4 } from "uuid";
-
 //import the actor from agent script to interact with smart contracts
 import actor from "../scripts/agent.js";
 
-//create a placeholder driver for the ride
-const PLACEHOLDER_DRIVER = {
-  contact: "",
-  name: "",
-  email: "",
-  role: "driver",
-  vehicleplatenumber: "",
-  vehicleseatnumber: "",
-  vehiclemake: "",
-  vehiclemodel: "",
-  vehiclecolor: "",
-  vehicletype: "",
-  vehicleyear: "",
-  rating: 0,
-  currentstatus: { Active: null },
-  address: "",
-};
-
 //create a placeholder rider for the ride
 const PLACEHOLDER_RIDER = {
   contact: "",
@@ -49,52 +29,27 @@ const registerRider = async (rider) => {
 };
 
 // rider wallet address primary id?
-//registerRide registers a new ride using actor.register_ride
+//registerRide requests a new ride for the logged in rider using actor.request_ride
 const registerRide = async (riderAddress, pickup, dropoff) => {
-  console.log("register_ride for rider address", riderAddress);
-  // retrieve logged in rider
-  const rider = await searchRiderByAddress(riderAddress);
-  const rideId = uuidv4(); // generate unique ride id
-  const pickupJson = JSON.stringify({
-    lat: pickup.lat,
-    lng: pickup.lng,
-    address_text: pickup.address_text,
+  console.log("request_ride for rider address", riderAddress);
+  const toLocation = (location) => ({
+    address_text: location.address_text,
+    lat: Number(location.lat),
+    lng: Number(location.lng),
   });
-  const dropoffJson = JSON.stringify({
-    lat: dropoff.lat,
-    lng: dropoff.lng,
-    address_text: dropoff.address_text,
+  const result = await actor.request_ride({
+    pickup: toLocation(pickup),
+    dropoff: toLocation(dropoff),
+    stops: [],
+    promo_code: [],
+    quoted_surge: [],
   });
-  const ride = {
-    rideid: rideId,
-    pickup: pickupJson,
-    dropoff: dropoffJson,
-    status: { Active: null },
-    timestamp: Date.now().toString(),
-    rating: 0,
-    driver: PLACEHOLDER_DRIVER, // placeholder values, driver should be opt so it can be null until one accepts ride
-    driverrating: 0,
-    driverfeedback: "",
-    driverconfirmation: "",
-    rider: rider, // currently logged in rider
-    riderrating: 0,
-    riderfeedback: "",
-    riderconfirmation: "",
-  };
-  // console.log(ride);
-  // console.log("register_ride", rideId);
-  await actor.register_ride(ride);
-  // console.log("register_ride success, ride id ", rideId);
-
-  const newRide = await getMostRecentRideForRider(riderAddress);
-  // console.log(newRide);
-  if (!newRide) {
-    console.log('can\'t find ride', rideId);
-  } else if (newRide.rideId !== rideId) {
-    console.log('ride id mismatch, rideid ', rideId, ' doesn\'t match found id ', newRide.rideId);
-  } else {
-    console.log('ride registered', rideId);
+  if ("Err" in result) {
+    console.error("request_ride error", result.Err);
+    return null;
   }
+  const rideId = result.Ok;
+  console.log('ride registered', rideId);
   return rideId;
 };
 